@@ -1,17 +1,54 @@
 #![feature(os)]
 #![feature(path)]
 #![feature(collections)]
+#![feature(io)]
 
 extern crate gcc;
 
 use std::default::Default;
+use std::old_io::File;
 use std::os::{getenv, setenv};
 
+/// The oldest duktape release which supports the debugger protocol.
+const DEBUGGER_MIN_VERSION: u32 = 10200;
+
+/// Read `DUK_VERSION` from the duktape headers we're about to build.
+fn duktape_version() -> Option<u32> {
+    let header = Path::new("duktape/src/duktape.h");
+    let text = match File::open(&header).read_to_string() {
+        Ok(text) => text,
+        Err(_) => return None
+    };
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.words();
+            match (words.next(), words.next(), words.next()) {
+                (Some("#define"), Some("DUK_VERSION"), Some(value)) =>
+                    value.trim_right_matches('L').parse().ok(),
+                _ => None
+            }
+        })
+        .next()
+}
+
 fn main() {
     // Make sure we get a thread-safe build.  Without this, duktape refuses
     // to set DUK_USE_VARIADIC_MACROS and falls back to global variables.
     let mut cflags = getenv("CFLAGS").unwrap_or("".to_string());
     cflags.push_str(" -std=c99");
+    if getenv("CARGO_FEATURE_DEBUGGER").is_some() {
+        // Older releases silently ignore DUK_OPT_DEBUGGER_SUPPORT, and
+        // we'd fail to link, so explain what's wrong.
+        match duktape_version() {
+            Some(v) if v >= DEBUGGER_MIN_VERSION => {}
+            found => panic!("the debugger feature needs duktape 1.2 or \
+                             later in duktape_sys/duktape, but found \
+                             DUK_VERSION {:?}", found)
+        }
+        // The debugger needs the interrupt counter to notice breakpoints.
+        cflags.push_str(" -DDUK_OPT_DEBUGGER_SUPPORT \
+                         -DDUK_OPT_INTERRUPT_COUNTER");
+    }
     setenv("CFLAGS", cflags);
 
     gcc::compile_library("libduktape.a", &gcc::Config {
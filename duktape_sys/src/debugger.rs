@@ -0,0 +1,39 @@
+//! Bindings for the debugger API added in duktape 1.2.  These are only
+//! available when duktape is built with `DUK_OPT_DEBUGGER_SUPPORT`, which
+//! is what our `debugger` feature does.
+
+use generated::*;
+use bindings::*;
+
+pub type duk_debug_read_function =
+    ::std::option::Option<unsafe extern "C" fn
+                              (udata: *mut ::libc::c_void,
+                               buffer: *mut ::libc::c_char,
+                               length: duk_size_t) -> duk_size_t>;
+pub type duk_debug_write_function =
+    ::std::option::Option<unsafe extern "C" fn
+                              (udata: *mut ::libc::c_void,
+                               buffer: *const ::libc::c_char,
+                               length: duk_size_t) -> duk_size_t>;
+pub type duk_debug_peek_function =
+    ::std::option::Option<unsafe extern "C" fn
+                              (udata: *mut ::libc::c_void) -> duk_size_t>;
+pub type duk_debug_read_flush_function =
+    ::std::option::Option<unsafe extern "C" fn(udata: *mut ::libc::c_void)>;
+pub type duk_debug_write_flush_function =
+    ::std::option::Option<unsafe extern "C" fn(udata: *mut ::libc::c_void)>;
+pub type duk_debug_detached_function =
+    ::std::option::Option<unsafe extern "C" fn(udata: *mut ::libc::c_void)>;
+
+extern "C" {
+    pub fn duk_debugger_attach(ctx: *mut duk_context,
+                               read_cb: duk_debug_read_function,
+                               write_cb: duk_debug_write_function,
+                               peek_cb: duk_debug_peek_function,
+                               read_flush_cb: duk_debug_read_flush_function,
+                               write_flush_cb: duk_debug_write_flush_function,
+                               detached_cb: duk_debug_detached_function,
+                               udata: *mut ::libc::c_void);
+    pub fn duk_debugger_detach(ctx: *mut duk_context);
+    pub fn duk_debugger_cooperate(ctx: *mut duk_context);
+}
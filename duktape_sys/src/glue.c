@@ -1,5 +1,13 @@
 #include "duktape.h"
 
+/// Returned by a Rust handler to ask `duk_rust_trampoline` to throw the
+/// value on the top of the stack.  Must match `glue.rs`.
+#define DUK_RET_RUST_THROW (-1000)
+
+/// Hidden property where we store the Rust handler called by
+/// `duk_rust_trampoline`.  Must match `glue.rs`.
+#define DUK_RUST_HANDLER_PROP "\xff" "rhandler"
+
 /// A custom add-on to the duktape API, replacing the macro
 /// `duk_push_error_object`,
 extern duk_idx_t
@@ -10,3 +18,48 @@ duk_push_error_object_string(duk_context *ctx, duk_errcode_t err_code,
     return duk_push_error_object_raw(ctx, err_code, filename, line, "%s",
                                      message);
 }
+
+/// Call the Rust handler stored in `DUK_RUST_HANDLER_PROP` of the object
+/// on top of the stack, popping the object.  If the handler returns
+/// `DUK_RET_RUST_THROW`, we throw the value on the top of the stack from
+/// here, so that duktape's `longjmp` never has to unwind through any Rust
+/// stack frames.
+static duk_ret_t
+duk_rust_call_handler(duk_context *ctx)
+{
+    duk_c_function handler;
+    duk_ret_t ret;
+
+    duk_get_prop_string(ctx, -1, DUK_RUST_HANDLER_PROP);
+    handler = (duk_c_function) duk_get_pointer(ctx, -1);
+    duk_pop_2(ctx);
+    if (handler == NULL) {
+        return DUK_RET_INTERNAL_ERROR;
+    }
+
+    ret = handler(ctx);
+    if (ret == DUK_RET_RUST_THROW) {
+        duk_throw(ctx);
+    }
+    return ret;
+}
+
+/// A C function which calls the Rust handler stored on the current
+/// function.
+extern duk_ret_t
+duk_rust_trampoline(duk_context *ctx)
+{
+    duk_push_current_function(ctx);
+    return duk_rust_call_handler(ctx);
+}
+
+/// A C function which calls the Rust handler stored in the heap stash.
+/// This is used for functions created in bulk by `duk_put_function_list`,
+/// which have no properties of their own, and which are told apart using
+/// their magic values.
+extern duk_ret_t
+duk_rust_magic_trampoline(duk_context *ctx)
+{
+    duk_push_heap_stash(ctx);
+    return duk_rust_call_handler(ctx);
+}
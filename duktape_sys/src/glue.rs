@@ -4,6 +4,16 @@
 use generated::*;
 use bindings::*;
 
+/// Returned by a Rust handler to ask `duk_rust_trampoline` to throw the
+/// value on the top of the stack.  Must match `glue.c`.
+pub const DUK_RET_RUST_THROW: duk_ret_t = -1000;
+
+/// The hidden property where `duk_rust_trampoline` looks for its Rust
+/// handler, as a NUL-terminated string.  Must match `glue.c`.
+pub const DUK_RUST_HANDLER_PROP: [i8; 10] =
+    [-1, 'r' as i8, 'h' as i8, 'a' as i8, 'n' as i8, 'd' as i8, 'l' as i8,
+     'e' as i8, 'r' as i8, 0];
+
 extern "C" {
     /// A wrapper around duk_push_error_object, which relies on varargs in
     /// the original API.
@@ -11,4 +21,16 @@ extern "C" {
         ctx: *mut duk_context, err_code: duk_errcode_t,
         filename: *const i8, line: duk_int_t,
         message: *const i8) -> duk_idx_t;
+
+    /// Call the `duk_c_function` stored as a pointer in the hidden
+    /// property `DUK_RUST_HANDLER_PROP` of the current function.  If it
+    /// returns `DUK_RET_RUST_THROW`, throw the value on top of the stack.
+    /// This allows Rust code to throw arbitrary errors without unwinding
+    /// Rust stack frames using `longjmp`.
+    pub fn duk_rust_trampoline(ctx: *mut duk_context) -> duk_ret_t;
+
+    /// Like `duk_rust_trampoline`, but look for the handler in the heap
+    /// stash instead of on the current function.  The handler can use
+    /// `duk_get_current_magic` to tell functions apart.
+    pub fn duk_rust_magic_trampoline(ctx: *mut duk_context) -> duk_ret_t;
 }
@@ -17,10 +17,12 @@ extern crate libc;
 pub use generated::*;
 pub use bindings::*;
 pub use glue::*;
+#[cfg(feature = "debugger")] pub use debugger::*;
 
 mod generated;
 mod bindings;
 mod glue;
+#[cfg(feature = "debugger")] mod debugger;
 
 #[test]
 fn test_eval() {
@@ -0,0 +1,183 @@
+//! Argument lists for calling JavaScript functions from Rust.  Anything
+//! implementing `IntoArgs` can be passed to `Context::call`, including
+//! tuples of encodable values, vectors of `Value`, stack slots, and
+//! slices or arrays of `&DuktapeEncodable` references.
+//!
+//! ```
+//! use std::borrow::Cow;
+//! use duktape::{Context, Value};
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.eval("function describe(n, s, xs) { return s + n + xs.length; }")
+//!     .unwrap();
+//! assert_eq!(Ok(Value::String(Cow::Borrowed("two13"))),
+//!            ctx.call("describe", (1.0f64, "two", vec!(3.0f64))));
+//! ```
+
+use errors::*;
+use types::Value;
+use encoder::{Encoder, DuktapeEncodable};
+use stack::Slot;
+
+/// A list of arguments which can be passed to a JavaScript function.
+pub trait IntoArgs {
+    /// The number of arguments in this list.
+    fn arg_count(&self) -> usize;
+
+    /// Push each of our arguments, in order.  If this fails, the caller
+    /// is responsible for removing any arguments we managed to push.
+    fn encode_args(&self, s: &mut Encoder) -> DuktapeResult<()>;
+}
+
+impl IntoArgs for () {
+    fn arg_count(&self) -> usize { 0 }
+    fn encode_args(&self, _s: &mut Encoder) -> DuktapeResult<()> { Ok(()) }
+}
+
+impl<'a, 'b> IntoArgs for &'a [&'b (DuktapeEncodable + 'b)] {
+    fn arg_count(&self) -> usize { self.len() }
+
+    fn encode_args(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        for arg in self.iter() {
+            try!(arg.duktape_encode(s));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'f> IntoArgs for &'a [Slot<'f>] {
+    fn arg_count(&self) -> usize { self.len() }
+
+    fn encode_args(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        for arg in self.iter() {
+            try!(arg.duktape_encode(s));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IntoArgs for Vec<Value<'a>> {
+    fn arg_count(&self) -> usize { self.len() }
+
+    fn encode_args(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        for arg in self.iter() {
+            try!(arg.duktape_encode(s));
+        }
+        Ok(())
+    }
+}
+
+/// Implement `IntoArgs` for a tuple type with the specified elements.
+macro_rules! tuple_into_args {
+    ($count:expr; $($name:ident),+) => {
+        impl<$($name: DuktapeEncodable),+> IntoArgs for ($($name,)+) {
+            fn arg_count(&self) -> usize { $count }
+
+            #[allow(non_snake_case)]
+            fn encode_args(&self, s: &mut Encoder) -> DuktapeResult<()> {
+                let &($(ref $name,)+) = self;
+                $(try!($name.duktape_encode(s));)+
+                Ok(())
+            }
+        }
+    }
+}
+
+tuple_into_args!(1; A);
+tuple_into_args!(2; A, B);
+tuple_into_args!(3; A, B, C);
+tuple_into_args!(4; A, B, C, D);
+tuple_into_args!(5; A, B, C, D, E);
+tuple_into_args!(6; A, B, C, D, E, F);
+tuple_into_args!(7; A, B, C, D, E, F, G);
+tuple_into_args!(8; A, B, C, D, E, F, G, H);
+tuple_into_args!(9; A, B, C, D, E, F, G, H, I);
+tuple_into_args!(10; A, B, C, D, E, F, G, H, I, J);
+tuple_into_args!(11; A, B, C, D, E, F, G, H, I, J, K);
+tuple_into_args!(12; A, B, C, D, E, F, G, H, I, J, K, L);
+
+/// Implement `IntoArgs` for references to arrays of `&DuktapeEncodable`,
+/// so that `ctx.call("f", &[&a, &b])` works without slicing.
+macro_rules! array_into_args {
+    ($($count:expr),+) => {
+        $(
+            impl<'a, 'b> IntoArgs
+                for &'a [&'b (DuktapeEncodable + 'b); $count]
+            {
+                fn arg_count(&self) -> usize { $count }
+
+                fn encode_args(&self, s: &mut Encoder) -> DuktapeResult<()> {
+                    (&self[]).encode_args(s)
+                }
+            }
+        )+
+    }
+}
+
+/// Implement `IntoArgs` for references to arrays of `&T`, so that
+/// `&[&1.0f64, &2.0f64]` works without casting each element.  We skip the
+/// empty array, so that `&[]` can only be an array of `&DuktapeEncodable`.
+macro_rules! typed_array_into_args {
+    ($($count:expr),+) => {
+        $(
+            impl<'a, 'b, T: DuktapeEncodable> IntoArgs for &'a [&'b T; $count] {
+                fn arg_count(&self) -> usize { $count }
+
+                fn encode_args(&self, s: &mut Encoder) -> DuktapeResult<()> {
+                    for arg in self.iter() {
+                        try!(arg.duktape_encode(s));
+                    }
+                    Ok(())
+                }
+            }
+        )+
+    }
+}
+
+array_into_args!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12);
+typed_array_into_args!(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12);
+
+#[test]
+fn test_into_args() {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::u64;
+    use context::Context;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("function list() {\
+                return Array.prototype.slice.call(arguments).join(',');\
+              }\
+              function count() { return arguments.length; }\
+              function self() { return this.name; }").unwrap();
+
+    assert_eq!(Ok(Value::Number(0.0)), ctx.call("count", ()));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("1,two,3,4"))),
+               ctx.call("list", (1.0f64, "two", vec!(3.0f64, 4.0f64))));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("5,true"))),
+               ctx.call("list", (5i32, true)));
+    assert_eq!(Ok(Value::Number(12.0)),
+               ctx.call("count", (1u8, 2u8, 3u8, 4u8, 5u8, 6u8,
+                                  7u8, 8u8, 9u8, 10u8, 11u8, 12u8)));
+    assert_eq!(Ok(Value::String(Cow::Borrowed(",,x"))),
+               ctx.call("list", vec!(Value::Undefined, Value::Null,
+                                     Value::String(Cow::Borrowed("x")))));
+    let args: Vec<&DuktapeEncodable> = vec!(&1.0f64, &"a");
+    assert_eq!(Ok(Value::String(Cow::Borrowed("1,a"))),
+               ctx.call("list", &args[]));
+    assert_eq!(Ok(Value::Number(0.0)), ctx.call("count", &[]));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("2,1"))),
+               ctx.call("list", &[&2.0f64, &1.0f64]));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("1,b"))),
+               ctx.call("list", &[&1.0f64 as &DuktapeEncodable, &"b"]));
+
+    // Arguments which can't be encoded are reported as errors.
+    assert!(ctx.call("list", (1.0f64, u64::MAX)).is_err());
+
+    // We can also supply an explicit `this`.
+    let mut this = HashMap::new();
+    this.insert("name".to_string(), "obj".to_string());
+    assert_eq!(Ok(Value::String(Cow::Borrowed("obj"))),
+               ctx.apply("self", &this, ()));
+    assert!(ctx.apply("missing", &this, ()).is_err());
+}
@@ -0,0 +1,207 @@
+//! Arguments passed from JavaScript to a Rust `Callback`.  Each argument
+//! is decoded on demand, so a callback can accept optional or
+//! variable-length arguments, and a bad argument produces a `TypeError`
+//! naming it.
+//!
+//! ```
+//! use duktape::{Context, Borrowed, Args, Value, DuktapeEncodable,
+//!               DuktapeResult};
+//!
+//! fn sum(_ctx: &mut Context<Borrowed>, args: &Args) ->
+//!     DuktapeResult<Box<DuktapeEncodable + 'static>>
+//! {
+//!     let scale: f64 = try!(args.require(0));
+//!     let xs: Vec<f64> = try!(args.rest(1));
+//!     Ok(Box::new(scale * xs.iter().fold(0.0, |a, &b| a + b)))
+//! }
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.register("sum", sum, None);
+//! assert_eq!(Ok(Value::Number(12.0)), ctx.eval("sum(2, 1, 2, 3)"));
+//! assert!(ctx.eval("sum('two', 1)").is_err());
+//! ```
+
+use rustc_serialize::Decodable;
+use ffi::*;
+use errors::*;
+use decoder::{Decoder, DuktapeDecodable};
+
+/// The arguments passed to a Rust `Callback` by JavaScript.  Arguments
+/// are only converted to Rust values when you ask for them, and any
+/// conversion errors will name the argument and the expected type.
+pub struct Args {
+    ctx: *mut duk_context,
+    len: usize
+}
+
+/// Create an `Args` object for the function call currently running on
+/// `ctx`.  Re-exported within the crate, but not outside.
+pub unsafe fn new_args(ctx: *mut duk_context) -> Args {
+    Args{ctx: ctx, len: duk_get_top(ctx) as usize}
+}
+
+/// Decode the value on the top of the stack, restoring the stack to
+/// `top` afterwards, whatever happens.
+unsafe fn decode_top<T: DuktapeDecodable>(ctx: *mut duk_context,
+                                          top: duk_idx_t) ->
+    DuktapeResult<T>
+{
+    let result = {
+        let mut decoder = Decoder::new(ctx);
+        Decodable::decode(&mut decoder)
+    };
+    duk_set_top(ctx, top);
+    result
+}
+
+/// Add a description of argument `label` to an error message.
+fn label_err(label: &str, err: DuktapeError) -> DuktapeError {
+    let msg = format!("{}: {}", label, err);
+    DuktapeError::from_code_and_str(err_code(&err), &msg[])
+}
+
+/// Decode argument `idx`, using `label` to describe it in any error
+/// message.  Re-exported within the crate, but not outside.
+pub fn arg_labeled<T: DuktapeDecodable>(args: &Args, idx: usize,
+                                        label: &str) -> DuktapeResult<T> {
+    if idx >= args.len {
+        let msg = format!("{}: missing", label);
+        return Err(DuktapeError::from_code_and_str(ErrorCode::Type,
+                                                   &msg[]));
+    }
+    unsafe {
+        let top = duk_get_top(args.ctx);
+        duk_dup(args.ctx, idx as duk_idx_t);
+        decode_top(args.ctx, top).map_err(|err| label_err(label, err))
+    }
+}
+
+impl Args {
+    /// The number of arguments passed to this function.  If the function
+    /// was registered with a fixed number of arguments, this will always
+    /// be that number.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Is argument `idx` missing or `undefined`?
+    pub fn is_undefined(&self, idx: usize) -> bool {
+        idx >= self.len ||
+            unsafe { duk_is_undefined(self.ctx, idx as duk_idx_t) != 0 }
+    }
+
+    /// Get argument `idx` as type `T`, or return `None` if it is missing
+    /// or has the wrong type.  This is analogous to duktape's `duk_get_*`
+    /// functions.
+    pub fn get<T: DuktapeDecodable>(&self, idx: usize) -> Option<T> {
+        match self.optional(idx) {
+            Ok(val) => val,
+            Err(_) => None
+        }
+    }
+
+    /// Get argument `idx` as type `T`, or return a `TypeError` naming the
+    /// argument if it is missing or can't be converted.  This is
+    /// analogous to duktape's `duk_require_*` functions.
+    pub fn require<T: DuktapeDecodable>(&self, idx: usize) ->
+        DuktapeResult<T>
+    {
+        arg_labeled(self, idx, &format!("argument {}", idx)[])
+    }
+
+    /// Get argument `idx` as type `T`, returning `None` if it is missing
+    /// or `undefined`, and a `TypeError` if it can't be converted.
+    pub fn optional<T: DuktapeDecodable>(&self, idx: usize) ->
+        DuktapeResult<Option<T>>
+    {
+        if self.is_undefined(idx) {
+            Ok(None)
+        } else {
+            self.require(idx).map(|v| Some(v))
+        }
+    }
+
+    /// Get the `this` binding of the current function call as type `T`.
+    pub fn this<T: DuktapeDecodable>(&self) -> DuktapeResult<T> {
+        unsafe {
+            let top = duk_get_top(self.ctx);
+            duk_push_this(self.ctx);
+            decode_top(self.ctx, top).map_err(|err| label_err("this", err))
+        }
+    }
+
+    /// Get all the arguments starting at `idx` as type `T`.  Useful for
+    /// functions which take a variable number of arguments.
+    pub fn rest<T: DuktapeDecodable>(&self, idx: usize) ->
+        DuktapeResult<Vec<T>>
+    {
+        let mut result = vec!();
+        for i in range(idx, self.len) {
+            result.push(try!(self.require(i)));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use errors::*;
+    use context::{Context, Borrowed};
+    use encoder::DuktapeEncodable;
+    use super::*;
+
+    pub fn describe(_ctx: &mut Context<Borrowed>, args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        let name: String = try!(args.require(0));
+        let count: Option<f64> = try!(args.optional(1));
+        let extras: Vec<f64> = try!(args.rest(2));
+        let desc = format!("{} {} {} {}", name, count.unwrap_or(-1.0),
+                           extras.len(), args.len());
+        Ok(Box::new(desc))
+    }
+
+    pub fn lenient(_ctx: &mut Context<Borrowed>, args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        Ok(Box::new(args.get(0).unwrap_or(-1.0f64)))
+    }
+
+    pub fn this_name(_ctx: &mut Context<Borrowed>, args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        let name: String = try!(args.this());
+        Ok(Box::new(name))
+    }
+}
+
+#[test]
+fn test_args() {
+    use std::borrow::Cow;
+    use types::Value;
+    use context::Context;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.register("describe", test::describe, None);
+    ctx.register("lenient", test::lenient, Some(1));
+    ctx.register("this_name", test::this_name, Some(0));
+
+    assert_eq!(Ok(Value::String(Cow::Borrowed("x -1 0 1"))),
+               ctx.eval("describe('x')"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("x 2 2 4"))),
+               ctx.eval("describe('x', 2, 3, 4)"));
+    assert_eq!(Err(DuktapeError::from_str(
+        "TypeError: argument 0: Expected string, got number")),
+        ctx.eval("describe(1)"));
+    assert_eq!(Err(DuktapeError::from_str(
+        "TypeError: argument 0: missing")),
+        ctx.eval("describe()"));
+    assert_eq!(Err(DuktapeError::from_str(
+        "TypeError: argument 3: Expected number, got boolean")),
+        ctx.eval("describe('x', 2, 3, true)"));
+
+    assert_eq!(Ok(Value::Number(2.0)), ctx.eval("lenient(2)"));
+    assert_eq!(Ok(Value::Number(-1.0)), ctx.eval("lenient('2')"));
+    assert_eq!(Ok(Value::Number(-1.0)), ctx.eval("lenient()"));
+
+    assert_eq!(Ok(Value::String(Cow::Borrowed("hi"))),
+               ctx.eval("this_name.call('hi')"));
+}
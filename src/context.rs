@@ -1,15 +1,30 @@
 use std::borrow::Cow;
+use std::string::CowString;
 use std::ffi::CString;
+use std::marker::PhantomData;
 use std::mem::transmute;
 use std::ops::Deref;
 use std::ptr::null_mut;
 use std::slice::from_raw_buf;
 use libc::c_void;
+use rustc_serialize::Decodable;
 use cesu8::{to_cesu8, from_cesu8};
 use ffi::*;
 use errors::*;
-use types::Value;
+use types::{Value, PropertyAttributes};
 use encoder::{Encoder, DuktapeEncodable};
+use decoder::{Decoder, DuktapeDecodable};
+use jsstring::from_lstring_lossy;
+use args::{Args, new_args};
+use arg_list::IntoArgs;
+use options::{EncoderOptions, DecoderOptions, Int64Layout, StringMode,
+              CompileMode, EvalOptions};
+use state;
+use stack::{self, StackFrame};
+use int64;
+use heap::{self, HeapStats};
+use source;
+#[cfg(feature = "debugger")] use profiler;
 
 /// To avoid massive debugging frustration, wrap stack manipulation code in
 /// this macro.
@@ -27,11 +42,22 @@ macro_rules! assert_stack_height_unchanged {
 /// Convert a duktape-format string into a Rust `String`.
 pub unsafe fn from_lstring(data: *const i8, len: duk_size_t) ->
     DuktapeResult<String>
+{
+    from_lstring_borrowed(data, len).map(|s| s.into_owned())
+}
+
+/// Convert a duktape-format string into a Rust string, borrowing the
+/// underlying data if it's already valid UTF-8.  This is true unless the
+/// string contains characters outside the Basic Multilingual Plane, which
+/// duktape represents as CESU-8 surrogate pairs.  The caller must choose
+/// a lifetime `'a` which doesn't outlive the duktape string.
+pub unsafe fn from_lstring_borrowed<'a>(data: *const i8, len: duk_size_t) ->
+    DuktapeResult<CowString<'a>>
 {
     let ptr = data as *const u8;
-    let bytes = from_raw_buf(&ptr, len as usize);
+    let bytes: &'a [u8] = transmute(from_raw_buf(&ptr, len as usize));
     match from_cesu8(bytes) {
-        Ok(str) => Ok(str.into_owned()),
+        Ok(str) => Ok(str),
         Err(_) => Err(DuktapeError::from_str("can't convert string to UTF-8"))
     }
 }
@@ -40,42 +66,125 @@ pub unsafe fn from_lstring(data: *const i8, len: duk_size_t) ->
 /// can't be accessed from JavaScript without a lot of trickery.
 const RUST_FN_PROP: [i8; 5] = [-1, 'r' as i8, 'f' as i8, 'n' as i8, 0];
 
-/// A Rust callback which can be invoked from JavaScript.
-pub type Callback = fn (&mut Context, &[Value<'static>]) ->
-    DuktapeResult<Value<'static>>;
+/// A Rust callback which can be invoked from JavaScript.  The callback
+/// may return any value which can be encoded, including a `Value`, a
+/// `Buffer`, or a struct which implements `Encodable`.
+pub type Callback = fn (&mut Context<Borrowed>, &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>;
+
+/// A Rust function which knows how to register itself with JavaScript,
+/// normally defined using the `js_fn!` macro.
+pub trait NativeFunction {
+    /// The number of arguments expected by this function, or `None` if it
+    /// accepts a variable number of arguments.
+    fn arg_count(&self) -> Option<u16>;
+
+    /// The `Callback` which implements this function.
+    fn callback(&self) -> Callback;
+}
 
 /// A duktape interpreter context.  An individual context is not
-/// re-entrant: You may only access it from one thread at a time.
-pub struct Context {
+/// re-entrant: You may only access it from one thread at a time.  A
+/// `Context` may be moved to another thread, but it can't be shared
+/// between threads.  To use JavaScript from several threads at once, see
+/// `Executor`.
+///
+/// The contexts passed to callbacks are `Context<Borrowed>`, which point
+/// into a heap owned by somebody else and can't leave their thread.
+pub struct Context<H = Owned> {
     ptr: *mut duk_context,
-    owned: bool
+    owned: bool,
+    _marker: PhantomData<H>
 }
 
+/// Marks a `Context` which owns its duktape heap, and which will destroy
+/// it when dropped.
+#[allow(missing_copy_implementations)]
+pub enum Owned {}
+
+/// Marks a `Context` which points into a heap owned by another
+/// `Context`, such as the one passed to a `Callback`.
+#[allow(missing_copy_implementations)]
+pub enum Borrowed {}
+
+// A duktape heap has no thread affinity, and a `Context` which owns its
+// heap is the only way to reach it outside of a callback.  Borrowed
+// contexts are not `Send`: a callback could otherwise swap the context it
+// was given with `mem::replace` and smuggle it onto another thread while
+// the owner keeps using the heap.
+unsafe impl Send for Context<Owned> {}
+
 impl Context {
     /// Create a new duktape context.
     pub fn new() -> DuktapeResult<Context> {
-        let ptr = unsafe {
-            duk_create_heap(None, None, None, null_mut(), None)
-        };
+        let ptr = unsafe { heap::create_heap() };
         if ptr.is_null() {
             Err(DuktapeError::from_str("Could not create heap"))
         } else {
-            Ok(Context{ptr: ptr, owned: true})
+            Ok(Context{ptr: ptr, owned: true, _marker: PhantomData})
         }
     }
+}
 
+impl Context<Borrowed> {
     /// Create a new duktape context by wrapping an existing mutable
     /// pointer.  This is potentially unsafe, because it allows you to
     /// create two Rust objects pointing to the same duktape interpreter!
-    /// So if you create a Context using this API
-    pub unsafe fn from_borrowed_mut_ptr(ptr: *mut duk_context) -> Context {
-        Context{ptr: ptr, owned: false}
+    /// The new context never destroys the heap, and it can't be sent to
+    /// another thread.
+    pub unsafe fn from_borrowed_mut_ptr(ptr: *mut duk_context) ->
+        Context<Borrowed>
+    {
+        Context{ptr: ptr, owned: false, _marker: PhantomData}
     }
+}
+
+impl<H> Context<H> {
 
     /// Get the underlying context pointer.  You generally don't need this
     /// unless you're implementing low-level add-ons to this library.
     pub unsafe fn as_mut_ptr(&mut self) -> *mut duk_context { self.ptr }
 
+    /// Set the options used when encoding Rust values as JavaScript
+    /// values, including the return values of callbacks.  This fails if
+    /// we can't install the globals needed by `Int64Layout::Object`.
+    pub fn set_encoder_options(&mut self, options: EncoderOptions) ->
+        DuktapeResult<()>
+    {
+        unsafe {
+            if options.int64 == Int64Layout::Object {
+                try!(int64::install(self.ptr));
+            }
+            state::get_or_install(self.ptr).encoder_options = options;
+        }
+        Ok(())
+    }
+
+    /// Get the options used when encoding Rust values.
+    pub fn encoder_options(&mut self) -> EncoderOptions {
+        unsafe { state::encoder_options(self.ptr) }
+    }
+
+    /// Set the options used when decoding JavaScript values as Rust
+    /// values, including the arguments of callbacks.  This fails if we
+    /// can't install the globals needed by `Int64Layout::Object`.
+    pub fn set_decoder_options(&mut self, options: DecoderOptions) ->
+        DuktapeResult<()>
+    {
+        unsafe {
+            if options.int64 == Int64Layout::Object {
+                try!(int64::install(self.ptr));
+            }
+            state::get_or_install(self.ptr).decoder_options = options;
+        }
+        Ok(())
+    }
+
+    /// Get the options used when decoding JavaScript values.
+    pub fn decoder_options(&mut self) -> DecoderOptions {
+        unsafe { state::decoder_options(self.ptr) }
+    }
+
     /// Debugging: Dump the interpreter context.
     #[allow(dead_code)]
     fn dump_context(&mut self) -> String {
@@ -93,7 +202,17 @@ impl Context {
     /// Get the specified value from our context, and convert it to a Rust
     /// type.  This is a low-level, unsafe function, and you won't normally
     /// need to call it.
-    unsafe fn get(&mut self, idx: duk_idx_t) -> DuktapeResult<Value<'static>> {
+    pub unsafe fn get(&mut self, idx: duk_idx_t) -> DuktapeResult<Value<'static>> {
+        self.get_borrowed(idx).map(|v| v.into_owned())
+    }
+
+    /// Like `get`, but strings borrow their data from the duktape heap
+    /// instead of being copied whenever possible.  The result borrows the
+    /// context, but you're responsible for not removing the value at `idx`
+    /// from the stack while the result is still alive.
+    pub unsafe fn get_borrowed<'a>(&'a mut self, idx: duk_idx_t) ->
+        DuktapeResult<Value<'a>>
+    {
         match duk_get_type(self.ptr, idx) {
             DUK_TYPE_UNDEFINED => Ok(Value::Undefined),
             DUK_TYPE_NULL => Ok(Value::Null),
@@ -107,9 +226,17 @@ impl Context {
             DUK_TYPE_STRING => {
                 let mut len: duk_size_t = 0;
                 let str = duk_get_lstring(self.ptr, idx, &mut len);
-                Ok(Value::String(Cow::Owned(try!(from_lstring(str, len)))))
+                match (from_lstring_borrowed(str, len),
+                       state::decoder_options(self.ptr).strings) {
+                    (Ok(s), _) => Ok(Value::String(s)),
+                    (Err(_), StringMode::Lossy) =>
+                        Ok(Value::String(Cow::Owned(
+                            from_lstring_lossy(str, len)))),
+                    (Err(err), StringMode::Strict) => Err(err)
+                }
             }
-            _ => panic!("Cannot convert duktape data type")
+            _ => Err(DuktapeError::from_code_and_str(
+                ErrorCode::Type, "Cannot convert duktape data type"))
         }
     }
 
@@ -130,10 +257,13 @@ impl Context {
     }
 
     /// Push an encodable value onto the call stack.  We can push any data
-    /// type that implements Encodable.
-    pub unsafe fn push<T: DuktapeEncodable>(&mut self, object: &T) {
+    /// type that implements Encodable.  If the value can't be encoded,
+    /// the stack is left unchanged.
+    pub unsafe fn push<T: DuktapeEncodable>(&mut self, object: &T) ->
+        DuktapeResult<()>
+    {
         let mut encoder = Encoder::new(self.ptr);
-        object.duktape_encode(&mut encoder).unwrap();
+        object.duktape_encode(&mut encoder)
     }        
 
     /// Interpret the value on the top of the stack as either a return
@@ -144,11 +274,43 @@ impl Context {
         if status == DUK_EXEC_SUCCESS {
             self.get(-1)
         } else {
-            let mut len: duk_size_t = 0;
-            let str = duk_safe_to_lstring(self.ptr, -1, &mut len);
-            let msg = try!(from_lstring(str, len));
-            Err(DuktapeError::from_str(&msg[]))
+            Err(self.get_error(-1))
+        }
+    }
+
+    /// Convert the error at `idx` into a `DuktapeError`.  The message is
+    /// converted lossily, because we'd rather report a slightly mangled
+    /// error than a conversion failure.
+    unsafe fn get_error(&mut self, idx: duk_idx_t) -> DuktapeError {
+        let idx = duk_normalize_index(self.ptr, idx);
+        duk_dup(self.ptr, idx);
+        let mut len: duk_size_t = 0;
+        let str = duk_safe_to_lstring(self.ptr, -1, &mut len);
+        let msg = from_lstring_lossy(str, len);
+        duk_pop(self.ptr);
+        let mut err = DuktapeError::from_str(&msg[]);
+
+        // Script errors know where they were thrown.  These may be
+        // getters which throw, so we read them inside a protected call,
+        // and just skip the location if that fails.
+        if duk_is_object(self.ptr, idx) != 0 &&
+            duk_check_stack(self.ptr, 3) != 0
+        {
+            duk_dup(self.ptr, idx);
+            let status =
+                duk_safe_call(self.ptr, Some(get_error_location), 1, 2);
+            if status == DUK_EXEC_SUCCESS &&
+                duk_is_string(self.ptr, -2) != 0 &&
+                duk_is_number(self.ptr, -1) != 0
+            {
+                let str = duk_get_lstring(self.ptr, -2, &mut len);
+                let file = from_lstring_lossy(str, len);
+                let line = duk_get_number(self.ptr, -1) as u32;
+                source::locate_error(self.ptr, &mut err, &file[], line);
+            }
+            duk_pop_2(self.ptr);
         }
+        err
     }
 
     /// Given the status code returned by a duktape exec function, pop
@@ -174,60 +336,252 @@ impl Context {
     {
         unsafe {
             assert_stack_height_unchanged!(self, {
-                // Push our filename parameter and evaluate our code.
-                duk_push_lstring(self.ptr, filename.as_ptr() as *const i8,
-                                 filename.len() as duk_size_t);
-                let status = duk_eval_raw(self.ptr, code.as_ptr() as *const i8,
-                                          code.len() as duk_size_t,
-                                          DUK_COMPILE_EVAL |
-                                          DUK_COMPILE_NOSOURCE |
-                                          DUK_COMPILE_SAFE);
+                let status = self.eval_raw(filename, code);
                 self.pop_result(status)
             })
         }
     }
 
+    /// Evaluate JavaScript source code as specified by `options`, and
+    /// decode the result as type `T`.
+    ///
+    /// ```
+    /// use std::default::Default;
+    /// use duktape::{Context, EvalOptions};
+    ///
+    /// let mut ctx = Context::new().unwrap();
+    /// let strict = EvalOptions{strict: true, ..Default::default()};
+    /// assert!(ctx.eval_with::<()>(&strict, "undeclared = 1;").is_err());
+    /// let x: f64 = ctx.eval_with(&Default::default(), "2 + 2").unwrap();
+    /// assert_eq!(4.0, x);
+    /// ```
+    pub fn eval_with<T: DuktapeDecodable>(&mut self, options: &EvalOptions,
+                                          code: &str) -> DuktapeResult<T>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                let status = self.eval_raw_with(options, code);
+                let result = if status != DUK_EXEC_SUCCESS {
+                    Err(self.get_error(-1))
+                } else {
+                    let mut decoder = Decoder::new(self.ptr);
+                    Decodable::decode(&mut decoder)
+                };
+                duk_pop(self.ptr);
+                result
+            })
+        }
+    }
+
+    /// Like `eval_with`, but throw away the result without converting it,
+    /// which saves time when it isn't needed.  Errors are still reported
+    /// in full.  We don't use `DUK_COMPILE_NORESULT` for this, because it
+    /// makes duktape discard errors along with results.
+    pub fn eval_with_no_result(&mut self, options: &EvalOptions,
+                               code: &str) -> DuktapeResult<()>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                let status = self.eval_raw_with(options, code);
+                let result = if status != DUK_EXEC_SUCCESS {
+                    Err(self.get_error(-1))
+                } else {
+                    Ok(())
+                };
+                duk_pop(self.ptr);
+                result
+            })
+        }
+    }
+
+    /// Evaluate JavaScript source code and pass the result to `f`.  Unlike
+    /// `eval`, strings will borrow their data from the duktape heap
+    /// whenever possible, which avoids copying large results.
+    pub fn eval_borrowed<R, F>(&mut self, code: &str, f: F) ->
+        DuktapeResult<R>
+        where F: for<'a> FnOnce(Value<'a>) -> R
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                let status = self.eval_raw("<eval>", code);
+                let result = if status == DUK_EXEC_SUCCESS {
+                    self.get_borrowed(-1).map(f)
+                } else {
+                    Err(self.get_error(-1))
+                };
+                duk_pop(self.ptr);
+                result
+            })
+        }
+    }
+
+    /// Evaluate JavaScript source code, leaving either the result or an
+    /// error on the stack, and return the duktape status code.
+    pub unsafe fn eval_raw(&mut self, filename: &str, code: &str) ->
+        duk_int_t
+    {
+        self.eval_raw_flags(filename, code, DUK_COMPILE_EVAL)
+    }
+
+    /// Like `eval_raw`, but compile the code as specified by `options`.
+    pub unsafe fn eval_raw_with(&mut self, options: &EvalOptions,
+                                code: &str) -> duk_int_t
+    {
+        let mut flags = match options.mode {
+            CompileMode::Eval => DUK_COMPILE_EVAL,
+            CompileMode::Program => 0,
+            CompileMode::Function => DUK_COMPILE_FUNCTION
+        };
+        if options.strict { flags |= DUK_COMPILE_STRICT; }
+        self.eval_raw_flags(&options.filename[], code, flags)
+    }
+
+    /// Evaluate JavaScript source code using the compile flags `flags`,
+    /// plus whatever we always need.
+    unsafe fn eval_raw_flags(&mut self, filename: &str, code: &str,
+                             flags: duk_uint_t) -> duk_int_t
+    {
+        before_eval(self, filename, code);
+        let flags = flags | DUK_COMPILE_SAFE |
+            source::prepare_compile(self.ptr, filename, code);
+
+        // Push our filename parameter and evaluate our code.
+        duk_push_lstring(self.ptr, filename.as_ptr() as *const i8,
+                         filename.len() as duk_size_t);
+        duk_eval_raw(self.ptr, code.as_ptr() as *const i8,
+                     code.len() as duk_size_t, flags)
+    }
+
     /// Call the global JavaScript function named `fn_name` with `args`, and
-    /// return the result.
-    pub fn call(&mut self, fn_name: &str, args: &[&DuktapeEncodable]) ->
+    /// return the result.  `args` may be a tuple, `()`, a `Vec<Value>`,
+    /// or a slice or array of `&DuktapeEncodable` references, as in
+    /// `ctx.call("add", &[&2.0f64, &1.0f64])`.
+    pub fn call<A: IntoArgs>(&mut self, fn_name: &str, args: A) ->
         DuktapeResult<Value<'static>>
     {
         unsafe {
-            assert_stack_height_unchanged!(self, {
-                duk_push_global_object(self.ptr);
-                let c_str = CString::from_slice(fn_name.as_bytes());
-                duk_get_prop_string(self.ptr, -1, c_str.as_ptr());
-                {
-                    let mut encoder = Encoder::new(self.ptr);
-                    for arg in args.iter() {
-                        (*arg).duktape_encode(&mut encoder).unwrap();
-                    }
-                }
-                let status = duk_pcall(self.ptr, args.len() as i32);
-                let result = self.pop_result(status);
-                duk_pop(self.ptr); // Remove global object.
+            self.call_and_convert(fn_name, None, &args, |ctx| ctx.get(-1))
+        }
+    }
+
+    /// Call the global JavaScript function named `fn_name` with `args`,
+    /// using `this` as the value of `this`, and return the result.
+    pub fn apply<T, A>(&mut self, fn_name: &str, this: &T, args: A) ->
+        DuktapeResult<Value<'static>>
+        where T: DuktapeEncodable, A: IntoArgs
+    {
+        unsafe {
+            self.call_and_convert(fn_name, Some(this as &DuktapeEncodable),
+                                  &args, |ctx| ctx.get(-1))
+        }
+    }
+
+    /// Call the global JavaScript function named `fn_name` with `args`, and
+    /// decode the result as type `T`.
+    pub fn call_as<T, A>(&mut self, fn_name: &str, args: A) ->
+        DuktapeResult<T>
+        where T: DuktapeDecodable, A: IntoArgs
+    {
+        self.call_and_decode(None, fn_name, args)
+    }
+
+    /// Like `call_as`, but decode the result using `options` instead of
+    /// the context's decoder options.  This is handy for decoding a
+    /// single result with a different `StringMode`.
+    ///
+    /// ```
+    /// use std::default::Default;
+    /// use duktape::{Context, DecoderOptions, StringMode};
+    ///
+    /// let mut ctx = Context::new().unwrap();
+    /// ctx.eval("function broken() { return '\\ud800'; }").unwrap();
+    /// let lossy = DecoderOptions{strings: StringMode::Lossy,
+    ///                            .. Default::default()};
+    /// let s: String = ctx.call_as_with(&lossy, "broken", ()).unwrap();
+    /// assert_eq!("\u{fffd}", s);
+    /// ```
+    pub fn call_as_with<T, A>(&mut self, options: &DecoderOptions,
+                              fn_name: &str, args: A) -> DuktapeResult<T>
+        where T: DuktapeDecodable, A: IntoArgs
+    {
+        self.call_and_decode(Some(options), fn_name, args)
+    }
+
+    /// Call the global JavaScript function named `fn_name` with `args`,
+    /// and decode the result using `options`, or the context's decoder
+    /// options if `options` is `None`.
+    fn call_and_decode<T, A>(&mut self, options: Option<&DecoderOptions>,
+                             fn_name: &str, args: A) -> DuktapeResult<T>
+        where T: DuktapeDecodable, A: IntoArgs
+    {
+        unsafe {
+            self.call_and_convert(fn_name, None, &args, |ctx| {
+                let top = duk_get_top(ctx.ptr);
+                duk_dup_top(ctx.ptr);
+                let result = {
+                    let mut decoder = match options {
+                        Some(options) =>
+                            Decoder::with_options(ctx.ptr, options.clone()),
+                        None => Decoder::new(ctx.ptr)
+                    };
+                    Decodable::decode(&mut decoder)
+                };
+                duk_set_top(ctx.ptr, top);
                 result
             })
         }
     }
 
+    /// Call the global JavaScript function named `fn_name` with `args`,
+    /// and use `convert` to convert the result on top of the stack.  If
+    /// `this` is `None`, it will be `undefined`.
+    unsafe fn call_and_convert<T, F>(&mut self, fn_name: &str,
+                                     this: Option<&DuktapeEncodable>,
+                                     args: &IntoArgs, convert: F) ->
+        DuktapeResult<T>
+        where F: FnOnce(&mut Context<H>) -> DuktapeResult<T>
+    {
+        assert_stack_height_unchanged!(self, {
+            let top = duk_get_top(self.ptr);
+            duk_push_global_object(self.ptr);
+            let c_str = CString::from_slice(fn_name.as_bytes());
+            duk_get_prop_string(self.ptr, -1, c_str.as_ptr());
+            let encoded = {
+                let mut encoder = Encoder::new(self.ptr);
+                match this {
+                    Some(this) => this.duktape_encode(&mut encoder),
+                    None => {
+                        duk_push_undefined(self.ptr);
+                        Ok(())
+                    }
+                }.and_then(|()| args.encode_args(&mut encoder))
+            };
+            if let Err(err) = encoded {
+                // Remove our function, global object, and any
+                // arguments we managed to encode.
+                duk_set_top(self.ptr, top);
+                return Err(err);
+            }
+            let status = duk_pcall_method(self.ptr,
+                                          args.arg_count() as duk_idx_t);
+            let result = if status == DUK_EXEC_SUCCESS {
+                convert(self)
+            } else {
+                Err(self.get_error(-1))
+            };
+            duk_set_top(self.ptr, top); // Remove result and global object.
+            result
+        })
+    }
+
     /// Register a Rust callback as a global JavaScript function.
     pub fn register(&mut self, fn_name: &str, f: Callback,
                     arg_count: Option<u16>) {
-        let c_arg_count =
-            arg_count.map(|n| n as duk_int_t).unwrap_or(DUK_VARARGS);
         unsafe {
             assert_stack_height_unchanged!(self, {
-                // Push our global context and a pointer to our standard
-                // wrapper function.
+                // Push our global context and our function.
                 duk_push_global_object(self.ptr);
-                duk_push_c_function(self.ptr,
-                                    Some(rust_duk_callback),
-                                    c_arg_count);
-
-                // Store `f` as a hidden property in our function.
-                duk_push_pointer(self.ptr, f as *mut c_void);
-                duk_put_prop_string(self.ptr, -2, RUST_FN_PROP.as_ptr());
+                self.push_callback(f, arg_count);
 
                 // Store our function in a global property.
                 let c_str = CString::from_slice(fn_name.as_bytes());
@@ -236,16 +590,305 @@ impl Context {
             })
         }
     }
+
+    /// Push a JavaScript function which calls the Rust callback `f`.
+    pub unsafe fn push_callback(&mut self, f: Callback,
+                                arg_count: Option<u16>) {
+        let c_arg_count =
+            arg_count.map(|n| n as duk_int_t).unwrap_or(DUK_VARARGS);
+
+        // Push a pointer to our standard C trampoline.
+        duk_push_c_function(self.ptr, Some(duk_rust_trampoline),
+                            c_arg_count);
+
+        // Tell the trampoline to call our standard wrapper function.
+        duk_push_pointer(self.ptr, rust_duk_callback as *mut c_void);
+        duk_put_prop_string(self.ptr, -2, DUK_RUST_HANDLER_PROP.as_ptr());
+
+        // Store `f` as a hidden property in our function.
+        duk_push_pointer(self.ptr, f as *mut c_void);
+        duk_put_prop_string(self.ptr, -2, RUST_FN_PROP.as_ptr());
+    }
+
+    /// Run `f` with a new `StackFrame`, which provides safe access to the
+    /// duktape value stack.  Anything pushed onto the frame will be
+    /// removed when `f` returns.
+    pub fn with_frame<R, F>(&mut self, f: F) -> R
+        where F: for<'f> FnOnce(&mut StackFrame<'f>) -> R
+    {
+        stack::with_frame(self, f)
+    }
+
+    /// Register a `NativeFunction`, typically defined using `js_fn!`, as
+    /// a global JavaScript function.
+    pub fn register_fn<F: NativeFunction>(&mut self, fn_name: &str, f: F) {
+        self.register(fn_name, f.callback(), f.arg_count())
+    }
+
+    /// Set the global variable `name` to `value`.  Unlike generating
+    /// source code for `eval`, this works for any value and name.  Fails
+    /// if the variable is read-only.
+    pub fn set_global<T: DuktapeEncodable>(&mut self, name: &str,
+                                           value: &T) -> DuktapeResult<()> {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                                 name.len() as duk_size_t);
+                if let Err(err) = self.push(value) {
+                    duk_pop(self.ptr);
+                    return Err(err);
+                }
+                // The C API uses strict mode, so assigning to a read-only
+                // property throws.
+                let status =
+                    duk_safe_call(self.ptr, Some(put_global_prop), 2, 1);
+                self.pop_result(status).map(|_| ())
+            })
+        }
+    }
+
+    /// Get the global variable `name` as type `T`.  Missing variables are
+    /// `undefined`, so use an `Option` if they might not exist.
+    pub fn get_global<T: DuktapeDecodable>(&mut self, name: &str) ->
+        DuktapeResult<T>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                let top = duk_get_top(self.ptr);
+                try!(get_global(self.ptr, name));
+                let result = {
+                    let mut decoder = Decoder::new(self.ptr);
+                    Decodable::decode(&mut decoder)
+                };
+                duk_set_top(self.ptr, top);
+                result
+            })
+        }
+    }
+
+    /// Is there a global variable named `name`?
+    pub fn has_global(&mut self, name: &str) -> bool {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                duk_push_global_object(self.ptr);
+                duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                                 name.len() as duk_size_t);
+                let found = duk_has_prop(self.ptr, -2) != 0;
+                duk_pop(self.ptr);
+                found
+            })
+        }
+    }
+
+    /// Delete the global variable `name`.  Returns false if the variable
+    /// exists but can't be deleted.
+    pub fn delete_global(&mut self, name: &str) -> bool {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                delete_global_bytes(self.ptr, name.as_bytes())
+            })
+        }
+    }
+
+    /// Define the global variable `name` with the specified attributes,
+    /// as if by `Object.defineProperty`.  This fails if `name` already
+    /// exists and isn't configurable.
+    pub fn define_global<T: DuktapeEncodable>(&mut self, name: &str,
+                                              value: &T,
+                                              attrs: PropertyAttributes) ->
+        DuktapeResult<()>
+    {
+        unsafe {
+            assert_stack_height_unchanged!(self, {
+                let top = duk_get_top(self.ptr);
+                if duk_check_stack(self.ptr, 6) == 0 {
+                    return Err(DuktapeError::from_code(ErrorCode::Alloc));
+                }
+
+                // Push `Object.defineProperty`, then its arguments: the
+                // global object, `name`, and an empty descriptor.
+                let c_object = CString::from_slice(b"Object");
+                let c_define = CString::from_slice(b"defineProperty");
+                duk_get_global_string(self.ptr, c_object.as_ptr());
+                duk_get_prop_string(self.ptr, -1, c_define.as_ptr());
+                duk_remove(self.ptr, -2);
+                duk_push_global_object(self.ptr);
+                duk_push_lstring(self.ptr, name.as_ptr() as *const i8,
+                                 name.len() as duk_size_t);
+                duk_push_object(self.ptr);
+
+                // Fill in the descriptor.
+                if let Err(err) = self.push(value) {
+                    duk_set_top(self.ptr, top);
+                    return Err(err);
+                }
+                let c_value = CString::from_slice(b"value");
+                duk_put_prop_string(self.ptr, -2, c_value.as_ptr());
+                let flags = [("writable", attrs.writable),
+                             ("enumerable", attrs.enumerable),
+                             ("configurable", attrs.configurable)];
+                for &(key, flag) in flags.iter() {
+                    let c_key = CString::from_slice(key.as_bytes());
+                    duk_push_boolean(self.ptr, flag as duk_bool_t);
+                    duk_put_prop_string(self.ptr, -2, c_key.as_ptr());
+                }
+
+                let status = duk_pcall(self.ptr, 3);
+                let result = self.pop_result(status);
+                duk_set_top(self.ptr, top);
+                result.map(|_| ())
+            })
+        }
+    }
+
+    /// Run a full garbage collection cycle, freeing any unreachable
+    /// objects, including those in reference cycles.
+    pub fn gc(&mut self) {
+        unsafe {
+            duk_gc(self.ptr, 0);
+            if let Some(state) = state::get(self.ptr) {
+                if !state.counters.is_null() {
+                    (*state.counters).explicit_gc_calls += 1;
+                }
+            }
+        }
+    }
+
+    /// Get a snapshot of this heap's memory usage.
+    pub fn heap_stats(&mut self) -> HeapStats {
+        heap::stats(self)
+    }
+
+    /// Shrink the property storage of the global object `name` to fit
+    /// its current properties.  This is handy after deleting many
+    /// properties from a long-lived table.  Values which aren't objects
+    /// are left alone; use `StackFrame::compact` for other objects.
+    pub fn compact(&mut self, name: &str) -> DuktapeResult<()> {
+        self.with_frame(|frame| {
+            let obj = try!(frame.push_global(name));
+            frame.compact(obj)
+        })
+    }
 }
 
-impl Drop for Context {
+#[unsafe_destructor]
+impl<H> Drop for Context<H> {
   fn drop(&mut self) {
       if self.owned {
-          unsafe { duk_destroy_heap(self.ptr); }
+          unsafe {
+              heap::destroy_heap(self.ptr);
+          }
       }
   }
 }
 
+/// Let the profiler see each script before we compile it.  A callback's
+/// borrowed context is in the middle of running a script, so it isn't
+/// idle.
+#[cfg(feature = "debugger")]
+fn before_eval<H>(ctx: &mut Context<H>, filename: &str, code: &str) {
+    let idle = ctx.owned;
+    profiler::before_eval(ctx, filename, code, idle);
+}
+
+/// Without the debugger, there's no profiler to tell.
+#[cfg(not(feature = "debugger"))]
+fn before_eval<H>(_ctx: &mut Context<H>, _filename: &str, _code: &str) {}
+
+/// Replace the error on top of the stack with its `fileName` and
+/// `lineNumber`.  Called using `duk_safe_call`, which shares our caller's
+/// stack frame.
+unsafe extern "C" fn get_error_location(ctx: *mut duk_context) ->
+    duk_ret_t
+{
+    let c_file = CString::from_slice(b"fileName");
+    let c_line = CString::from_slice(b"lineNumber");
+    duk_get_prop_string(ctx, -1, c_file.as_ptr());
+    duk_get_prop_string(ctx, -2, c_line.as_ptr());
+    2
+}
+
+/// Assign the value on top of the stack to the global property named by
+/// the key below it.  Called using `duk_safe_call`, which shares our
+/// caller's stack frame.
+unsafe extern "C" fn put_global_prop(ctx: *mut duk_context) -> duk_ret_t {
+    duk_push_global_object(ctx);
+    duk_insert(ctx, -3);
+    duk_put_prop(ctx, -3);
+    1
+}
+
+/// Push the value of the global variable `name`.  Looking it up may call a
+/// getter, so we do it inside a protected call, and push nothing if it
+/// fails.
+pub unsafe fn get_global(ctx: *mut duk_context, name: &str) ->
+    DuktapeResult<()>
+{
+    if duk_check_stack(ctx, 2) == 0 {
+        return Err(DuktapeError::from_code(ErrorCode::Alloc));
+    }
+    duk_push_lstring(ctx, name.as_ptr() as *const i8,
+                     name.len() as duk_size_t);
+    let status = duk_safe_call(ctx, Some(get_global_prop), 1, 1);
+    if status != DUK_EXEC_SUCCESS {
+        let mut borrowed = Context::from_borrowed_mut_ptr(ctx);
+        return Err(borrowed.pop_result(status).err().unwrap());
+    }
+    Ok(())
+}
+
+/// Pop the value on top of the stack and assign it to the global variable
+/// `name`, inside a protected call.
+pub unsafe fn put_global(ctx: *mut duk_context, name: &str) ->
+    DuktapeResult<()>
+{
+    if duk_check_stack(ctx, 2) == 0 {
+        duk_pop(ctx);
+        return Err(DuktapeError::from_code(ErrorCode::Alloc));
+    }
+    duk_push_lstring(ctx, name.as_ptr() as *const i8,
+                     name.len() as duk_size_t);
+    duk_swap_top(ctx, -2);
+    let status = duk_safe_call(ctx, Some(put_global_prop), 2, 1);
+    let mut borrowed = Context::from_borrowed_mut_ptr(ctx);
+    borrowed.pop_result(status).map(|_| ())
+}
+
+/// Delete the global variable whose name is the duktape string `name`,
+/// which needn't be valid UTF-8.  Returns false if the variable can't be
+/// deleted.
+pub unsafe fn delete_global_bytes(ctx: *mut duk_context, name: &[u8]) ->
+    bool
+{
+    if duk_check_stack(ctx, 2) == 0 {
+        return false;
+    }
+    duk_push_lstring(ctx, name.as_ptr() as *const i8,
+                     name.len() as duk_size_t);
+    // Deleting a non-configurable property throws.
+    let status = duk_safe_call(ctx, Some(del_global_prop), 1, 1);
+    duk_pop(ctx);
+    status == DUK_EXEC_SUCCESS
+}
+
+/// Replace the key on top of the stack with the value of the global
+/// property it names.  Called using `duk_safe_call`.
+unsafe extern "C" fn get_global_prop(ctx: *mut duk_context) -> duk_ret_t {
+    duk_push_global_object(ctx);
+    duk_insert(ctx, -2);
+    duk_get_prop(ctx, -2);
+    1
+}
+
+/// Delete the global property named by the key on top of the stack.
+/// Called using `duk_safe_call`.
+unsafe extern "C" fn del_global_prop(ctx: *mut duk_context) -> duk_ret_t {
+    duk_push_global_object(ctx);
+    duk_insert(ctx, -2);
+    duk_del_prop(ctx, -2);
+    1
+}
+
 /// Our generic callback function.
 unsafe extern "C" fn rust_duk_callback(ctx: *mut duk_context) -> duk_ret_t {
     // ERROR-HANDLING NOTE: Try to avoid any Rust panics or duktape unwinds
@@ -270,50 +913,67 @@ unsafe extern "C" fn rust_duk_callback(ctx: *mut duk_context) -> duk_ret_t {
         assert!(p != null_mut());
         transmute(p)
     });
+    invoke_callback(&mut ctx, f)
+}
 
-    // Coerce our arguments to Rust values.
-    let arg_count = duk_get_top(ctx.ptr) as usize;
-    let mut args = Vec::with_capacity(arg_count);
-    for i in range(0, arg_count) {
-        match ctx.get(i as duk_idx_t) {
-            Ok(arg) => args.push(arg),
-            // Can't convert argument to Rust.
-            // TODO: Need testcase.
-            Err(_) => return DUK_RET_TYPE_ERROR
-        }
+/// The handler for functions created by `Module`, which share a single
+/// trampoline and are told apart by their magic values.  Re-exported
+/// within the crate, but not outside.
+pub unsafe extern "C" fn rust_duk_magic_callback(ctx: *mut duk_context) ->
+    duk_ret_t
+{
+    assert!(ctx != null_mut());
+    let mut ctx = Context::from_borrowed_mut_ptr(ctx);
+    let magic = duk_get_current_magic(ctx.ptr);
+    let f = state::get(ctx.ptr).and_then(|s| {
+        s.magic_callbacks.get(magic as usize).map(|&f| f)
+    });
+    match f {
+        Some(f) => invoke_callback(&mut ctx, f),
+        None => -(ErrorCode::Internal as duk_int_t)
     }
-    //println!("args: {}", args);
+}
 
-    // Call our function.
+/// Call `f` with the arguments of the current function call, and either
+/// push its return value or prepare an error for our trampoline to throw.
+unsafe fn invoke_callback(ctx: &mut Context<Borrowed>, f: Callback) ->
+    duk_ret_t
+{
+    // Wrap our arguments, which will be converted to Rust values on
+    // demand.
+    let args = new_args(ctx.ptr);
+
+    // Call our function, and push the return value onto the stack.
     let result =
         abort_on_panic!("unexpected panic in code called from JavaScript", {
-            f(&mut ctx, &args[])
+            f(ctx, &args).and_then(|val| {
+                let mut encoder = Encoder::new(ctx.ptr);
+                val.duktape_encode(&mut encoder)
+            })
         });
 
     // Return our result.
     match result {
-        // No return value.
-        Ok(Value::Undefined) => { 0 }
-        // A single return value.
-        Ok(ref val) => { ctx.push_old(val); 1 }
+        // A single return value, which may be `undefined`.
+        Ok(()) => { 1 }
         Err(ref err) => {
             let code = err_code(err) as duk_int_t;
             match err_message(err) {
-                // An error with an actual error message.
-                &Some(ref _msg) => {
-                    // The following would more-or-less work, but it
-                    // performs a non-local exit from a Rust function using
-                    // C APIs, which is a Bad Idea.
-                    //to_cesu8(&msg[]).with_c_str(|c_str| {
-                    //    duk_push_error_object_string(ctx.ptr, code,
-                    //                                 file!().as_ptr()
-                    //                                     as *const i8,
-                    //                                 line!() as i32,
-                    //                                 c_str as *const i8);
-                    //});
-                    //duk_throw(ctx.ptr);
-                    //-1
-                    DUK_RET_ERROR
+                // An error with an actual error message.  We can't call
+                // `duk_throw` ourselves, because that would perform a
+                // non-local exit from a Rust function using C APIs, which
+                // is a Bad Idea.  So we push an error object and ask
+                // `duk_rust_trampoline` to throw it for us.
+                &Some(ref msg) => {
+                    let encoded: Vec<u8> = to_cesu8(&msg[]).iter()
+                        .map(|&b| b).filter(|&b| b != 0).collect();
+                    let c_msg = CString::from_vec(encoded);
+                    duk_push_error_object_string(ctx.ptr, code,
+                                                 concat!(file!(), "\0")
+                                                     .as_ptr() as *const i8,
+                                                 line!() as i32,
+                                                 c_msg.as_ptr());
+                    DUK_RET_RUST_THROW
                 }
                 // A generic error using one of the standard codes.
                 &None => { -code }
@@ -350,10 +1010,39 @@ fn test_unicode_supplementary_planes() {
                ctx.call("id", &[&"𓀀"]));
 }
 
+#[test]
+fn test_borrowed_strings() {
+    fn is_borrowed(v: Value) -> bool {
+        match v {
+            Value::String(Cow::Borrowed(_)) => true,
+            _ => false
+        }
+    }
+
+    let mut ctx = Context::new().unwrap();
+    // Strings which are already valid UTF-8 aren't copied...
+    assert_eq!(Ok(true), ctx.eval_borrowed("'héllo'", is_borrowed));
+    assert_eq!(Ok(5), ctx.eval_borrowed("'hello'", |v| {
+        match v { Value::String(s) => s.len(), _ => 0 }
+    }));
+    // ...but CESU-8 surrogate pairs need to be converted.
+    assert_eq!(Ok(false), ctx.eval_borrowed("'𓀀'", is_borrowed));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("𓀀"))),
+               ctx.eval_borrowed("'𓀀'", |v| v.into_owned()));
+    assert!(ctx.eval_borrowed("throw new Error('x')", is_borrowed).is_err());
+}
+
 #[test]
 fn test_eval_errors() {
     let mut ctx = Context::new().unwrap();
     assert_eq!(true, ctx.eval("3 +").is_err());
+
+    // Locations which can't be read are ignored.
+    ctx.eval("var sneaky = { toString: function () { return 'sneaky'; },\
+                             get fileName() { throw new Error('no'); } };")
+        .unwrap();
+    assert_eq!(Err(DuktapeError::from_str("sneaky")),
+               ctx.eval("throw sneaky;"));
 }
 
 #[test]
@@ -378,36 +1067,52 @@ fn test_call_function_by_name() {
 mod test {
     use errors::*;
     use types::*;
+    use args::Args;
+    use encoder::{Encoder, DuktapeEncodable};
     use super::*;
 
-    pub fn rust_add(_ctx: &mut Context, args: &[Value<'static>]) -> 
-        DuktapeResult<Value<'static>>
+    pub fn rust_add(_ctx: &mut Context<Borrowed>, args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
     {
         let mut sum = 0.0;
-        for arg in args.iter() {
-            // TODO: Type checking.
-            if let &Value::Number(n) = arg {
-                sum += n;
-            }
+        for n in try!(args.rest::<f64>(0)).iter() {
+            sum += *n;
         }
-        Ok(Value::Number(sum))
+        Ok(Box::new(Value::Number(sum)))
     }
 
     macro_rules! rust_callback {
         ($name:ident, $retval:expr) => {
-            pub fn $name(_ctx: &mut Context, _args: &[Value<'static>]) ->
-                DuktapeResult<Value<'static>>
+            pub fn $name(_ctx: &mut Context<Borrowed>, _args: &Args) ->
+                DuktapeResult<Box<DuktapeEncodable + 'static>>
             {
                 $retval
             }
         }
     }
 
-    rust_callback!{rust_return_undefined, Ok(Value::Undefined)}
+    #[derive(RustcEncodable)]
+    pub struct Point { pub x: f64, pub y: f64 }
+
+    /// A value which always fails to encode.
+    pub struct Unencodable;
+
+    impl DuktapeEncodable for Unencodable {
+        fn duktape_encode(&self, _: &mut Encoder) -> DuktapeResult<()> {
+            Err(DuktapeError::from_str("can't encode"))
+        }
+    }
+
+    rust_callback!{rust_return_undefined, Ok(Box::new(Value::Undefined))}
     rust_callback!{rust_return_simple_error,
                    Err(DuktapeError::from_code(ErrorCode::Type))}
     rust_callback!{rust_return_custom_error,
                    Err(DuktapeError::from_str("custom error"))}
+    rust_callback!{rust_return_struct,
+                   Ok(Box::new(vec!(Point{x: 1.0, y: 2.0})))}
+    rust_callback!{rust_return_buffer,
+                   Ok(Box::new(Buffer(vec!(1u8, 2, 3))))}
+    rust_callback!{rust_return_unencodable, Ok(Box::new(Unencodable))}
 }
 
 #[test]
@@ -417,6 +1122,9 @@ fn test_callbacks() {
     // An ordinary function, with arguments and a useful return value.
     ctx.register("add", test::rust_add, Some(2));
     assert_eq!(Value::Number(5.0), ctx.eval("add(2.0, 3.0)").unwrap());
+    assert_eq!(Err(DuktapeError::from_str(
+        "TypeError: argument 1: Expected number, got string")),
+        ctx.eval("add(2.0, 'three')"));
 
     // A funtion which returns `undefined` (the same as having no return
     // value).
@@ -431,5 +1139,107 @@ fn test_callbacks() {
     // A function which returns a custom error with a string.
     ctx.register("custom_error", test::rust_return_custom_error, Some(0));
     let res = ctx.eval("custom_error()");
-    assert!(res.is_err());
+    assert_eq!(Err(DuktapeError::from_str("Error: custom error")), res);
+    assert_eq!(Value::String(Cow::Borrowed("custom error")),
+               ctx.eval("try { custom_error() } catch (e) { e.message }")
+                   .unwrap());
+
+    // Functions which return arbitrary encodable values.
+    ctx.register("ret_struct", test::rust_return_struct, Some(0));
+    assert_eq!(Value::String(Cow::Borrowed("[{\"x\":1,\"y\":2}]")),
+               ctx.eval("JSON.stringify(ret_struct())").unwrap());
+    ctx.register("ret_buffer", test::rust_return_buffer, Some(0));
+    assert_eq!(Value::String(Cow::Borrowed("buffer 3 2")),
+               ctx.eval("var b = ret_buffer(); \
+                         typeof b + ' ' + b.length + ' ' + b[1]").unwrap());
+
+    // Values which can't be encoded turn into JavaScript errors.
+    ctx.register("ret_unencodable", test::rust_return_unencodable, Some(0));
+    assert_eq!(Err(DuktapeError::from_str("Error: can't encode")),
+               ctx.eval("ret_unencodable()"));
+}
+
+#[test]
+fn test_globals() {
+    use std::default::Default;
+    use std::collections::BTreeMap;
+
+    let mut ctx = Context::new().unwrap();
+    let tricky = "'); throw new Error('injected'); ('";
+    ctx.set_global("config", &tricky).unwrap();
+    assert_eq!(Ok(tricky.to_string()), ctx.get_global::<String>("config"));
+    assert_eq!(Ok(Value::Number(tricky.len() as f64)),
+               ctx.eval("config.length"));
+
+    let mut map = BTreeMap::new();
+    map.insert("port".to_string(), 8080.0f64);
+    ctx.set_global("settings", &map).unwrap();
+    assert_eq!(Ok(Value::Number(8080.0)), ctx.eval("settings.port"));
+    assert_eq!(Ok(map), ctx.get_global("settings"));
+
+    // Names are passed through as-is, even with embedded NULs.
+    ctx.set_global("a\0b", &1.0f64).unwrap();
+    assert_eq!(Ok(1.0f64), ctx.get_global("a\0b"));
+    assert_eq!(Ok(None), ctx.get_global::<Option<f64>>("a"));
+
+    // Errors thrown by getters are returned.
+    ctx.eval("Object.defineProperty(this, 'broken', \
+              { get: function () { throw new Error('getter'); } });")
+        .unwrap();
+    assert_eq!(Err(DuktapeError::from_str("Error: getter")),
+               ctx.get_global::<f64>("broken"));
+
+    assert!(ctx.has_global("settings"));
+    assert!(!ctx.has_global("missing"));
+    assert_eq!(Ok(None), ctx.get_global::<Option<f64>>("missing"));
+    assert!(ctx.get_global::<f64>("missing").is_err());
+    assert!(ctx.delete_global("settings"));
+    assert!(!ctx.has_global("settings"));
+
+    // Read-only globals can't be changed by scripts.
+    let attrs = PropertyAttributes{writable: false, configurable: false,
+                                   .. Default::default()};
+    ctx.define_global("LIMIT", &10.0f64, attrs).unwrap();
+    ctx.eval("LIMIT = 20;").unwrap();
+    assert_eq!(Ok(10.0f64), ctx.get_global("LIMIT"));
+    assert!(ctx.set_global("LIMIT", &20.0f64).is_err());
+    assert!(!ctx.delete_global("LIMIT"));
+    assert!(ctx.define_global("LIMIT", &30.0f64, Default::default())
+               .is_err());
+}
+
+#[test]
+fn test_eval_with() {
+    use std::default::Default;
+
+    let mut ctx = Context::new().unwrap();
+    let defaults: EvalOptions = Default::default();
+    assert_eq!(Ok(4.0f64), ctx.eval_with(&defaults, "2 + 2"));
+    assert_eq!(Ok("x".to_string()), ctx.eval_with(&defaults, "'x'"));
+    assert!(ctx.eval_with::<f64>(&defaults, "'not a number'").is_err());
+
+    // Strict mode forbids assigning to undeclared variables.
+    let strict = EvalOptions{strict: true, .. Default::default()};
+    assert!(ctx.eval_with::<()>(&strict, "sloppy = 1;").is_err());
+    assert_eq!(Ok(1.0f64), ctx.eval_with(&defaults, "sloppy = 1;"));
+
+    // We can skip the result, but we still see errors.
+    let quiet = EvalOptions{filename: "quiet.js".to_string(),
+                            .. Default::default()};
+    assert_eq!(Ok(()),
+               ctx.eval_with_no_result(&quiet, "sloppy += 1; [1, 2, 3]"));
+    assert_eq!(Ok(2.0f64), ctx.get_global("sloppy"));
+    let err = ctx.eval_with_no_result(&quiet, "null.x").unwrap_err();
+    assert_eq!(Some("quiet.js"), err.file_name());
+    assert!(ctx.eval_with_no_result(&quiet, "3 +").is_err());
+
+    // Programs run at global scope, and functions are called for us.
+    let program = EvalOptions{mode: CompileMode::Program,
+                              .. Default::default()};
+    assert_eq!(Ok(()), ctx.eval_with(&program, "var fromProgram = 3;"));
+    assert_eq!(Ok(3.0f64), ctx.get_global("fromProgram"));
+    let function = EvalOptions{mode: CompileMode::Function,
+                               .. Default::default()};
+    assert_eq!(Ok(7.0f64),
+               ctx.eval_with(&function, "function () { return 7; }"));
 }
@@ -0,0 +1,304 @@
+//! Support for duktape's remote debugger protocol, which lets the
+//! standard duktape debug client set breakpoints, step through code and
+//! inspect variables.  This requires the `debugger` feature, which builds
+//! duktape with `DUK_OPT_DEBUGGER_SUPPORT`, and duktape 1.2 or later.
+//!
+//! ```no_run
+//! use duktape::{Context, TcpTransport, DEFAULT_DEBUG_PORT};
+//!
+//! let mut ctx = Context::new().unwrap();
+//! let addr = format!("127.0.0.1:{}", DEFAULT_DEBUG_PORT);
+//! // Wait for the debug client to connect.
+//! let transport = TcpTransport::listen(&addr[]).unwrap();
+//! ctx.attach_debugger(transport);
+//! // Execution pauses here until the client resumes it.
+//! ctx.eval("debugger; var x = 1;").unwrap();
+//! ctx.detach_debugger();
+//! ```
+//!
+//! Once a debugger is attached, a `debugger` statement pauses execution,
+//! and duktape blocks in `DebugTransport::read` until the client tells it
+//! to resume.  Without an attached debugger, `debugger` statements are
+//! ignored.
+
+use std::mem::transmute;
+use std::old_io::{IoResult, IoErrorKind, Reader, Writer, Listener, Acceptor};
+use std::old_io::net::tcp::{TcpListener, TcpStream};
+use std::slice::{from_raw_buf, from_raw_mut_buf};
+use libc::{c_void, c_char};
+use ffi::*;
+use context::Context;
+
+/// The TCP port used by the standard duktape debug client.
+pub const DEFAULT_DEBUG_PORT: u16 = 9091;
+
+/// A byte stream connecting duktape to a debug client.  Duktape speaks
+/// the debug protocol itself, so a transport just moves bytes.  Any error
+/// causes duktape to detach the debugger.
+pub trait DebugTransport: Send + 'static {
+    /// Read at least one byte into `buf`, blocking if necessary, and
+    /// return the number of bytes read.
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize>;
+
+    /// Write at least one byte from `buf`, and return the number of bytes
+    /// written.
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize>;
+
+    /// Return the number of bytes which can be read without blocking.
+    /// Duktape calls this while running to check for new commands.
+    fn peek(&mut self) -> IoResult<usize> { Ok(0) }
+
+    /// Called after duktape finishes reading a message.
+    fn read_flush(&mut self) {}
+
+    /// Called after duktape finishes writing a message.
+    fn write_flush(&mut self) -> IoResult<()> { Ok(()) }
+
+    /// Called when the debugger is detached, just before the transport
+    /// is dropped.
+    fn detached(&mut self) {}
+}
+
+/// A debug transport which talks to a client over TCP.
+pub struct TcpTransport {
+    stream: TcpStream,
+    /// Bytes we've read while peeking, but which duktape hasn't consumed.
+    peeked: Vec<u8>
+}
+
+impl TcpTransport {
+    /// Create a transport using a connected `stream`.
+    pub fn new(stream: TcpStream) -> TcpTransport {
+        TcpTransport{stream: stream, peeked: vec!()}
+    }
+
+    /// Listen on `addr`, and wait for a single debug client to connect.
+    pub fn listen(addr: &str) -> IoResult<TcpTransport> {
+        let listener = try!(TcpListener::bind(addr));
+        let mut acceptor = try!(listener.listen());
+        let stream = try!(acceptor.accept());
+        Ok(TcpTransport::new(stream))
+    }
+}
+
+impl DebugTransport for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.peeked.is_empty() {
+            return self.stream.read(buf);
+        }
+        let count = if buf.len() < self.peeked.len() {
+            buf.len()
+        } else {
+            self.peeked.len()
+        };
+        for (dst, src) in buf.iter_mut().zip(self.peeked.iter()) {
+            *dst = *src;
+        }
+        // Keep anything which didn't fit for the next read.
+        self.peeked = self.peeked[count..].to_vec();
+        Ok(count)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        try!(self.stream.write_all(buf));
+        Ok(buf.len())
+    }
+
+    fn peek(&mut self) -> IoResult<usize> {
+        if !self.peeked.is_empty() { return Ok(self.peeked.len()); }
+        let mut buf = [0u8; 256];
+        self.stream.set_read_timeout(Some(0));
+        let result = self.stream.read(&mut buf);
+        self.stream.set_read_timeout(None);
+        match result {
+            Ok(count) => {
+                self.peeked.push_all(&buf[..count]);
+                Ok(count)
+            }
+            Err(ref err) if err.kind == IoErrorKind::TimedOut => Ok(0),
+            Err(err) => Err(err)
+        }
+    }
+
+    fn write_flush(&mut self) -> IoResult<()> {
+        self.stream.flush()
+    }
+}
+
+type BoxedTransport = Box<DebugTransport + 'static>;
+
+unsafe fn transport<'a>(udata: *mut c_void) -> &'a mut BoxedTransport {
+    transmute(udata)
+}
+
+unsafe extern "C" fn debug_read(udata: *mut c_void, buffer: *mut c_char,
+                                length: duk_size_t) -> duk_size_t {
+    abort_on_panic!("unexpected panic in debugger transport", {
+        let ptr = buffer as *mut u8;
+        let buf = from_raw_mut_buf(&ptr, length as usize);
+        // Returning 0 tells duktape to detach.
+        transport(udata).read(buf).unwrap_or(0) as duk_size_t
+    })
+}
+
+unsafe extern "C" fn debug_write(udata: *mut c_void, buffer: *const c_char,
+                                 length: duk_size_t) -> duk_size_t {
+    abort_on_panic!("unexpected panic in debugger transport", {
+        let ptr = buffer as *const u8;
+        let buf = from_raw_buf(&ptr, length as usize);
+        transport(udata).write(buf).unwrap_or(0) as duk_size_t
+    })
+}
+
+unsafe extern "C" fn debug_peek(udata: *mut c_void) -> duk_size_t {
+    abort_on_panic!("unexpected panic in debugger transport", {
+        // We can't report errors here, so leave them for the next read.
+        transport(udata).peek().unwrap_or(0) as duk_size_t
+    })
+}
+
+unsafe extern "C" fn debug_read_flush(udata: *mut c_void) {
+    abort_on_panic!("unexpected panic in debugger transport", {
+        transport(udata).read_flush();
+    })
+}
+
+unsafe extern "C" fn debug_write_flush(udata: *mut c_void) {
+    abort_on_panic!("unexpected panic in debugger transport", {
+        let _ = transport(udata).write_flush();
+    })
+}
+
+/// Called by duktape exactly once per attach, including when the heap is
+/// destroyed, so this is where we free our transport.
+unsafe extern "C" fn debug_detached(udata: *mut c_void) {
+    abort_on_panic!("unexpected panic in debugger transport", {
+        let mut boxed: Box<BoxedTransport> = transmute(udata);
+        boxed.detached();
+    })
+}
+
+impl<H> Context<H> {
+    /// Attach a debugger which communicates using `transport`, detaching
+    /// any debugger which is already attached.  Duktape immediately sends
+    /// its protocol version to the client.
+    pub fn attach_debugger<T: DebugTransport>(&mut self, transport: T) {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            duk_debugger_detach(ptr);
+            let boxed: Box<BoxedTransport> = Box::new(Box::new(transport));
+            duk_debugger_attach(ptr, Some(debug_read), Some(debug_write),
+                                Some(debug_peek), Some(debug_read_flush),
+                                Some(debug_write_flush), Some(debug_detached),
+                                transmute(boxed));
+        }
+    }
+
+    /// Detach the current debugger, if any, and drop its transport.
+    pub fn detach_debugger(&mut self) {
+        unsafe { duk_debugger_detach(self.as_mut_ptr()); }
+    }
+
+    /// Process any pending debugger commands.  Duktape checks for
+    /// commands periodically while running code, so only call this while
+    /// the heap is otherwise idle.
+    pub fn debugger_cooperate(&mut self) {
+        unsafe { duk_debugger_cooperate(self.as_mut_ptr()); }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::old_io::{IoResult, IoError, IoErrorKind};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::DebugTransport;
+
+    /// A transport which records what duktape sends, and then fails as
+    /// soon as duktape tries to read a command.
+    pub struct Recorder {
+        pub written: Arc<AtomicUsize>,
+        pub detached: Arc<AtomicUsize>
+    }
+
+    impl DebugTransport for Recorder {
+        fn read(&mut self, _buf: &mut [u8]) -> IoResult<usize> {
+            Err(IoError{kind: IoErrorKind::EndOfFile, desc: "closed",
+                        detail: None})
+        }
+
+        fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+            self.written.fetch_add(buf.len(), Ordering::SeqCst);
+            Ok(buf.len())
+        }
+
+        fn detached(&mut self) {
+            self.detached.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[test]
+fn test_tcp_transport_keeps_peeked_bytes() {
+    use std::old_io::{Listener, Acceptor};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.socket_name().unwrap();
+    let mut acceptor = listener.listen().unwrap();
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"0123456789").unwrap();
+    });
+    let mut transport = TcpTransport::new(acceptor.accept().unwrap());
+    client.join().unwrap();
+
+    // Peek until everything has arrived, then read it in small pieces.
+    while transport.peek().unwrap() < 10 {
+        let mut more = [0u8; 1];
+        let count = transport.stream.read(&mut more).unwrap();
+        transport.peeked.push_all(&more[..count]);
+    }
+    let mut received = vec!();
+    while received.len() < 10 {
+        let mut buf = [0u8; 4];
+        let count = transport.read(&mut buf).unwrap();
+        received.push_all(&buf[..count]);
+    }
+    assert_eq!(b"0123456789".to_vec(), received);
+}
+
+#[test]
+fn test_debugger() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use types::Value;
+
+    let mut ctx = Context::new().unwrap();
+    let written = Arc::new(AtomicUsize::new(0));
+    let detached = Arc::new(AtomicUsize::new(0));
+    let recorder = || test::Recorder{
+        written: written.clone(), detached: detached.clone()
+    };
+
+    // Duktape introduces itself as soon as we attach.
+    ctx.attach_debugger(recorder());
+    assert!(written.load(Ordering::SeqCst) > 0);
+    ctx.detach_debugger();
+    assert_eq!(1, detached.load(Ordering::SeqCst));
+
+    // A `debugger` statement pauses and reads a command, and our
+    // transport's error detaches the debugger so we can keep running.
+    ctx.attach_debugger(recorder());
+    assert_eq!(Ok(Value::Number(2.0)), ctx.eval("debugger; 1 + 1"));
+    assert_eq!(2, detached.load(Ordering::SeqCst));
+
+    // Without a debugger, `debugger` statements do nothing.
+    assert_eq!(Ok(Value::Number(3.0)), ctx.eval("debugger; 1 + 2"));
+
+    // Destroying the heap detaches, too.
+    ctx.attach_debugger(recorder());
+    drop(ctx);
+    assert_eq!(3, detached.load(Ordering::SeqCst));
+}
@@ -1,25 +1,245 @@
+use std::ffi::CString;
 use std::iter::Iterator;
 use rustc_serialize::Decodable;
 use ffi::*;
 use errors::*;
-use context::{Context, from_lstring};
+use context::{Context, Borrowed, from_lstring};
+use encoder::MAX_SAFE_INTEGER;
+use options::*;
+use state;
+use int64;
+use jsstring;
 
 /// Translates JavaScript values into Rust values.
-#[allow(dead_code)] // WIP
 pub struct Decoder {
     /// An internal `Context` object, for convenience.  We own this,
     /// because if we use a reference to somebody else's, the lifetimes
     /// make it very hard to work with &Encodable references.
-    ctx: Context
+    ctx: Context<Borrowed>,
+
+    /// How we expect values with several possible encodings to look.
+    options: DecoderOptions,
+
+    /// Are we currently decoding the key of a map stored as an object?
+    /// If so, numbers will be represented as strings.
+    in_object_key: bool
 }
 
 impl Decoder {
     /// Create a new decoder which pops values from `ctx`.  If you create
     /// one of these, you're responsible for making sure it gets used
     /// safely.
-    #[allow(dead_code)] // WIP
     pub unsafe fn new(ctx: *mut duk_context) -> Decoder {
-        Decoder{ctx: Context::from_borrowed_mut_ptr(ctx)}
+        Decoder::with_options(ctx, state::decoder_options(ctx))
+    }
+
+    /// Create a new decoder which pops values from `ctx`, overriding the
+    /// options set on the context.
+    pub unsafe fn with_options(ctx: *mut duk_context,
+                               options: DecoderOptions) -> Decoder {
+        Decoder{ctx: Context::from_borrowed_mut_ptr(ctx), options: options,
+                in_object_key: false}
+    }
+
+    /// Check the value on top of the stack using `tester`.  If it fails,
+    /// pop it and return an error.
+    fn expect(&mut self, tester: unsafe extern "C" fn(*mut duk_context,
+                                                      duk_idx_t) ->
+                  duk_bool_t,
+              expected: &str) -> DuktapeResult<()>
+    {
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            if tester(ptr, -1) != 0 {
+                Ok(())
+            } else {
+                let got = type_name(ptr, -1);
+                duk_pop(ptr);
+                Err(expected_err(expected, got))
+            }
+        }
+    }
+
+    /// Read the string on top of the stack without popping it.
+    fn peek_str(&mut self) -> DuktapeResult<String> {
+        unsafe {
+            let mut len = 0;
+            let ptr = duk_get_lstring(self.ctx.as_mut_ptr(), -1, &mut len);
+            from_lstring(ptr, len)
+        }
+    }
+
+    /// Push property `name` of the value on top of the stack, or
+    /// `undefined` if it's `null` or `undefined`.  (Looking up properties
+    /// on those would throw a JavaScript error.)
+    fn get_named(&mut self, name: &str) {
+        let c_name = CString::from_slice(name.as_bytes());
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            if duk_is_null_or_undefined(ptr, -1) != 0 {
+                duk_push_undefined(ptr);
+            } else {
+                duk_get_prop_string(ptr, -1, c_name.as_ptr());
+            }
+        }
+    }
+
+    /// Push element `idx` of the value on top of the stack, or
+    /// `undefined` if it's `null` or `undefined`.
+    fn get_index(&mut self, idx: usize) {
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            if duk_is_null_or_undefined(ptr, -1) != 0 {
+                duk_push_undefined(ptr);
+            } else {
+                duk_get_prop_index(ptr, -1, idx as u32);
+            }
+        }
+    }
+
+    /// Replace the object on top of the stack with an array of `[key,
+    /// value]` pairs, one for each of its own enumerable properties.
+    fn object_to_entries(&mut self) {
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            duk_push_array(ptr);
+            duk_enum(ptr, -2, DUK_ENUM_OWN_PROPERTIES_ONLY);
+            let mut i = 0;
+            while duk_next(ptr, -1, 1) != 0 {
+                // Stack: obj entries enum key value
+                duk_push_array(ptr);
+                duk_insert(ptr, -3);
+                duk_put_prop_index(ptr, -3, 1);
+                duk_put_prop_index(ptr, -2, 0);
+                duk_put_prop_index(ptr, -3, i);
+                i += 1;
+            }
+            duk_pop(ptr);
+            duk_replace(ptr, -2);
+        }
+    }
+
+    /// Call `f` to read the contents of the container on top of the
+    /// stack, and then pop the container.  If `f` fails, we also throw
+    /// away anything it left on the stack, so that a failed read consumes
+    /// exactly one value, just like a successful one.
+    fn read_contents<T, F>(&mut self, f: F) -> DuktapeResult<T>
+        where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
+    {
+        let ptr = unsafe { self.ctx.as_mut_ptr() };
+        let top = unsafe { duk_get_top(ptr) };
+        let result = f(self);
+        unsafe { duk_set_top(ptr, top - 1); }
+        result
+    }
+
+    /// Figure out which enum variant the value on top of the stack
+    /// represents, and replace it with something holding the variant's
+    /// fields.
+    fn read_variant_name(&mut self) -> DuktapeResult<String> {
+        let ptr = unsafe { self.ctx.as_mut_ptr() };
+        if unsafe { duk_is_string(ptr, -1) } != 0 {
+            let name = try!(self.peek_str());
+            unsafe { duk_push_array(ptr); duk_replace(ptr, -2); }
+            return Ok(name);
+        }
+        try!(self.expect(duk_is_object, "enum"));
+        match self.options.enums.clone() {
+            EnumLayout::External => unsafe {
+                duk_enum(ptr, -1, DUK_ENUM_OWN_PROPERTIES_ONLY);
+                if duk_next(ptr, -1, 1) == 0 {
+                    duk_pop(ptr);
+                    return Err(self.error("Expected enum, got empty object"));
+                }
+                // Stack: obj enum key value
+                duk_replace(ptr, -4);
+                let name = self.peek_str();
+                duk_pop_2(ptr);
+                name
+            },
+            EnumLayout::Internal{ref tag} => {
+                self.get_named(&tag[]);
+                let name = self.peek_str();
+                unsafe { duk_pop(ptr); }
+                name
+            }
+            EnumLayout::Adjacent{ref tag, ref content} => {
+                self.get_named(&tag[]);
+                let name = self.peek_str();
+                unsafe { duk_pop(ptr); }
+                self.get_named(&content[]);
+                unsafe { duk_replace(ptr, -2); }
+                name
+            }
+        }
+    }
+
+    /// Push element `elt` of entry `idx` in the array of map entries on
+    /// top of the stack.
+    fn get_entry(&mut self, idx: usize, elt: usize) {
+        self.get_index(idx);
+        self.get_index(elt);
+        unsafe { duk_remove(self.ctx.as_mut_ptr(), -2); }
+    }
+
+    /// Read a number, and make sure it's an integer between `min` and
+    /// `max`.
+    fn read_integer(&mut self, min: f64, max: f64) -> DuktapeResult<f64> {
+        let v = try!(::rustc_serialize::Decoder::read_f64(self));
+        if v.floor() == v && min <= v && v <= max {
+            Ok(v)
+        } else {
+            let msg = format!("Expected integer between {} and {}, got {}",
+                              min, max, v);
+            Err(DuktapeError::from_code_and_str(ErrorCode::Range, &msg[]))
+        }
+    }
+
+    /// Read a string containing the decimal representation of an
+    /// integer.
+    fn read_decimal<T: ::std::str::FromStr>(&mut self) -> DuktapeResult<T> {
+        let s = try!(::rustc_serialize::Decoder::read_str(self));
+        s.parse::<T>().map_err(|_| {
+            let msg = format!("Expected integer in range, got \"{}\"", s);
+            DuktapeError::from_code_and_str(ErrorCode::Range, &msg[])
+        })
+    }
+
+    /// Read a 64-bit integer using our configured `Int64Layout`.  `Int64`
+    /// and `UInt64` objects are accepted whatever the layout.
+    fn read_int64<T>(&mut self, min: f64, max: f64, from_f64: fn(f64) -> T)
+                     -> DuktapeResult<T>
+        where T: ::std::str::FromStr
+    {
+        let (is_object, is_string) = unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            if int64::push_decimal(ptr, -1) {
+                duk_remove(ptr, -2);
+                (true, true)
+            } else {
+                (false, duk_is_string(ptr, -1) != 0)
+            }
+        };
+        match self.options.int64 {
+            _ if is_object => self.read_decimal(),
+            Int64Layout::String | Int64Layout::Object if is_string =>
+                self.read_decimal(),
+            Int64Layout::Number =>
+                ::rustc_serialize::Decoder::read_f64(self).map(from_f64),
+            _ => {
+                let min = if min < -MAX_SAFE_INTEGER {
+                    -MAX_SAFE_INTEGER
+                } else {
+                    min
+                };
+                let max = if max > MAX_SAFE_INTEGER {
+                    MAX_SAFE_INTEGER
+                } else {
+                    max
+                };
+                self.read_integer(min, max).map(from_f64)
+            }
+        }
     }
 }
 
@@ -27,6 +247,38 @@ impl Decoder {
 pub trait DuktapeDecodable: Decodable {}
 impl<T: Decodable> DuktapeDecodable for T {}
 
+/// Build a "wrong type" error.
+pub fn expected_err(expected: &str, got: &str) -> DuktapeError {
+    let msg = format!("Expected {}, got {}", expected, got);
+    DuktapeError::from_code_and_str(ErrorCode::Type, &msg[])
+}
+
+/// Add the name of a field to an error message.
+fn field_err(name: &str, err: DuktapeError) -> DuktapeError {
+    let msg = format!("{}: {}", name, err);
+    DuktapeError::from_code_and_str(err_code(&err), &msg[])
+}
+
+/// The name of the JavaScript type at `idx`, for use in error messages.
+pub unsafe fn type_name(ctx: *mut duk_context, idx: duk_idx_t) ->
+    &'static str
+{
+    match duk_get_type(ctx, idx) {
+        DUK_TYPE_NONE => "nothing",
+        DUK_TYPE_UNDEFINED => "undefined",
+        DUK_TYPE_NULL => "null",
+        DUK_TYPE_BOOLEAN => "boolean",
+        DUK_TYPE_NUMBER => "number",
+        DUK_TYPE_STRING => "string",
+        DUK_TYPE_OBJECT if duk_is_array(ctx, idx) != 0 => "array",
+        DUK_TYPE_OBJECT if duk_is_function(ctx, idx) != 0 => "function",
+        DUK_TYPE_OBJECT => "object",
+        DUK_TYPE_BUFFER => "buffer",
+        DUK_TYPE_POINTER => "pointer",
+        _ => "unknown type"
+    }
+}
+
 macro_rules! read_and_convert {
     ($name:ident -> $ty:ident, $reader:ident -> $in_ty:ident) => {
         fn $name(&mut self) -> DuktapeResult<$ty> {
@@ -35,20 +287,35 @@ macro_rules! read_and_convert {
     }
 }
 
+macro_rules! read_checked {
+    ($name:ident -> $ty:ident) => {
+        fn $name(&mut self) -> DuktapeResult<$ty> {
+            self.read_integer(::std::$ty::MIN as f64, ::std::$ty::MAX as f64)
+                .map(|: v: f64| v as $ty)
+        }
+    }
+}
+
+macro_rules! read_int64 {
+    ($name:ident -> $ty:ident) => {
+        fn $name(&mut self) -> DuktapeResult<$ty> {
+            fn from_f64(v: f64) -> $ty { v as $ty }
+            self.read_int64(::std::$ty::MIN as f64, ::std::$ty::MAX as f64,
+                            from_f64)
+        }
+    }
+}
+
 macro_rules! read_with {
-    ($name:ident -> $ty:ident, $tester:ident,
+    ($name:ident -> $ty:ty, $tester:ident, $expected:expr,
      |$slf:ident, $idx:ident| $reader:block) => {
         fn $name(&mut $slf) -> DuktapeResult<$ty> {
+            try!($slf.expect($tester, $expected));
             unsafe {
                 let $idx = -1;
-                if $tester($slf.ctx.as_mut_ptr(), $idx) != 0 {
-                    let result = $reader;
-                    duk_pop($slf.ctx.as_mut_ptr());
-                    result
-                } else {
-                    duk_pop($slf.ctx.as_mut_ptr());
-                    Err(DuktapeError::from_str("Expected number"))
-                }
+                let result = $reader;
+                duk_pop($slf.ctx.as_mut_ptr());
+                result
             }
         }
     }
@@ -58,29 +325,49 @@ macro_rules! read_with {
 impl ::rustc_serialize::Decoder for Decoder {
     type Error = DuktapeError;
 
-    fn read_nil(&mut self) -> DuktapeResult<()> 
-    {
-        unimplemented!()
-    }
-
-    read_and_convert!(read_usize-> usize,read_f64 -> f64);
-    read_and_convert!(read_u64  -> u64,  read_f64 -> f64);
-    read_and_convert!(read_u32  -> u32,  read_f64 -> f64);
-    read_and_convert!(read_u16  -> u16,  read_f64 -> f64);
-    read_and_convert!(read_u8   -> u8,   read_f64 -> f64);
-    read_and_convert!(read_isize-> isize,read_f64 -> f64);
-    read_and_convert!(read_i64  -> i64,  read_f64 -> f64);
-    read_and_convert!(read_i32  -> i32,  read_f64 -> f64);
-    read_and_convert!(read_i16  -> i16,  read_f64 -> f64);
-    read_and_convert!(read_i8   -> i8,   read_f64 -> f64);
+    read_with!(read_nil -> (), duk_is_null_or_undefined, "null", |self, idx| {
+        Ok(())
+    });
 
-    read_with!(read_bool -> bool, duk_is_boolean, |self, idx| {
+    read_int64!(read_usize -> usize);
+    read_int64!(read_u64 -> u64);
+    read_checked!(read_u32 -> u32);
+    read_checked!(read_u16 -> u16);
+    read_checked!(read_u8 -> u8);
+    read_int64!(read_isize -> isize);
+    read_int64!(read_i64 -> i64);
+    read_checked!(read_i32 -> i32);
+    read_checked!(read_i16 -> i16);
+    read_checked!(read_i8 -> i8);
+
+    read_with!(read_bool -> bool, duk_is_boolean, "boolean", |self, idx| {
         Ok(duk_get_boolean(self.ctx.as_mut_ptr(), idx) != 0)
     });
 
-    read_with!(read_f64 -> f64, duk_is_number, |self, idx| {
-        Ok(duk_get_number(self.ctx.as_mut_ptr(), idx))
-    });
+    fn read_f64(&mut self) -> DuktapeResult<f64> {
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            if self.in_object_key && duk_is_string(ptr, -1) != 0 {
+                // Object keys are always strings, so convert them back.
+                let key = try!(self.peek_str());
+                let v = duk_to_number(ptr, -1);
+                duk_pop(ptr);
+                if v.is_nan() {
+                    let msg = format!("Expected numeric key, got \"{}\"", key);
+                    return Err(DuktapeError::from_code_and_str(
+                        ErrorCode::Type, &msg[]));
+                }
+                return Ok(v);
+            }
+        }
+        try!(self.expect(duk_is_number, "number"));
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            let result = duk_get_number(ptr, -1);
+            duk_pop(ptr);
+            Ok(result)
+        }
+    }
     read_and_convert!(read_f32 -> f32, read_f64 -> f64);
 
     fn read_char(&mut self) -> DuktapeResult<char> {
@@ -106,10 +393,13 @@ impl ::rustc_serialize::Decoder for Decoder {
         }
     }
 
-    read_with!(read_str -> String, duk_is_string, |self, idx| {
+    read_with!(read_str -> String, duk_is_string, "string", |self, idx| {
         let mut len = 0;
         let ptr = duk_get_lstring(self.ctx.as_mut_ptr(), idx, &mut len);
-        from_lstring(ptr, len)
+        match self.options.strings {
+            StringMode::Strict => from_lstring(ptr, len),
+            StringMode::Lossy => Ok(jsstring::from_lstring_lossy(ptr, len))
+        }
     });
 
     // Compound types:
@@ -117,16 +407,25 @@ impl ::rustc_serialize::Decoder for Decoder {
                     f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        f(self)
     }
 
     fn read_enum_variant<T,F>(&mut self,
                             names: &[&str],
-                            f: F)
+                            mut f: F)
                             -> DuktapeResult<T>
         where F: FnMut(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_contents(|d: &mut Decoder| {
+            let name = try!(d.read_variant_name());
+            match names.iter().position(|n| *n == &name[]) {
+                Some(idx) => f(d, idx),
+                None => {
+                    let msg = format!("Unknown variant \"{}\"", name);
+                    Err(d.error(&msg[]))
+                }
+            }
+        })
     }
     fn read_enum_variant_arg<T,F>(&mut self,
                                 a_idx: usize,
@@ -134,7 +433,8 @@ impl ::rustc_serialize::Decoder for Decoder {
                                 -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>        
     {
-        unimplemented!()
+        self.get_index(a_idx);
+        f(self)
     }
 
     fn read_enum_struct_variant<T,F>(&mut self,
@@ -143,7 +443,7 @@ impl ::rustc_serialize::Decoder for Decoder {
                                    -> DuktapeResult<T>
         where F: FnMut(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_enum_variant(names, f)
     }
     fn read_enum_struct_variant_field<T,F>(&mut self,
                                          f_name: &str,
@@ -152,14 +452,20 @@ impl ::rustc_serialize::Decoder for Decoder {
                                          -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_enum_variant_arg(f_idx, f)
     }
 
     fn read_struct<T,F>(&mut self, s_name: &str, len: usize, f: F)
                       -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        if jsstring::is_js_string_struct(s_name) {
+            // Give `JsString` its UTF-16 code units.
+            try!(self.expect(duk_is_string, "string"));
+            unsafe { jsstring::string_to_struct(self.ctx.as_mut_ptr()); }
+        }
+        try!(self.expect(duk_is_object, "object"));
+        self.read_contents(f)
     }
     fn read_struct_field<T,F>(&mut self,
                             f_name: &str,
@@ -168,18 +474,27 @@ impl ::rustc_serialize::Decoder for Decoder {
                             -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.get_named(f_name);
+        f(self).map_err(|err| field_err(f_name, err))
     }
 
     fn read_tuple<T,F>(&mut self, len: usize, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_seq(|: d: &mut Decoder, actual: usize| {
+            if actual == len {
+                f(d)
+            } else {
+                let msg = format!("Expected tuple of length {}, got {}",
+                                  len, actual);
+                Err(d.error(&msg[]))
+            }
+        })
     }
     fn read_tuple_arg<T,F>(&mut self, a_idx: usize, f: F) -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_seq_elt(a_idx, f)
     }
 
     fn read_tuple_struct<T,F>(&mut self,
@@ -189,7 +504,7 @@ impl ::rustc_serialize::Decoder for Decoder {
                             -> DuktapeResult<T>
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_tuple(len, f)
     }
     fn read_tuple_struct_arg<T,F>(&mut self,
                                 a_idx: usize,
@@ -197,53 +512,82 @@ impl ::rustc_serialize::Decoder for Decoder {
                                 -> DuktapeResult<T> 
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.read_tuple_arg(a_idx, f)
     }
 
     // Specialized types:
-    fn read_option<T,F>(&mut self, f: F) -> DuktapeResult<T> 
+    fn read_option<T,F>(&mut self, mut f: F) -> DuktapeResult<T> 
         where F: FnMut(&mut Decoder, bool) -> DuktapeResult<T>
     {
-        unimplemented!()
+        // Both `null` and `undefined` map to `None`, because that's how
+        // missing function arguments show up.
+        let present = unsafe {
+            duk_is_null_or_undefined(self.ctx.as_mut_ptr(), -1) == 0
+        };
+        if present {
+            f(self, true)
+        } else {
+            unsafe { duk_pop(self.ctx.as_mut_ptr()); }
+            f(self, false)
+        }
     }
 
     fn read_seq<T,F>(&mut self, f: F) -> DuktapeResult<T> 
         where F: FnOnce(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        try!(self.expect(duk_is_array, "array"));
+        let len = unsafe { duk_get_length(self.ctx.as_mut_ptr(), -1) };
+        self.read_contents(|d: &mut Decoder| f(d, len as usize))
     }
     fn read_seq_elt<T,F>(&mut self, idx: usize, f: F) -> DuktapeResult<T> 
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.get_index(idx);
+        f(self)
     }
 
     fn read_map<T,F>(&mut self, f: F) -> DuktapeResult<T> 
         where F: FnOnce(&mut Decoder, usize) -> DuktapeResult<T>
     {
-        unimplemented!()
+        // Either way, we end up with an array of `[key, value]` pairs.
+        match self.options.maps {
+            MapLayout::Object => {
+                try!(self.expect(duk_is_object, "object"));
+                self.object_to_entries();
+            }
+            MapLayout::Array => {
+                try!(self.expect(duk_is_array, "array of [key, value]"));
+            }
+        }
+        let len = unsafe { duk_get_length(self.ctx.as_mut_ptr(), -1) };
+        self.read_contents(|d: &mut Decoder| f(d, len as usize))
     }
     fn read_map_elt_key<T,F>(&mut self, idx: usize, f: F) -> DuktapeResult<T> 
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.get_entry(idx, 0);
+        self.in_object_key = self.options.maps == MapLayout::Object;
+        let result = f(self);
+        self.in_object_key = false;
+        result
     }
     fn read_map_elt_val<T,F>(&mut self, idx: usize, f: F) -> DuktapeResult<T> 
         where F: FnOnce(&mut Decoder) -> DuktapeResult<T>
     {
-        unimplemented!()
+        self.get_entry(idx, 1);
+        f(self)
     }
 
     // Failure
     fn error(&mut self, err: &str) -> DuktapeError 
     {
-        unimplemented!()
+        DuktapeError::from_code_and_str(ErrorCode::Type, err)
     }
 }
 
 #[test]
 fn test_decoder() {
-    //use std::collections::HashMap;
+    use std::collections::HashMap;
     use std::fmt::Debug;
     use encoder::{Encoder, DuktapeEncodable};
 
@@ -287,40 +631,205 @@ fn test_decoder() {
     //assert_decode!('c'); // https://github.com/rust-lang/rust/issues/19719
     assert_decode!('𓀀');
 
-    //// Enums.
-    //#[derive(RustcEncodable, Decodable, PartialEq, Show)]
-    //enum ExEnum { Foo, Bar(f64), Baz{x: f64, y: f64} }
-    //assert_decode!(ExEnum::Foo);
-    //assert_decode!(ExEnum::Bar(1.0));
-    //assert_decode!(ExEnum::Baz{x: 1.0, y: 2.0});
+    // Enums.
+    #[derive(RustcEncodable, RustcDecodable, PartialEq, Show)]
+    enum ExEnum { Foo, Bar(f64), Baz{x: f64, y: f64} }
+    assert_decode!(ExEnum::Foo);
+    assert_decode!(ExEnum::Bar(1.0));
+    assert_decode!(ExEnum::Baz{x: 1.0, y: 2.0});
 
-    //// Structs.
-    //#[derive(RustcEncodable, Decodable, PartialEq, Show)]
-    //struct ExStruct { x: f64, y: f64 }
-    //assert_decode!(ExStruct{x: 1.0, y: 2.0});
+    // Structs.
+    #[derive(RustcEncodable, RustcDecodable, PartialEq, Show)]
+    struct ExStruct { x: f64, y: f64 }
+    assert_decode!(ExStruct{x: 1.0, y: 2.0});
 
-    //// Tuples.
-    //assert_decode!((1u, 2us));
+    // Tuples.
+    assert_decode!((1us, 2us));
 
-    //// Tuple structs.
-    //#[derive(RustcEncodable, Decodable, PartialEq, Show)]
-    //struct ExTupleStruct(f64);
-    //assert_decode!(ExTupleStruct(1.0));
+    // Tuple structs.
+    #[derive(RustcEncodable, RustcDecodable, PartialEq, Show)]
+    struct ExTupleStruct(f64);
+    assert_decode!(ExTupleStruct(1.0));
 
-    //// Options.
-    //let none_f64: Option<f64> = None;
-    //assert_decode!(none_f64);
-    //assert_decode!(Some(1.0f64));
+    // Options.
+    let none_f64: Option<f64> = None;
+    assert_decode!(none_f64);
+    assert_decode!(Some(1.0f64));
 
-    //// Sequences.
-    //let seq = vec!(1.0f64);
-    //assert_decode!(seq);
+    // Sequences.
+    let seq = vec!(1.0f64);
+    assert_decode!(seq);
 
     // Maps.
-    //let mut hash: HashMap<String,int> = HashMap::new();
-    //hash.insert("test".to_string(), 3);
-    //assert_decode!(&hash);    
-    //let mut hash2: HashMap<int,int> = HashMap::new();
-    //hash2.insert(7, 3);
-    //assert_decode!(hash2);    
+    let mut hash: HashMap<String,i32> = HashMap::new();
+    hash.insert("test".to_string(), 3);
+    assert_decode!(hash);
+    let mut hash2: HashMap<i32,i32> = HashMap::new();
+    hash2.insert(7, 3);
+    assert_decode!(hash2);
+}
+
+#[test]
+fn test_decoder_errors() {
+    use std::collections::HashMap;
+
+    let mut ctx = Context::new().unwrap();
+
+    fn decode<T: DuktapeDecodable>(ctx: &mut Context, code: &str) ->
+        DuktapeResult<T>
+    {
+        unsafe {
+            let ptr = ctx.as_mut_ptr();
+            let top = duk_get_top(ptr);
+            let filename = "input";
+            duk_push_lstring(ptr, filename.as_ptr() as *const i8,
+                             filename.len() as duk_size_t);
+            duk_eval_raw(ptr, code.as_ptr() as *const i8,
+                         code.len() as duk_size_t,
+                         DUK_COMPILE_EVAL | DUK_COMPILE_NOSOURCE |
+                         DUK_COMPILE_SAFE);
+            let result = {
+                let mut decoder = Decoder::new(ptr);
+                Decodable::decode(&mut decoder)
+            };
+            // Whether we succeed or fail, we consume exactly one value.
+            assert_eq!(top, duk_get_top(ptr));
+            result
+        }
+    }
+
+    #[derive(RustcDecodable, PartialEq, Show)]
+    struct ExStruct { x: f64, y: f64 }
+
+    #[derive(RustcDecodable, PartialEq, Show)]
+    enum ExEnum { Foo(f64) }
+
+    assert_eq!(Err(DuktapeError::from_code_and_str(
+        ErrorCode::Type, "y: Expected number, got string")),
+        decode::<ExStruct>(&mut ctx, "({x: 1, y: 'two'})"));
+    assert_eq!(Err(DuktapeError::from_code_and_str(
+        ErrorCode::Type, "Expected array, got null")),
+        decode::<Vec<f64>>(&mut ctx, "null"));
+    assert_eq!(Err(DuktapeError::from_code_and_str(
+        ErrorCode::Type, "Expected tuple of length 2, got 3")),
+        decode::<(f64, f64)>(&mut ctx, "[1, 2, 3]"));
+
+    // Integers are never silently truncated.
+    assert_eq!(Err(DuktapeError::from_code_and_str(
+        ErrorCode::Range, "Expected integer between 0 and 255, got 300.5")),
+        decode::<u8>(&mut ctx, "300.5"));
+    assert_eq!(Err(DuktapeError::from_code_and_str(
+        ErrorCode::Range, "Expected integer between 0 and 255, got -1")),
+        decode::<u8>(&mut ctx, "-1"));
+    assert_eq!(Ok(-128i8), decode::<i8>(&mut ctx, "-128"));
+    assert!(decode::<i64>(&mut ctx, "9007199254740992").is_err());
+
+    // Errors inside containers don't leave anything behind.
+    assert!(decode::<Vec<ExStruct>>(&mut ctx, "[{x: 1, y: 2}, {x: 1}]")
+            .is_err());
+    assert!(decode::<HashMap<String, f64>>(&mut ctx, "({a: 1, b: 'x'})")
+            .is_err());
+    assert!(decode::<ExEnum>(&mut ctx, "({variant: 'Foo', fields: ['x']})")
+            .is_err());
+    assert!(decode::<ExEnum>(&mut ctx, "({variant: 'Baz', fields: []})")
+            .is_err());
+    assert_eq!(Ok(ExEnum::Foo(1.0)),
+               decode::<ExEnum>(&mut ctx, "({variant: 'Foo', fields: [1]})"));
+}
+
+#[test]
+fn test_encoder_decoder_options() {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::default::Default;
+    use std::fmt::Debug;
+    use encoder::DuktapeEncodable;
+    use types::Value;
+
+    fn round_trip<T>(ctx: &mut Context, value: &T, expected_json: &str)
+        where T: DuktapeEncodable + DuktapeDecodable + PartialEq + Debug
+    {
+        unsafe {
+            let ptr = ctx.as_mut_ptr();
+            ctx.push(value).unwrap();
+            duk_dup_top(ptr);
+            duk_json_encode(ptr, -1);
+            let mut len = 0;
+            let json = duk_get_lstring(ptr, -1, &mut len);
+            assert_eq!(expected_json, &from_lstring(json, len).unwrap()[]);
+            duk_pop(ptr);
+            let mut decoder = Decoder::new(ptr);
+            let decoded: DuktapeResult<T> = Decodable::decode(&mut decoder);
+            assert_eq!(value, &decoded.unwrap());
+        }
+    }
+
+    #[derive(RustcEncodable, RustcDecodable, PartialEq, Show)]
+    enum ExEnum { Foo, Bar(f64) }
+
+    let mut ctx = Context::new().unwrap();
+    let mut hash: HashMap<i32,i32> = HashMap::new();
+    hash.insert(7, 3);
+    let none: Option<f64> = None;
+
+    // The defaults match `rustc_serialize::json`.
+    round_trip(&mut ctx, &ExEnum::Bar(1.0),
+               r#"{"variant":"Bar","fields":[1]}"#);
+    round_trip(&mut ctx, &hash, r#"{"7":3}"#);
+    round_trip(&mut ctx, &vec!(none), "[null]");
+
+    ctx.set_encoder_options(EncoderOptions{
+        enums: EnumLayout::External,
+        maps: MapLayout::Array,
+        none: NoneValue::Undefined,
+        int64: Int64Layout::String
+    }).unwrap();
+    ctx.set_decoder_options(DecoderOptions{
+        enums: EnumLayout::External,
+        maps: MapLayout::Array,
+        int64: Int64Layout::String,
+        strings: StringMode::Strict
+    }).unwrap();
+    round_trip(&mut ctx, &ExEnum::Foo, r#""Foo""#);
+    round_trip(&mut ctx, &ExEnum::Bar(1.0), r#"{"Bar":[1]}"#);
+    round_trip(&mut ctx, &hash, "[[7,3]]");
+    round_trip(&mut ctx, &vec!(none), "[null]");
+    round_trip(&mut ctx, &9007199254740993u64, r#""9007199254740993""#);
+    unsafe {
+        ctx.push(&none).unwrap();
+        assert!(duk_is_undefined(ctx.as_mut_ptr(), -1) != 0);
+        duk_pop(ctx.as_mut_ptr());
+    }
+
+    let tag = EnumLayout::Internal{tag: "type".to_string()};
+    ctx.set_encoder_options(EncoderOptions{
+        enums: tag.clone(), int64: Int64Layout::Checked,
+        .. Default::default()
+    }).unwrap();
+    ctx.set_decoder_options(DecoderOptions{
+        enums: tag.clone(), int64: Int64Layout::Checked,
+        .. Default::default()
+    }).unwrap();
+    round_trip(&mut ctx, &ExEnum::Foo, r#"{"type":"Foo"}"#);
+    round_trip(&mut ctx, &ExEnum::Bar(1.0), r#"{"type":"Bar","0":1}"#);
+    round_trip(&mut ctx, &9007199254740991u64, "9007199254740991");
+    unsafe {
+        // 2^53 + 1 rounds down to 2^53 as an f64, which mustn't hide it.
+        assert!(ctx.push(&9007199254740992u64).is_err());
+        assert!(ctx.push(&9007199254740993u64).is_err());
+        assert!(ctx.push(&-9007199254740993i64).is_err());
+    }
+
+    ctx.set_encoder_options(EncoderOptions{
+        int64: Int64Layout::Object, .. Default::default()
+    }).unwrap();
+    ctx.set_decoder_options(DecoderOptions{
+        int64: Int64Layout::Object, .. Default::default()
+    }).unwrap();
+    round_trip(&mut ctx, &18446744073709551615u64,
+               r#""18446744073709551615""#);
+    round_trip(&mut ctx, &vec!(-9007199254740993i64),
+               r#"["-9007199254740993"]"#);
+    assert_eq!(Ok(Value::String(Cow::Borrowed("9007199254740994"))),
+               ctx.eval("Int64('9007199254740993').add(1).toString()"));
 }
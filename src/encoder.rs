@@ -1,17 +1,41 @@
 use std::ops::Deref;
-use std::ptr::null_mut;
+use std::ptr::{null_mut, copy_nonoverlapping_memory};
 use rustc_serialize::Encodable;
 use cesu8::to_cesu8;
 use ffi::*;
 use errors::*;
-use context::Context;
+use types::{Value, Buffer};
+use context::{Context, Borrowed};
+use options::*;
+use state;
+use int64;
+use jsstring;
+
+/// The largest integer which can be stored exactly in a JavaScript
+/// number.  (Every integer with a smaller magnitude can be, too.)
+pub const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+/// The maximum depth of nested arrays and objects we're willing to
+/// encode.  Anything deeper than this is almost certainly a cycle.
+const MAX_DEPTH: usize = 1000;
+
+/// The number of stack slots we need to reserve before pushing a
+/// container, enough to hold the container itself, an enum's `fields`
+/// key and array, and the value being added to it.
+const CONTAINER_SLOTS: duk_idx_t = 4;
 
 /// Translates Rust values into JavaScript values.
 pub struct Encoder {
     /// An internal `Context` object, for convenience.  We own this,
     /// because if we use a reference to somebody else's, the lifetimes
     /// make it very hard to work with &Encodable references.
-    ctx: Context
+    ctx: Context<Borrowed>,
+
+    /// How many containers we're currently nested inside.
+    depth: usize,
+
+    /// How we represent values which have several possible encodings.
+    options: EncoderOptions
 }
 
 impl Encoder {
@@ -19,7 +43,87 @@ impl Encoder {
     /// one of these, you're responsible for making sure it gets used
     /// safely.
     pub unsafe fn new(ctx: *mut duk_context) -> Encoder {
-        Encoder{ctx: Context::from_borrowed_mut_ptr(ctx)}
+        Encoder::with_options(ctx, state::encoder_options(ctx))
+    }
+
+    /// Create a new encoder which pushes values to `ctx`, overriding the
+    /// options set on the context.
+    pub unsafe fn with_options(ctx: *mut duk_context,
+                               options: EncoderOptions) -> Encoder {
+        Encoder{ctx: Context::from_borrowed_mut_ptr(ctx), depth: 0,
+                options: options}
+    }
+
+    /// Get the underlying context pointer, for use by `DuktapeEncodable`
+    /// implementations which need to push values directly.
+    pub unsafe fn as_mut_ptr(&mut self) -> *mut duk_context {
+        self.ctx.as_mut_ptr()
+    }
+
+    /// Emit a 64-bit integer using our configured `Int64Layout`.  We check
+    /// `exact` against the integer itself, because converting it to
+    /// `as_f64` may already have rounded it into range.
+    fn emit_int64<T: ToString>(&mut self, v: T, as_f64: f64, exact: bool,
+                               signed: bool)
+                               -> EncodeResult
+    {
+        match self.options.int64 {
+            Int64Layout::Number => self.emit_f64(as_f64),
+            Int64Layout::Checked if exact => self.emit_f64(as_f64),
+            Int64Layout::Checked => {
+                let msg = format!("{} can't be represented exactly as a \
+                                   JavaScript number", v.to_string());
+                Err(DuktapeError::from_code_and_str(ErrorCode::Range,
+                                                    &msg[]))
+            }
+            Int64Layout::String => self.emit_str(&v.to_string()[]),
+            Int64Layout::Object => unsafe {
+                int64::push(self.ctx.as_mut_ptr(), signed, &v.to_string()[])
+            }
+        }
+    }
+
+    /// Put the value on top of the stack into the object below it, using
+    /// `name` as the key.
+    fn put_named(&mut self, name: &str) -> EncodeResult {
+        try!(self.emit_str(name));
+        unsafe {
+            duk_swap_top(self.ctx.as_mut_ptr(), -2);
+            duk_put_prop(self.ctx.as_mut_ptr(), -3);
+        }
+        Ok(())
+    }
+
+    /// Make sure we have room to push `count` more values.
+    fn reserve(&mut self, count: duk_idx_t) -> EncodeResult {
+        if unsafe { duk_check_stack(self.ctx.as_mut_ptr(), count) } != 0 {
+            Ok(())
+        } else {
+            Err(DuktapeError::from_code_and_str(
+                ErrorCode::Alloc, "out of space on the value stack"))
+        }
+    }
+
+    /// Push a new container using `push`, and call `f` to fill it in,
+    /// keeping track of how deeply we're nested.
+    fn nested<F>(&mut self, push: unsafe extern "C" fn(*mut duk_context) ->
+                     duk_idx_t,
+                 f: F) -> EncodeResult
+        where F: FnOnce(&mut Encoder) -> EncodeResult
+    {
+        if self.depth >= MAX_DEPTH {
+            let msg = format!("can't nest values more than {} levels deep \
+                               (does the value contain a cycle?)",
+                              MAX_DEPTH);
+            return Err(DuktapeError::from_code_and_str(ErrorCode::Range,
+                                                       &msg[]));
+        }
+        try!(self.reserve(CONTAINER_SLOTS));
+        unsafe { push(self.ctx.as_mut_ptr()); }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
     }
 }
 
@@ -33,7 +137,45 @@ pub trait DuktapeEncodable {
 
 impl<T: Encodable> DuktapeEncodable for T {
     fn duktape_encode(&self, s: &mut Encoder) -> EncodeResult {
-        self.encode(s)
+        // If we fail, throw away anything we've pushed so far.
+        let top = unsafe { duk_get_top(s.ctx.as_mut_ptr()) };
+        try!(s.reserve(1));
+        let result = self.encode(s);
+        if result.is_err() {
+            unsafe { duk_set_top(s.ctx.as_mut_ptr(), top); }
+        }
+        result
+    }
+}
+
+impl<'a> DuktapeEncodable for Value<'a> {
+    fn duktape_encode(&self, s: &mut Encoder) -> EncodeResult {
+        use rustc_serialize::Encoder;
+        try!(s.reserve(1));
+        match self {
+            &Value::Undefined => {
+                unsafe { duk_push_undefined(s.ctx.as_mut_ptr()); }
+                Ok(())
+            }
+            &Value::Null => s.emit_nil(),
+            &Value::Bool(v) => s.emit_bool(v),
+            &Value::Number(v) => s.emit_f64(v),
+            &Value::String(ref v) => s.emit_str(v.deref())
+        }
+    }
+}
+
+impl DuktapeEncodable for Buffer {
+    fn duktape_encode(&self, s: &mut Encoder) -> EncodeResult {
+        let &Buffer(ref bytes) = self;
+        try!(s.reserve(1));
+        unsafe {
+            let ptr = s.ctx.as_mut_ptr();
+            let buf = duk_push_fixed_buffer(ptr, bytes.len() as duk_size_t);
+            copy_nonoverlapping_memory(buf as *mut u8, bytes.as_ptr(),
+                                       bytes.len());
+        }
+        Ok(())
     }
 }
 
@@ -46,13 +188,18 @@ impl ::rustc_serialize::Encoder for Encoder {
     }
 
     // Integral types map to floats.
-    fn emit_usize(&mut self, v: usize) -> EncodeResult { self.emit_f64(v as f64)}
-    fn emit_u64(&mut self, v: u64) -> EncodeResult { self.emit_f64(v as f64) }
+    fn emit_usize(&mut self, v: usize) -> EncodeResult { self.emit_u64(v as u64) }
+    fn emit_u64(&mut self, v: u64) -> EncodeResult {
+        self.emit_int64(v, v as f64, v <= MAX_SAFE_INTEGER as u64, false)
+    }
     fn emit_u32(&mut self, v: u32) -> EncodeResult { self.emit_f64(v as f64) }
     fn emit_u16(&mut self, v: u16) -> EncodeResult { self.emit_f64(v as f64) }
     fn emit_u8(&mut self, v: u8) -> EncodeResult  { self.emit_f64(v as f64) }
-    fn emit_isize(&mut self, v: isize) -> EncodeResult { self.emit_f64(v as f64)}
-    fn emit_i64(&mut self, v: i64) -> EncodeResult { self.emit_f64(v as f64) }
+    fn emit_isize(&mut self, v: isize) -> EncodeResult { self.emit_i64(v as i64) }
+    fn emit_i64(&mut self, v: i64) -> EncodeResult {
+        let max = MAX_SAFE_INTEGER as i64;
+        self.emit_int64(v, v as f64, -max <= v && v <= max, true)
+    }
     fn emit_i32(&mut self, v: i32) -> EncodeResult { self.emit_f64(v as f64) }
     fn emit_i16(&mut self, v: i16) -> EncodeResult { self.emit_f64(v as f64) }
     fn emit_i8(&mut self, v: i8) -> EncodeResult  { self.emit_f64(v as f64) }
@@ -91,21 +238,31 @@ impl ::rustc_serialize::Encoder for Encoder {
                             len: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        if len == 0 {
-            self.emit_str(v_name.as_slice())
-        } else {
-            unsafe {
-                duk_push_object(self.ctx.as_mut_ptr());
-                self.emit_str("variant").unwrap();
-                self.emit_str(v_name.as_slice()).unwrap();
-                duk_put_prop(self.ctx.as_mut_ptr(), -3);
-
-                self.emit_str("fields").unwrap();
-                duk_push_array(self.ctx.as_mut_ptr());
-                f(self).unwrap();
-                duk_put_prop(self.ctx.as_mut_ptr(), -3);
+        match self.options.enums.clone() {
+            EnumLayout::Internal{ref tag} => {
+                // Fields are stored as numbered properties alongside the
+                // tag, so we don't need a separate container for them.
+                self.nested(duk_push_object, |: s: &mut Encoder| {
+                    try!(s.emit_str(v_name));
+                    try!(s.put_named(&tag[]));
+                    f(s)
+                })
+            }
+            _ if len == 0 => self.emit_str(v_name),
+            EnumLayout::External => {
+                self.nested(duk_push_object, |: s: &mut Encoder| {
+                    try!(s.nested(duk_push_array, f));
+                    s.put_named(v_name)
+                })
+            }
+            EnumLayout::Adjacent{ref tag, ref content} => {
+                self.nested(duk_push_object, |: s: &mut Encoder| {
+                    try!(s.emit_str(v_name));
+                    try!(s.put_named(&tag[]));
+                    try!(s.nested(duk_push_array, f));
+                    s.put_named(&content[])
+                })
             }
-            Ok(())
         }
     }
 
@@ -113,42 +270,46 @@ impl ::rustc_serialize::Encoder for Encoder {
         DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
+        try!(f(self));
         unsafe {
-            f(self).unwrap();
             duk_put_prop_index(self.ctx.as_mut_ptr(), -2, a_idx as u32);
         }
         Ok(())
     }
 
-    #[allow(unused_variables)]
     fn emit_enum_struct_variant<F>(&mut self, v_name: &str, v_id: usize,
                                    len: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        // TODO: Not called during normal serialization.
-        unimplemented!()
+        // Not called by `#[derive]`, but handle it the same way as
+        // `rustc_serialize::json` does.
+        self.emit_enum_variant(v_name, v_id, len, f)
     }
 
-    #[allow(unused_variables)]
-    fn emit_enum_struct_variant_field<F>(&mut self, f_name: &str, f_idx: usize, f: F) -> DuktapeResult<()>
+    fn emit_enum_struct_variant_field<F>(&mut self, _f_name: &str,
+                                         f_idx: usize, f: F) ->
+        DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        // TODO: Not called during normal serialization.
-        unimplemented!()
+        self.emit_enum_variant_arg(f_idx, f)
     }
 
-    fn emit_struct<F>(&mut self, _name: &str, _len: usize, f: F) -> DuktapeResult<()>
+    fn emit_struct<F>(&mut self, name: &str, _len: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        unsafe { duk_push_object(self.ctx.as_mut_ptr()); }
-        f(self)
+        try!(self.nested(duk_push_object, f));
+        if jsstring::is_js_string_struct(name) {
+            // Turn `JsString`'s UTF-16 code units back into a string.
+            unsafe { jsstring::struct_to_string(self.ctx.as_mut_ptr()); }
+        }
+        Ok(())
     }
 
     fn emit_struct_field<F>(&mut self, f_name: &str, _f_idx: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        self.emit_str(f_name).unwrap();
-        f(self).unwrap();
+        try!(self.emit_str(f_name));
+        try!(f(self));
         unsafe { duk_put_prop(self.ctx.as_mut_ptr(), -3); }
         Ok(())
     }
@@ -165,20 +326,16 @@ impl ::rustc_serialize::Encoder for Encoder {
         self.emit_seq_elt(idx, f)
     }
 
-    #[allow(unused_variables)]
-    fn emit_tuple_struct<F>(&mut self, name: &str, len: usize, f: F) -> DuktapeResult<()>
+    fn emit_tuple_struct<F>(&mut self, _name: &str, len: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        // TODO: Not currently used.
-        unimplemented!()
+        self.emit_seq(len, f)
     }
 
-    #[allow(unused_variables)]
     fn emit_tuple_struct_arg<F>(&mut self, f_idx: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        // TODO: Not currently used.
-        unimplemented!()
+        self.emit_seq_elt(f_idx, f)
     }
 
     fn emit_option<F>(&mut self, f: F) -> DuktapeResult<()>
@@ -189,7 +346,13 @@ impl ::rustc_serialize::Encoder for Encoder {
 
     fn emit_option_none(&mut self) -> EncodeResult
     {
-        self.emit_nil()
+        match self.options.none {
+            NoneValue::Null => self.emit_nil(),
+            NoneValue::Undefined => {
+                unsafe { duk_push_undefined(self.ctx.as_mut_ptr()); }
+                Ok(())
+            }
+        }
     }
 
     fn emit_option_some<F>(&mut self, f: F) -> DuktapeResult<()>
@@ -201,14 +364,13 @@ impl ::rustc_serialize::Encoder for Encoder {
     fn emit_seq<F>(&mut self, _len: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        unsafe { duk_push_array(self.ctx.as_mut_ptr()); }
-        f(self)
+        self.nested(duk_push_array, f)
     }
 
     fn emit_seq_elt<F>(&mut self, idx: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        f(self).unwrap();
+        try!(f(self));
         unsafe { duk_put_prop_index(self.ctx.as_mut_ptr(), -2, idx as u32); }
         Ok(())
     }
@@ -216,23 +378,49 @@ impl ::rustc_serialize::Encoder for Encoder {
     fn emit_map<F>(&mut self, _len: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        unsafe { duk_push_object(self.ctx.as_mut_ptr()); }
-        f(self)
+        match self.options.maps {
+            MapLayout::Object => self.nested(duk_push_object, f),
+            MapLayout::Array => self.nested(duk_push_array, f)
+        }
     }
 
     fn emit_map_elt_key<F>(&mut self, _idx: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        f(self).unwrap();
-        unsafe { duk_safe_to_lstring(self.ctx.as_mut_ptr(), -1, null_mut()); }
-        Ok(())
+        match self.options.maps {
+            MapLayout::Object => {
+                try!(f(self));
+                unsafe {
+                    duk_safe_to_lstring(self.ctx.as_mut_ptr(), -1,
+                                        null_mut());
+                }
+                Ok(())
+            }
+            MapLayout::Array => {
+                // Leave a `[key]` pair on the stack for `emit_map_elt_val`.
+                self.nested(duk_push_array, |: s: &mut Encoder| {
+                    try!(f(s));
+                    unsafe { duk_put_prop_index(s.ctx.as_mut_ptr(), -2, 0); }
+                    Ok(())
+                })
+            }
+        }
     }
 
-    fn emit_map_elt_val<F>(&mut self, _idx: usize, f: F) -> DuktapeResult<()>
+    fn emit_map_elt_val<F>(&mut self, idx: usize, f: F) -> DuktapeResult<()>
         where F: FnOnce(&mut Encoder) -> DuktapeResult<()>
     {
-        f(self).unwrap();
-        unsafe { duk_put_prop(self.ctx.as_mut_ptr(), -3); }
+        try!(f(self));
+        unsafe {
+            let ptr = self.ctx.as_mut_ptr();
+            match self.options.maps {
+                MapLayout::Object => { duk_put_prop(ptr, -3); }
+                MapLayout::Array => {
+                    duk_put_prop_index(ptr, -2, 1);
+                    duk_put_prop_index(ptr, -2, idx as u32);
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -240,7 +428,6 @@ impl ::rustc_serialize::Encoder for Encoder {
 #[test]
 fn test_encoder() {
     use std::collections::HashMap;
-    use types::Value;
 
     let mut ctx = Context::new().unwrap();
     ctx.eval(r"
@@ -253,7 +440,8 @@ function assert_json(expected, value) {
     fn assert_json<T: DuktapeEncodable>(
         ctx: &mut Context, expected: &str, value: &T)
     {
-        match ctx.call("assert_json", &[&expected, value]) {
+        let args: [&DuktapeEncodable; 2] = [&expected, value];
+        match ctx.call("assert_json", &args) {
             Ok(Value::Bool(true)) => {},
             Ok(Value::String(ref got)) =>
                 panic!("expected {:?}, got {:?}", expected, got),
@@ -328,3 +516,27 @@ function assert_json(expected, value) {
     hash2.insert(7, 3);
     assert_encode!(&hash2);    
 }
+
+#[test]
+fn test_encoder_errors() {
+    use rustc_serialize::json::Json;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("function id(x) { return x; }").unwrap();
+
+    // Build a value which is nested too deeply to encode.
+    let mut deep = Json::Null;
+    for _ in range(0, MAX_DEPTH + 1) {
+        deep = Json::Array(vec!(deep));
+    }
+
+    unsafe {
+        let top = duk_get_top(ctx.as_mut_ptr());
+        assert!(ctx.push(&deep).is_err());
+        assert_eq!(top, duk_get_top(ctx.as_mut_ptr()));
+    }
+    assert!(ctx.call("id", (1.0f64, &deep)).is_err());
+
+    // The context should still be usable afterwards.
+    assert_eq!(Ok(Value::Number(1.0)), ctx.call("id", &[&1.0f64]));
+}
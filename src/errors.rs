@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt;
+use std::old_io::IoError;
 use std::result::Result;
 use ffi::*;
 
@@ -28,7 +29,10 @@ pub enum ErrorCode {
 /// A duktape API error.  The is used as both the return type of duktape of
 /// functions, and also the return type of Rust functions called from
 /// duktape.
-#[derive(Show, PartialEq, Eq)]
+///
+/// Errors compare equal if their codes and messages match, regardless of
+/// where they happened.
+#[derive(Show)]
 pub struct DuktapeError {
     /// The error code, if a specific one is available, or
     /// `ErrorCode::Error` if we have nothing better.
@@ -37,29 +41,91 @@ pub struct DuktapeError {
     /// Errors have some sort of internal structure, but the duktape
     /// documentation always just converts them to strings.  So that's all
     /// we'll store for now.
-    message: Option<String>
+    message: Option<String>,
+
+    /// Where a script error was thrown, if known.
+    location: Option<(String, u32)>,
+
+    /// The lines of source around `location`, if we have them.
+    code_frame: Option<String>,
+
+    /// The underlying error, if we failed to read a script.
+    io_error: Option<IoError>
 }
 
 impl DuktapeError {
     /// Create an error specifying just the error code.
     pub fn from_code(code: ErrorCode) -> DuktapeError {
-        DuktapeError{code: code, message: None}
+        DuktapeError{code: code, message: None, location: None,
+                     code_frame: None, io_error: None}
     }
 
     /// Create an error, specifying an error message.
     pub fn from_str(message: &str) -> DuktapeError {
-        DuktapeError{code: ErrorCode::Error, message: Some(message.to_string())}
+        DuktapeError::from_code_and_str(ErrorCode::Error, message)
+    }
+
+    /// Create an error, specifying both an error code and a message.
+    pub fn from_code_and_str(code: ErrorCode, message: &str) -> DuktapeError {
+        DuktapeError{code: code, message: Some(message.to_string()),
+                     location: None, code_frame: None, io_error: None}
+    }
+
+    /// Create an error reporting that a script couldn't be read.
+    pub fn from_io_error(err: IoError) -> DuktapeError {
+        let msg = format!("I/O error: {}", err);
+        let mut result = DuktapeError::from_str(&msg[]);
+        result.io_error = Some(err);
+        result
+    }
+
+    /// If we failed to read a script, as opposed to running it, return
+    /// the underlying I/O error.
+    pub fn io_error(&self) -> Option<&IoError> {
+        self.io_error.as_ref()
+    }
+
+    /// The name of the file where a script error was thrown, if known.
+    pub fn file_name(&self) -> Option<&str> {
+        self.location.as_ref().map(|&(ref file, _)| &file[])
+    }
+
+    /// The line number where a script error was thrown, if known.
+    pub fn line_number(&self) -> Option<u32> {
+        self.location.as_ref().map(|&(_, line)| line)
+    }
+
+    /// The failing line and its neighbours, with the failing line marked,
+    /// if the source was kept using `Context::set_keep_source`.
+    pub fn code_frame(&self) -> Option<&str> {
+        self.code_frame.as_ref().map(|frame| &frame[])
+    }
+}
+
+impl PartialEq for DuktapeError {
+    fn eq(&self, other: &DuktapeError) -> bool {
+        self.code == other.code && self.message == other.message &&
+            self.io_error.is_some() == other.io_error.is_some()
     }
 }
 
+impl Eq for DuktapeError {}
+
 /// Re-exported within the crate, but not outside.
 pub fn err_code(err: &DuktapeError) -> ErrorCode { err.code }
 pub fn err_message(err: &DuktapeError) -> &Option<String> { &err.message }
+pub fn set_err_location(err: &mut DuktapeError, file: String, line: u32,
+                        code_frame: Option<String>) {
+    err.location = Some((file, line));
+    err.code_frame = code_frame;
+}
 
 impl Error for DuktapeError {
     fn description(&self) -> &str { "script error:" }
 
-    fn cause(&self) -> Option<&Error> { None }
+    fn cause(&self) -> Option<&Error> {
+        self.io_error.as_ref().map(|err| err as &Error)
+    }
 }
 
 impl fmt::Display for DuktapeError {
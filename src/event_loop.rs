@@ -0,0 +1,561 @@
+//! Timers for JavaScript code, driven by an explicit event loop.  Duktape
+//! doesn't provide `setTimeout` and friends, because it has no idea how
+//! your application schedules work.  An `EventLoop` installs them, keeps
+//! track of pending timers in Rust, and runs them when you ask it to.
+//!
+//! ```
+//! use std::time::Duration;
+//! use duktape::{Context, EventLoop, Value};
+//!
+//! let ctx = Context::new().unwrap();
+//! let mut ev = EventLoop::with_virtual_clock(ctx).unwrap();
+//! ev.eval("var log = []; setTimeout(function () { log.push('a'); }, 100);")
+//!     .unwrap();
+//! ev.run_for(Duration::milliseconds(50)).unwrap();
+//! assert_eq!(Ok(Value::Number(0.0)), ev.eval("log.length"));
+//! ev.run_until_idle().unwrap();
+//! assert_eq!(Ok(Value::Number(1.0)), ev.eval("log.length"));
+//! ```
+
+use std::old_io::timer::sleep;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+use time::precise_time_ns;
+use ffi::*;
+use errors::*;
+use types::Value;
+use args::Args;
+use context::{Context, Borrowed};
+use encoder::DuktapeEncodable;
+use state;
+use promise::{self, PendingPromise};
+
+/// The hidden global stash property holding an object which maps timer
+/// IDs to `[callback, args...]` arrays, as a NUL-terminated byte string.
+const TIMERS_PROP: &'static [u8] = b"\xfftimers\0";
+
+/// A pending timer.
+struct Timer {
+    id: u32,
+    /// When the timer should fire, in milliseconds since the loop started.
+    due: f64,
+    /// How often the timer repeats, if it's an interval.
+    interval: Option<f64>,
+    /// Used to run timers with the same due time in the order they were
+    /// scheduled.
+    seq: u64
+}
+
+/// How long to sleep between checks for finished promises, in
+/// milliseconds.
+const POLL_INTERVAL: f64 = 1.0;
+
+/// The shortest delay between runs of an interval, in milliseconds.
+/// Otherwise `setInterval(f, 0)` would always be due, and the loop would
+/// never get past it.
+const MIN_INTERVAL: f64 = 1.0;
+
+/// The Rust side of the event loop, stored in our `HeapState` so that our
+/// JavaScript callbacks can find it.
+pub struct LoopState {
+    timers: Vec<Timer>,
+    /// Promises waiting for Rust code to finish.
+    pub futures: Vec<PendingPromise>,
+    next_id: u32,
+    next_seq: u64,
+    /// The current time, in milliseconds since the loop started.  When
+    /// using a real clock, this is updated whenever we look at the clock.
+    now: f64,
+    /// When we started, if we're using a real clock.
+    started_ns: Option<u64>
+}
+
+impl LoopState {
+    fn new(real_clock: bool) -> LoopState {
+        LoopState{timers: vec!(), futures: vec!(), next_id: 1, next_seq: 0,
+                  now: 0.0,
+                  started_ns: if real_clock { Some(precise_time_ns()) }
+                              else { None }}
+    }
+
+    /// Update `now` from the real clock, if we have one.
+    fn update_clock(&mut self) {
+        if let Some(started) = self.started_ns {
+            self.now = (precise_time_ns() - started) as f64 / 1_000_000.0;
+        }
+    }
+
+    /// Allocate a new timer or promise ID.
+    pub fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn schedule(&mut self, id: u32, delay: f64, interval: Option<f64>) {
+        self.update_clock();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.timers.push(Timer{id: id, due: self.now + delay,
+                               interval: interval, seq: seq});
+    }
+
+    fn cancel(&mut self, id: u32) -> bool {
+        let before = self.timers.len();
+        self.timers.retain(|t| t.id != id);
+        self.timers.len() != before
+    }
+
+    /// The index of the next timer to run.
+    fn next_timer(&self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for (i, t) in self.timers.iter().enumerate() {
+            best = match best {
+                Some(b) if (self.timers[b].due, self.timers[b].seq) <=
+                    (t.due, t.seq) => Some(b),
+                _ => Some(i)
+            };
+        }
+        best
+    }
+}
+
+/// Get the `LoopState` for the heap containing `ctx`.  Callers must not
+/// hold onto the result while running JavaScript code.  Re-exported
+/// within the crate, but not outside.
+pub unsafe fn loop_state<'a>(ctx: *mut duk_context) ->
+    DuktapeResult<&'a mut LoopState>
+{
+    match state::get(ctx).and_then(|s| s.event_loop.as_mut()) {
+        Some(q) => Ok(q),
+        None => Err(DuktapeError::from_code_and_str(
+            ErrorCode::Error, "no event loop is installed"))
+    }
+}
+
+/// Push the global stash object named `name`, creating it if necessary.
+/// `name` must be NUL-terminated.  Re-exported within the crate, but not
+/// outside.
+pub unsafe fn push_stash_table(ctx: *mut duk_context, name: &[u8]) {
+    let name = name.as_ptr() as *const i8;
+    duk_push_global_stash(ctx);
+    duk_get_prop_string(ctx, -1, name);
+    if duk_is_object(ctx, -1) == 0 {
+        duk_pop(ctx);
+        duk_push_object(ctx);
+        duk_dup_top(ctx);
+        duk_put_prop_string(ctx, -3, name);
+    }
+    duk_remove(ctx, -2);
+}
+
+/// Store the callback and extra arguments of the current function call
+/// in our timers table, and schedule a timer for it.
+fn add_timer(ctx: &mut Context<Borrowed>, args: &Args,
+             delay_idx: Option<usize>, repeat: bool) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    let delay = match delay_idx {
+        Some(idx) => args.get::<f64>(idx).unwrap_or(0.0),
+        None => 0.0
+    };
+    let delay = if delay.is_nan() || delay < 0.0 { 0.0 } else { delay };
+    let delay = if repeat { delay.max(MIN_INTERVAL) } else { delay };
+    let first_arg = delay_idx.map(|i| i + 1).unwrap_or(1);
+    unsafe {
+        let ptr = ctx.as_mut_ptr();
+        if args.len() == 0 || duk_is_function(ptr, 0) == 0 {
+            return Err(DuktapeError::from_code_and_str(
+                ErrorCode::Type, "argument 0: Expected function"));
+        }
+        if duk_check_stack(ptr, 4) == 0 {
+            return Err(DuktapeError::from_code(ErrorCode::Alloc));
+        }
+        let state = try!(loop_state(ptr));
+        let id = state.next_id();
+        state.schedule(id, delay, if repeat { Some(delay) } else { None });
+
+        // Stack: timers entry
+        push_stash_table(ptr, TIMERS_PROP);
+        duk_push_array(ptr);
+        duk_dup(ptr, 0);
+        duk_put_prop_index(ptr, -2, 0);
+        for (n, i) in range(first_arg, args.len()).enumerate() {
+            duk_dup(ptr, i as duk_idx_t);
+            duk_put_prop_index(ptr, -2, (n + 1) as u32);
+        }
+        duk_put_prop_index(ptr, -2, id);
+        duk_pop(ptr);
+        Ok(Box::new(id as f64))
+    }
+}
+
+/// Forget about timer `id`.
+unsafe fn remove_timer(ctx: *mut duk_context, id: u32) {
+    if let Ok(state) = loop_state(ctx) {
+        state.cancel(id);
+    }
+    push_stash_table(ctx, TIMERS_PROP);
+    duk_del_prop_index(ctx, -1, id);
+    duk_pop(ctx);
+}
+
+fn set_timeout(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    add_timer(ctx, args, Some(1), false)
+}
+
+fn set_interval(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    add_timer(ctx, args, Some(1), true)
+}
+
+fn set_immediate(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    add_timer(ctx, args, None, false)
+}
+
+fn clear_timer(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    if let Some(id) = args.get::<u32>(0) {
+        unsafe { remove_timer(ctx.as_mut_ptr(), id); }
+    }
+    Ok(Box::new(Value::Undefined))
+}
+
+/// The global functions installed by `install`.
+const TIMER_FNS: [&'static str; 6] =
+    ["setTimeout", "setInterval", "setImmediate",
+     "clearTimeout", "clearInterval", "clearImmediate"];
+
+/// Install the timer functions and our `Promise` polyfill in `ctx`, using
+/// either a real or virtual clock.  If the polyfill fails, the timers are
+/// removed again.
+fn install(ctx: &mut Context, real_clock: bool) -> DuktapeResult<()> {
+    unsafe {
+        state::get_or_install(ctx.as_mut_ptr()).event_loop =
+            Some(LoopState::new(real_clock));
+    }
+    ctx.register("setTimeout", set_timeout, None);
+    ctx.register("setInterval", set_interval, None);
+    ctx.register("setImmediate", set_immediate, None);
+    ctx.register("clearTimeout", clear_timer, Some(1));
+    ctx.register("clearInterval", clear_timer, Some(1));
+    ctx.register("clearImmediate", clear_timer, Some(1));
+    let result = promise::install(ctx);
+    if result.is_err() { uninstall(ctx); }
+    result
+}
+
+/// Forget our timers and remove the timer functions from `ctx`.  Scripts
+/// may have made some of them undeletable, in which case they're left
+/// behind, but setting a timer fails once `event_loop` is cleared.
+fn uninstall(ctx: &mut Context) {
+    unsafe {
+        if let Some(state) = state::get(ctx.as_mut_ptr()) {
+            state.event_loop = None;
+        }
+    }
+    for name in TIMER_FNS.iter() {
+        ctx.delete_global(*name);
+    }
+}
+
+/// A `Context` with `setTimeout`, `setInterval`, `setImmediate` and the
+/// matching `clear` functions installed, plus a `Promise` polyfill if
+/// needed.  Timers only run, and `Promise` objects returned by Rust
+/// callbacks are only settled, when you call `run_until_idle` or
+/// `run_for`.
+pub struct EventLoop {
+    ctx: Context
+}
+
+impl EventLoop {
+    /// Install timers in `ctx`, using the system clock.  This fails if
+    /// the `Promise` polyfill can't be loaded.
+    pub fn new(mut ctx: Context) -> DuktapeResult<EventLoop> {
+        try!(install(&mut ctx, true));
+        Ok(EventLoop{ctx: ctx})
+    }
+
+    /// Install timers in `ctx`, using a virtual clock which only advances
+    /// when the event loop runs.  Time passes instantly, which makes this
+    /// useful for tests.
+    pub fn with_virtual_clock(mut ctx: Context) ->
+        DuktapeResult<EventLoop>
+    {
+        try!(install(&mut ctx, false));
+        Ok(EventLoop{ctx: ctx})
+    }
+
+    /// Remove the timer functions and return the underlying context.
+    /// Any pending timers are discarded, and pending promises will never
+    /// be settled.
+    pub fn into_inner(mut self) -> Context {
+        uninstall(&mut self.ctx);
+        let EventLoop{ctx} = self;
+        ctx
+    }
+
+    fn state(&mut self) -> &mut LoopState {
+        unsafe { loop_state(self.ctx.as_mut_ptr()).unwrap() }
+    }
+
+    /// The time on the event loop's clock, measured from when it was
+    /// created.
+    pub fn now(&mut self) -> Duration {
+        let state = self.state();
+        state.update_clock();
+        Duration::microseconds((state.now * 1000.0) as i64)
+    }
+
+    /// The number of timers and promises waiting to run.
+    pub fn pending(&mut self) -> usize {
+        let state = self.state();
+        state.timers.len() + state.futures.len()
+    }
+
+    /// Settle the first promise whose result is ready, if any.
+    fn settle_one(&mut self) -> DuktapeResult<bool> {
+        let ready = {
+            let state = self.state();
+            let mut ready = None;
+            for i in range(0, state.futures.len()) {
+                let polled = state.futures[i].poll();
+                if let Some(result) = polled {
+                    ready = Some((state.futures.remove(i), result));
+                    break;
+                }
+            }
+            ready
+        };
+        match ready {
+            Some((pending, result)) => unsafe {
+                try!(pending.settle(self.ctx.as_mut_ptr(), result));
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Remove timer `index` from the queue and run it, advancing a
+    /// virtual clock if necessary.
+    fn fire_next(&mut self, index: usize) -> DuktapeResult<()> {
+        let timer = {
+            let state = self.state();
+            let timer = state.timers.remove(index);
+            if state.started_ns.is_none() && timer.due > state.now {
+                state.now = timer.due;
+            }
+            if let Some(interval) = timer.interval {
+                // Reschedule intervals before running them, so they can
+                // cancel themselves.  We never schedule an interval in the
+                // past, or we might never catch up.
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                let due = timer.due.max(state.now) + interval;
+                state.timers.push(Timer{id: timer.id, due: due,
+                                        interval: Some(interval), seq: seq});
+            }
+            timer
+        };
+        unsafe { self.fire(&timer) }
+    }
+
+    /// Call the JavaScript function for `timer`.
+    unsafe fn fire(&mut self, timer: &Timer) -> DuktapeResult<()> {
+        let ptr = self.ctx.as_mut_ptr();
+        let top = duk_get_top(ptr);
+        push_stash_table(ptr, TIMERS_PROP);
+        duk_get_prop_index(ptr, -1, timer.id);
+        if timer.interval.is_none() {
+            duk_del_prop_index(ptr, -2, timer.id);
+        }
+        if duk_is_object(ptr, -1) == 0 {
+            // Cleared by somebody else.
+            duk_set_top(ptr, top);
+            return Ok(());
+        }
+        let len = duk_get_length(ptr, -1) as duk_idx_t;
+        if duk_check_stack(ptr, len + 1) == 0 {
+            duk_set_top(ptr, top);
+            return Err(DuktapeError::from_code(ErrorCode::Alloc));
+        }
+        for i in range(0, len) {
+            duk_get_prop_index(ptr, top + 1, i as u32);
+        }
+        let status = duk_pcall(ptr, len - 1);
+        let result = self.ctx.pop_result(status);
+        duk_set_top(ptr, top);
+        result.map(|_| ())
+    }
+
+    /// Settle a promise or run a timer due at or before `deadline` (in
+    /// milliseconds), or wait a while if nothing is ready yet.  Returns
+    /// false once there's nothing left to do before `deadline`.
+    fn step(&mut self, deadline: f64) -> DuktapeResult<bool> {
+        if try!(self.settle_one()) { return Ok(true); }
+        let (next, now, waiting, real_clock) = {
+            let state = self.state();
+            state.update_clock();
+            let next = state.next_timer()
+                .map(|i| (i, state.timers[i].due))
+                .and_then(|(i, due)| {
+                    if due <= deadline { Some((i, due)) } else { None }
+                });
+            (next, state.now, !state.futures.is_empty(),
+             state.started_ns.is_some())
+        };
+
+        // A virtual clock jumps straight to the next timer, but only once
+        // no promises are waiting, because those take real time.
+        if let Some((index, due)) = next {
+            if due <= now || !(real_clock || waiting) {
+                try!(self.fire_next(index));
+                return Ok(true);
+            }
+        }
+
+        // Nothing is ready, so decide whether to wait.
+        let until = next.map(|(_, due)| due).unwrap_or(deadline);
+        if !waiting && (next.is_none() || !real_clock) { return Ok(false); }
+        if real_clock && until <= now { return Ok(false); }
+        let wait =
+            if !real_clock { POLL_INTERVAL }
+            else if waiting { POLL_INTERVAL.min(until - now) }
+            else { until - now };
+        sleep_ms(wait);
+        Ok(true)
+    }
+
+    /// Run timers and settle promises until none are left.  With a real
+    /// clock, this waits for each timer to become due.  If a timer throws
+    /// an error, we stop and return it, but the remaining timers stay
+    /// scheduled.
+    pub fn run_until_idle(&mut self) -> DuktapeResult<()> {
+        while try!(self.step(::std::f64::INFINITY)) {}
+        Ok(())
+    }
+
+    /// Run any timers which become due in the next `duration`, and then
+    /// wait for the rest of `duration` to pass.  With a virtual clock,
+    /// this advances the clock by exactly `duration`, but it first waits
+    /// for any pending promises to be settled.
+    pub fn run_for(&mut self, duration: Duration) -> DuktapeResult<()> {
+        let ms = duration.num_microseconds().unwrap_or(::std::i64::MAX)
+            as f64 / 1000.0;
+        let deadline = {
+            let state = self.state();
+            state.update_clock();
+            state.now + ms
+        };
+        while try!(self.step(deadline)) {}
+        let state = self.state();
+        state.update_clock();
+        if state.started_ns.is_some() {
+            if deadline > state.now { sleep_ms(deadline - state.now); }
+        } else {
+            state.now = deadline;
+        }
+        Ok(())
+    }
+}
+
+/// Sleep for `ms` milliseconds.
+fn sleep_ms(ms: f64) {
+    sleep(Duration::microseconds((ms * 1000.0) as i64));
+}
+
+impl Deref for EventLoop {
+    type Target = Context;
+    fn deref(&self) -> &Context { &self.ctx }
+}
+
+impl DerefMut for EventLoop {
+    fn deref_mut(&mut self) -> &mut Context { &mut self.ctx }
+}
+
+#[test]
+fn test_event_loop() {
+    use std::borrow::Cow;
+
+    let ctx = Context::new().unwrap();
+    let mut ev = EventLoop::with_virtual_clock(ctx).unwrap();
+    ev.eval("var log = [];\
+             function note(x) { log.push(x + '@' + Date.now()); }\
+             setTimeout(function (a, b) { log.push(a + b); }, 30, 'x', 'y');\
+             setTimeout(function () { log.push('t10'); }, 10);\
+             setImmediate(function () { log.push('now'); });\
+             var n = 0;\
+             var i = setInterval(function () {\
+               log.push('i' + (++n)); if (n == 3) clearInterval(i); }, 20);\
+             var c = setTimeout(function () { log.push('never'); }, 5);\
+             clearTimeout(c);").unwrap();
+    assert_eq!(4, ev.pending());
+
+    ev.run_for(Duration::milliseconds(20)).unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("now,t10,i1"))),
+               ev.eval("log.join(',')"));
+    assert_eq!(Duration::milliseconds(20), ev.now());
+
+    ev.run_until_idle().unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("now,t10,i1,xy,i2,i3"))),
+               ev.eval("log.join(',')"));
+    assert_eq!(Duration::milliseconds(60), ev.now());
+    assert_eq!(0, ev.pending());
+
+    // Errors stop the loop, but leave other timers alone.
+    ev.eval("setTimeout(function () { throw new Error('oops'); }, 1);\
+             setTimeout(function () { log.push('after'); }, 2);").unwrap();
+    assert_eq!(Err(DuktapeError::from_str("Error: oops")),
+               ev.run_until_idle());
+    assert_eq!(1, ev.pending());
+    ev.run_until_idle().unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("after"))),
+               ev.eval("log[log.length-1]"));
+
+    assert!(ev.eval("setTimeout('not a function', 1)").is_err());
+}
+
+#[test]
+fn test_short_intervals() {
+    use std::borrow::Cow;
+
+    // Intervals which would never wait run once per millisecond instead.
+    let ctx = Context::new().unwrap();
+    let mut ev = EventLoop::with_virtual_clock(ctx).unwrap();
+    ev.eval("var counts = [0, 0, 0];\
+             var ids = [0, NaN, -5].map(function (delay, i) {\
+               return setInterval(function () { counts[i]++; }, delay);\
+             });").unwrap();
+    ev.run_for(Duration::milliseconds(5)).unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("5,5,5"))),
+               ev.eval("counts.join(',')"));
+    ev.eval("ids.forEach(function (id) { clearInterval(id); });").unwrap();
+    assert_eq!(0, ev.pending());
+}
+
+#[test]
+fn test_uninstall() {
+    // Timers which can't be deleted are left behind, but stop working.
+    let ctx = Context::new().unwrap();
+    let mut ev = EventLoop::with_virtual_clock(ctx).unwrap();
+    ev.eval("Object.defineProperty(this, 'setTimeout', \
+               {configurable: false});").unwrap();
+    let mut ctx = ev.into_inner();
+    assert_eq!(Ok(Value::Bool(true)),
+               ctx.eval("typeof setInterval === 'undefined'"));
+    assert_eq!(Err(DuktapeError::from_str("Error: no event loop is installed")),
+               ctx.eval("setTimeout(function () {}, 1)"));
+
+    // If the `Promise` polyfill fails, the timers are removed.
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("Object.defineProperty(this, 'Promise', {get: function () { \
+                throw new Error('no promises'); }});").unwrap();
+    assert_eq!(Err(DuktapeError::from_str("Error: no promises")),
+               EventLoop::with_virtual_clock(ctx).map(|_| ()));
+}
@@ -0,0 +1,220 @@
+//! Run JavaScript on a pool of worker threads, each of which owns its own
+//! `Context`.  Jobs are sent to the workers over a channel, and results
+//! come back over another channel, so no `Context` is ever shared between
+//! threads.
+//!
+//! ```
+//! use duktape::{Executor, Value};
+//!
+//! let exec = Executor::with_init(2, |ctx| {
+//!     ctx.eval("function double(x) { return 2*x; }").unwrap();
+//! });
+//! let result = exec.eval("double(21)").recv().unwrap();
+//! assert_eq!(Ok(Value::Number(42.0)), result);
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use errors::*;
+use types::Value;
+use context::Context;
+
+/// A job which can be run on a worker thread.  This is a workaround for
+/// the fact that we can't call a `Box<FnOnce>` directly.
+trait Job: Send {
+    fn run(self: Box<Self>, ctx: &mut Context);
+}
+
+impl<F: FnOnce(&mut Context) + Send> Job for F {
+    fn run(self: Box<F>, ctx: &mut Context) { (*self)(ctx) }
+}
+
+/// A fixed-size set of worker threads, each owning a `Context`.  Jobs are
+/// handed to whichever worker is free first, so don't rely on global
+/// state left behind by one job being visible to the next.
+///
+/// If a job panics, the `Receiver` for that job's result will be closed,
+/// and the worker is restarted with a fresh `Context` (and a fresh call to
+/// `init`).  If a worker can't create its `Context`, or `init` panics, the
+/// worker exits for good, and once no workers are left, `run` returns an
+/// error.
+pub struct Executor {
+    sender: Option<Sender<Box<Job + 'static>>>,
+    workers: Vec<thread::JoinHandle>
+}
+
+type Init = Box<Fn(&mut Context) + Send + Sync>;
+type JobReceiver = Arc<Mutex<Receiver<Box<Job + 'static>>>>;
+
+/// Run jobs from `receiver` on a child thread, restarting it whenever a
+/// job panics.
+fn supervise(receiver: JobReceiver, init: Arc<Init>) {
+    loop {
+        let ready = Arc::new(AtomicBool::new(false));
+        let worker_ready = ready.clone();
+        let worker_receiver = receiver.clone();
+        let worker_init = init.clone();
+        let result = thread::spawn(move || {
+            work(worker_receiver, worker_init, worker_ready)
+        }).join();
+        if result.is_ok() {
+            // The executor has been dropped, or we have no `Context`.
+            return;
+        } else if ready.load(Ordering::SeqCst) {
+            warn!("executor job panicked; restarting worker");
+        } else {
+            error!("executor init function panicked; stopping worker");
+            return;
+        }
+    }
+}
+
+/// Create a `Context`, set `ready` once `init` has run, and run jobs
+/// until the executor is dropped.
+fn work(receiver: JobReceiver, init: Arc<Init>, ready: Arc<AtomicBool>) {
+    let mut ctx = match Context::new() {
+        Ok(ctx) => ctx,
+        Err(err) => {
+            error!("executor worker can't create a context: {}", err);
+            return;
+        }
+    };
+    (**init)(&mut ctx);
+    ready.store(true, Ordering::SeqCst);
+    loop {
+        // Only hold the lock while waiting for a job.
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => break // The executor has been dropped.
+        };
+        job.run(&mut ctx);
+    }
+}
+
+impl Executor {
+    /// Create an executor with `threads` worker threads, each owning a
+    /// fresh `Context`.
+    pub fn new(threads: usize) -> Executor {
+        Executor::with_init(threads, |_| {})
+    }
+
+    /// Create an executor with `threads` worker threads, and call `init`
+    /// on each worker's `Context` before it runs any jobs.  This is a good
+    /// place to load libraries and register callbacks.
+    pub fn with_init<F>(threads: usize, init: F) -> Executor
+        where F: Fn(&mut Context) + Send + Sync + 'static
+    {
+        let (sender, receiver) = channel::<Box<Job + 'static>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let init: Arc<Init> = Arc::new(Box::new(init));
+        let workers = range(0, threads).map(|_| {
+            let receiver = receiver.clone();
+            let init = init.clone();
+            thread::spawn(move || supervise(receiver, init))
+        }).collect();
+        Executor{sender: Some(sender), workers: workers}
+    }
+
+    /// Run `f` on a worker thread, passing it that worker's `Context`.
+    /// The result can be read from the returned `Receiver`, which will be
+    /// closed if `f` panics.  Fails if every worker has stopped.
+    pub fn run<R, F>(&self, f: F) -> DuktapeResult<Receiver<R>>
+        where R: Send + 'static, F: FnOnce(&mut Context) -> R + Send + 'static
+    {
+        let (tx, rx) = channel();
+        let job = move |ctx: &mut Context| {
+            // The caller may have lost interest in the result.
+            let _ = tx.send(f(ctx));
+        };
+        match self.sender.as_ref().unwrap().send(Box::new(job)) {
+            Ok(()) => Ok(rx),
+            Err(_) => Err(DuktapeError::from_str(
+                "executor has no running workers"))
+        }
+    }
+
+    /// Evaluate `code` on a worker thread.  If every worker has stopped,
+    /// the error is delivered over the returned `Receiver`.
+    pub fn eval(&self, code: &str) -> Receiver<DuktapeResult<Value<'static>>> {
+        let code = code.to_string();
+        match self.run(move |ctx| ctx.eval(&code[])) {
+            Ok(rx) => rx,
+            Err(err) => {
+                let (tx, rx) = channel();
+                tx.send(Err(err)).unwrap();
+                rx
+            }
+        }
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // Closing the channel tells the workers to exit once they've
+        // finished any queued jobs.
+        self.sender = None;
+        for worker in self.workers.drain() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn test_executor() {
+    let exec = Executor::with_init(3, |ctx| {
+        ctx.eval("function square(x) { return x*x; }").unwrap();
+    });
+    let results: Vec<Receiver<f64>> = range(0, 10).map(|i| {
+        exec.run(move |ctx| {
+            ctx.call_as("square", &[&(i as f64)]).unwrap()
+        }).unwrap()
+    }).collect();
+    let squares: Vec<f64> =
+        results.iter().map(|rx| rx.recv().unwrap()).collect();
+    assert_eq!(vec!(0.0, 1.0, 4.0, 9.0, 16.0, 25.0, 36.0, 49.0, 64.0, 81.0),
+               squares);
+
+    assert!(exec.eval("syntax error").recv().unwrap().is_err());
+}
+
+#[test]
+fn test_executor_restarts_panicking_workers() {
+    let exec = Executor::with_init(1, |ctx| {
+        ctx.eval("var x = 1;").unwrap();
+    });
+    let failed = exec.run(|_| -> f64 { panic!("job failed") }).unwrap();
+    assert!(failed.recv().is_err());
+    for _ in range(0, 3) {
+        let rx = exec.run(|ctx| ctx.eval("x += 1")).unwrap();
+        assert_eq!(Ok(Value::Number(2.0)), rx.recv().unwrap());
+        let rx = exec.run(|_| -> f64 { panic!("job failed") }).unwrap();
+        assert!(rx.recv().is_err());
+    }
+}
+
+#[test]
+fn test_executor_without_workers() {
+    let exec = Executor::with_init(2, |_| panic!("init failed"));
+    // Our jobs may be queued before the workers give up, in which case
+    // they're dropped without running.
+    match exec.run(|_| 1.0f64) {
+        Ok(rx) => assert!(rx.recv().is_err()),
+        Err(_) => {}
+    }
+    match exec.eval("1").recv() {
+        Ok(result) => assert!(result.is_err()),
+        Err(_) => {}
+    }
+}
+
+#[test]
+fn test_context_is_send() {
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("var x = 1;").unwrap();
+    let result = thread::scoped(move || {
+        ctx.eval("x + 1").unwrap()
+    }).join();
+    assert_eq!(Value::Number(2.0), result);
+}
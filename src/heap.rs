@@ -0,0 +1,384 @@
+//! Heap creation and memory accounting.  We give every heap a custom
+//! allocator which keeps track of how much memory it's using, so that
+//! long-lived contexts can be monitored.
+
+use std::collections::HashSet;
+use std::mem::{size_of, transmute};
+use std::ptr::null_mut;
+use libc::{self, c_void, size_t};
+use ffi::*;
+use context::Context;
+use state;
+
+/// Space reserved in front of each allocation to remember its size.  This
+/// is large enough to preserve the alignment guaranteed by `malloc`.
+const HEADER: usize = 16;
+
+/// Counters updated by our allocator, by our mark-and-sweep sentinel and
+/// by `Context::gc`.
+pub struct HeapCounters {
+    bytes: usize,
+    blocks: usize,
+    allocations: u64,
+    mark_and_sweep_runs: u64,
+    /// How many times `Context::gc` has been called.
+    pub explicit_gc_calls: u64
+}
+
+/// A snapshot of a heap's memory usage.
+///
+/// Duktape 1.0 doesn't expose its internal object, string and buffer
+/// counts, so we find them by walking everything reachable from the
+/// global object, the stashes and the current value stack.  This takes
+/// time proportional to the size of the heap, and misses values only
+/// reachable from the stacks of other threads or from compiled functions.
+///
+/// Most garbage is freed by reference counting as soon as it becomes
+/// unreachable.  Only reference cycles need a mark-and-sweep collection,
+/// which duktape runs when it needs to, or when `Context::gc` is called.
+/// We count mark-and-sweep runs, but there's no matching statistic for
+/// reference counting, because duktape frees those values inline without
+/// any hook we could count.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The number of bytes currently allocated by the heap.
+    pub bytes_allocated: usize,
+    /// The number of memory blocks currently allocated by the heap.
+    pub blocks_allocated: usize,
+    /// The total number of allocations and reallocations performed since
+    /// the heap was created.
+    pub total_allocations: u64,
+    /// The number of reachable objects, including functions.
+    pub objects: usize,
+    /// The number of reachable strings, including property names.
+    pub strings: usize,
+    /// The number of reachable buffers.
+    pub buffers: usize,
+    /// The number of mark-and-sweep collections which have finished,
+    /// whether duktape started them itself or `Context::gc` did.
+    pub mark_and_sweep_runs: u64,
+    /// The number of collections requested using `Context::gc`.
+    pub explicit_gc_calls: u64,
+    /// The number of entries in the heap stash.
+    pub heap_stash_entries: usize,
+    /// The number of entries in the global stash.
+    pub global_stash_entries: usize
+}
+
+unsafe fn counters<'a>(udata: *mut c_void) -> &'a mut HeapCounters {
+    transmute(udata)
+}
+
+/// Find the start of the block containing `ptr`, and its size.
+unsafe fn block(ptr: *mut c_void) -> (*mut c_void, usize) {
+    let base = (ptr as *mut u8).offset(-(HEADER as isize));
+    (base as *mut c_void, *(base as *mut usize))
+}
+
+/// Record `size` in the header of `base` and return the user's pointer.
+unsafe fn init_block(base: *mut c_void, size: usize) -> *mut c_void {
+    *(base as *mut usize) = size;
+    (base as *mut u8).offset(HEADER as isize) as *mut c_void
+}
+
+unsafe extern "C" fn heap_alloc(udata: *mut c_void, size: duk_size_t) ->
+    *mut c_void
+{
+    let size = size as usize;
+    if size == 0 { return null_mut(); }
+    let base = libc::malloc((size + HEADER) as size_t);
+    if base.is_null() { return null_mut(); }
+    let counters = counters(udata);
+    counters.bytes += size;
+    counters.blocks += 1;
+    counters.allocations += 1;
+    init_block(base, size)
+}
+
+unsafe extern "C" fn heap_realloc(udata: *mut c_void, ptr: *mut c_void,
+                                  size: duk_size_t) -> *mut c_void {
+    if ptr.is_null() { return heap_alloc(udata, size); }
+    if size == 0 {
+        heap_free(udata, ptr);
+        return null_mut();
+    }
+    let size = size as usize;
+    let (base, old_size) = block(ptr);
+    let base = libc::realloc(base, (size + HEADER) as size_t);
+    // On failure, the original block is left untouched.
+    if base.is_null() { return null_mut(); }
+    let counters = counters(udata);
+    counters.bytes = counters.bytes - old_size + size;
+    counters.allocations += 1;
+    init_block(base, size)
+}
+
+unsafe extern "C" fn heap_free(udata: *mut c_void, ptr: *mut c_void) {
+    if ptr.is_null() { return; }
+    let (base, size) = block(ptr);
+    let counters = counters(udata);
+    counters.bytes -= size;
+    counters.blocks -= 1;
+    libc::free(base);
+}
+
+/// Create a new heap which uses our allocator, returning null on failure.
+/// Re-exported within the crate, but not outside.
+pub unsafe fn create_heap() -> *mut duk_context {
+    assert!(size_of::<usize>() <= HEADER);
+    let counters: *mut HeapCounters = transmute(Box::new(HeapCounters{
+        bytes: 0, blocks: 0, allocations: 0, mark_and_sweep_runs: 0,
+        explicit_gc_calls: 0
+    }));
+    let ptr = duk_create_heap(Some(heap_alloc), Some(heap_realloc),
+                              Some(heap_free), counters as *mut c_void, None);
+    if ptr.is_null() {
+        let _counters: Box<HeapCounters> = transmute(counters);
+        return ptr;
+    }
+    state::get_or_install(ptr).counters = counters;
+    push_sentinel(ptr);
+    ptr
+}
+
+/// Create an unreachable reference cycle with a finalizer.  Reference
+/// counting can never free it, so the finalizer runs at the end of the
+/// next mark-and-sweep collection.
+unsafe fn push_sentinel(ctx: *mut duk_context) {
+    duk_push_object(ctx);
+    duk_dup_top(ctx);
+    duk_put_prop_string(ctx, -2, b"self\0".as_ptr() as *const i8);
+    duk_push_c_function(ctx, Some(sentinel_finalizer), 1);
+    duk_set_finalizer(ctx, -2);
+    duk_pop(ctx);
+}
+
+/// Count a mark-and-sweep collection, and set up a sentinel for the next
+/// one, unless the heap is being destroyed.
+unsafe extern "C" fn sentinel_finalizer(ctx: *mut duk_context) ->
+    duk_ret_t
+{
+    if let Some(c) = state::get(ctx).map(|s| s.counters) {
+        if !c.is_null() {
+            (*c).mark_and_sweep_runs += 1;
+            push_sentinel(ctx);
+        }
+    }
+    0
+}
+
+/// Destroy a heap created by `create_heap`, along with its `HeapState`.
+pub unsafe fn destroy_heap(ptr: *mut duk_context) {
+    let counters = state::get(ptr).map(|s| s.counters)
+        .unwrap_or(null_mut());
+    state::destroy(ptr);
+    duk_destroy_heap(ptr);
+    // Our allocator is used right up until the heap is gone.
+    if !counters.is_null() {
+        let _counters: Box<HeapCounters> = transmute(counters);
+    }
+}
+
+/// Count the own properties of the object on top of the stack, and pop
+/// it.
+unsafe fn count_and_pop(ctx: *mut duk_context) -> usize {
+    let mut count = 0;
+    duk_enum(ctx, -1, DUK_ENUM_OWN_PROPERTIES_ONLY |
+             DUK_ENUM_INCLUDE_NONENUMERABLE | DUK_ENUM_INCLUDE_INTERNAL);
+    while duk_next(ctx, -1, 0) != 0 {
+        count += 1;
+        duk_pop(ctx);
+    }
+    duk_pop_2(ctx);
+    count
+}
+
+/// The distinct objects, strings and buffers found while walking a heap.
+struct Census {
+    seen: HashSet<usize>,
+    objects: usize,
+    strings: usize,
+    buffers: usize
+}
+
+impl Census {
+    /// Count the value at `idx` if we haven't seen it before.  New
+    /// objects are appended to the array at `queue`, so that we can visit
+    /// their properties later.
+    unsafe fn visit(&mut self, ctx: *mut duk_context, idx: duk_idx_t,
+                    queue: duk_idx_t, queued: &mut u32) {
+        let kind = duk_get_type(ctx, idx);
+        if kind != DUK_TYPE_OBJECT && kind != DUK_TYPE_STRING &&
+            kind != DUK_TYPE_BUFFER {
+            return;
+        }
+        // Heap-allocated values are coerced to their addresses.
+        duk_dup(ctx, idx);
+        let addr = duk_to_pointer(ctx, -1) as usize;
+        duk_pop(ctx);
+        if !self.seen.insert(addr) { return; }
+        match kind {
+            DUK_TYPE_STRING => self.strings += 1,
+            DUK_TYPE_BUFFER => self.buffers += 1,
+            _ => {
+                self.objects += 1;
+                duk_dup(ctx, idx);
+                duk_put_prop_index(ctx, queue, *queued);
+                *queued += 1;
+            }
+        }
+    }
+}
+
+/// Replace the object and property name on top of the stack with the
+/// property's descriptor, so that we can look at accessors without
+/// calling them.  Called using `duk_safe_call`.
+unsafe extern "C" fn get_descriptor(ctx: *mut duk_context) -> duk_ret_t {
+    duk_push_global_object(ctx);
+    duk_get_prop_string(ctx, -1, b"Object\0".as_ptr() as *const i8);
+    duk_get_prop_string(ctx, -1,
+                        b"getOwnPropertyDescriptor\0".as_ptr() as *const i8);
+    duk_insert(ctx, -5);
+    duk_pop_2(ctx);
+    duk_call(ctx, 2);
+    1
+}
+
+/// Walk everything reachable from the current value stack, the global
+/// object and the stashes, and count what we find.
+unsafe fn census(ctx: *mut duk_context) -> Census {
+    let mut census = Census{seen: HashSet::new(), objects: 0, strings: 0,
+                            buffers: 0};
+    let top = duk_get_top(ctx);
+    if duk_check_stack(ctx, 16) == 0 { return census; }
+
+    // Our queue isn't part of the heap we're measuring.
+    duk_push_array(ctx);
+    let queue = top;
+    duk_dup(ctx, queue);
+    census.seen.insert(duk_to_pointer(ctx, -1) as usize);
+    duk_pop(ctx);
+
+    let mut queued = 0u32;
+    for idx in range(0, top) {
+        census.visit(ctx, idx, queue, &mut queued);
+    }
+    duk_push_global_object(ctx);
+    duk_push_heap_stash(ctx);
+    duk_push_global_stash(ctx);
+    for idx in range(queue + 1, queue + 4) {
+        census.visit(ctx, idx, queue, &mut queued);
+    }
+    duk_set_top(ctx, queue + 1);
+
+    let mut next = 0;
+    while next < queued {
+        duk_get_prop_index(ctx, queue, next);
+        next += 1;
+        duk_get_prototype(ctx, -1);
+        census.visit(ctx, -1, queue, &mut queued);
+        duk_pop(ctx);
+        // Look at a proxy's own target and handler, not its traps.
+        duk_enum(ctx, -1, DUK_ENUM_OWN_PROPERTIES_ONLY |
+                 DUK_ENUM_INCLUDE_NONENUMERABLE | DUK_ENUM_INCLUDE_INTERNAL |
+                 DUK_ENUM_NO_PROXY_BEHAVIOR);
+        while duk_next(ctx, -1, 0) != 0 {
+            census.visit(ctx, -1, queue, &mut queued);
+            duk_dup(ctx, -3);
+            duk_dup(ctx, -2);
+            if duk_safe_call(ctx, Some(get_descriptor), 2, 1) ==
+                DUK_EXEC_SUCCESS && duk_is_object(ctx, -1) != 0
+            {
+                let fields: [&[u8]; 3] = [b"value\0", b"get\0", b"set\0"];
+                for field in fields.iter() {
+                    duk_get_prop_string(ctx, -1,
+                                        field.as_ptr() as *const i8);
+                    census.visit(ctx, -1, queue, &mut queued);
+                    duk_pop(ctx);
+                }
+            }
+            duk_pop_2(ctx);
+        }
+        duk_pop_2(ctx);
+    }
+    duk_set_top(ctx, top);
+    census
+}
+
+/// Collect statistics about the heap containing `ctx`.
+pub fn stats<H>(ctx: &mut Context<H>) -> HeapStats {
+    unsafe {
+        let ptr = ctx.as_mut_ptr();
+        let mut stats = HeapStats{
+            bytes_allocated: 0, blocks_allocated: 0, total_allocations: 0,
+            objects: 0, strings: 0, buffers: 0, mark_and_sweep_runs: 0,
+            explicit_gc_calls: 0, heap_stash_entries: 0,
+            global_stash_entries: 0
+        };
+        if let Some(c) = state::get(ptr).map(|s| s.counters) {
+            if !c.is_null() {
+                stats.bytes_allocated = (*c).bytes;
+                stats.blocks_allocated = (*c).blocks;
+                stats.total_allocations = (*c).allocations;
+                stats.mark_and_sweep_runs = (*c).mark_and_sweep_runs;
+                stats.explicit_gc_calls = (*c).explicit_gc_calls;
+            }
+        }
+        let census = census(ptr);
+        stats.objects = census.objects;
+        stats.strings = census.strings;
+        stats.buffers = census.buffers;
+        duk_push_heap_stash(ptr);
+        stats.heap_stash_entries = count_and_pop(ptr);
+        duk_push_global_stash(ptr);
+        stats.global_stash_entries = count_and_pop(ptr);
+        stats
+    }
+}
+
+#[test]
+fn test_heap_stats() {
+    let mut ctx = Context::new().unwrap();
+    let before = ctx.heap_stats();
+    assert!(before.bytes_allocated > 0);
+    assert!(before.blocks_allocated > 0);
+    assert_eq!(0, before.explicit_gc_calls);
+    // The built-in objects and their property names.
+    assert!(before.objects > 0);
+    assert!(before.strings > 0);
+    // Our `HeapState` lives in the heap stash.
+    assert!(before.heap_stash_entries >= 1);
+
+    ctx.eval("var big = []; \
+              for (var i = 0; i < 10000; i++) big.push({ n: i });").unwrap();
+    ctx.set_global("buf", &::types::Buffer(vec!(1, 2, 3))).unwrap();
+    let during = ctx.heap_stats();
+    assert!(during.bytes_allocated > before.bytes_allocated);
+    assert!(during.total_allocations > before.total_allocations);
+    assert!(during.objects >= before.objects + 10001);
+    assert!(during.buffers > before.buffers);
+
+    // Cycles can only be freed by mark-and-sweep.
+    ctx.eval("big = null; buf = null; \
+              var a = {}, b = { a: a }; a.b = b; a = b = null;").unwrap();
+    ctx.gc();
+    let after = ctx.heap_stats();
+    assert!(after.bytes_allocated < during.bytes_allocated);
+    assert!(after.objects < during.objects);
+    assert!(after.buffers < during.buffers);
+    assert_eq!(1, after.explicit_gc_calls);
+    assert!(after.mark_and_sweep_runs > during.mark_and_sweep_runs);
+
+    // Compacting an object shouldn't change what's in it.
+    ctx.compact("Math").unwrap();
+    assert_eq!(Ok(::types::Value::Bool(true)),
+               ctx.eval("Math.floor(1.5) === 1"));
+    ctx.eval("var table = {a: 1, b: 2}; delete table.a;").unwrap();
+    ctx.compact("table").unwrap();
+    assert_eq!(Ok(2.0f64), ctx.eval("table.b"));
+
+    // Other values are left alone.
+    ctx.eval("var n = 1;").unwrap();
+    ctx.compact("n").unwrap();
+    assert_eq!(Ok(1.0f64), ctx.eval("n"));
+}
@@ -0,0 +1,455 @@
+//! Host objects let scripts access Rust data structures lazily.  Each
+//! host object is exposed to JavaScript as a `Proxy`, so every property
+//! access calls into Rust on demand, instead of encoding the whole
+//! structure up front.
+//!
+//! ```
+//! use std::collections::BTreeMap;
+//! use duktape::{Context, HostObject, PropertyValue, DuktapeEncodable,
+//!               DuktapeResult, Value};
+//!
+//! struct Config(BTreeMap<String, String>);
+//!
+//! impl HostObject for Config {
+//!     fn get(&mut self, key: &str) -> Option<Box<DuktapeEncodable>> {
+//!         self.0.get(key)
+//!             .map(|v| Box::new(v.clone()) as Box<DuktapeEncodable>)
+//!     }
+//!
+//!     fn set(&mut self, key: &str, value: &PropertyValue) ->
+//!         DuktapeResult<bool>
+//!     {
+//!         self.0.insert(key.to_string(), try!(value.decode()));
+//!         Ok(true)
+//!     }
+//!
+//!     fn keys(&mut self) -> Vec<String> { self.0.keys().cloned().collect() }
+//! }
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.register_host_object("config", Config(BTreeMap::new())).unwrap();
+//! ctx.eval("config.mode = 'fast';").unwrap();
+//! assert_eq!(Ok(Value::Number(1.0)), ctx.eval("Object.keys(config).length"));
+//! ```
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::mem::transmute;
+use std::ptr::null_mut;
+use ffi::*;
+use errors::*;
+use types::Value;
+use args::Args;
+use context::{Context, Borrowed, Callback, get_global, put_global};
+use encoder::{Encoder, DuktapeEncodable};
+use decoder::DuktapeDecodable;
+use stack::{StackFrame, Slot};
+
+/// The hidden property of a proxy target which points to its host object.
+const HOST_PTR_PROP: [i8; 6] =
+    [-1, 'h' as i8, 'o' as i8, 's' as i8, 't' as i8, 0];
+
+/// The hidden global stash property where we cache our proxy handler
+/// object, as a NUL-terminated byte string.
+const HANDLER_PROP: &'static [u8] = b"\xffhostHandler\0";
+
+/// A Rust object whose properties can be accessed from JavaScript.  Only
+/// `get` is required; by default, host objects are read-only and have no
+/// enumerable keys.
+///
+/// Property access on the JavaScript side includes lookups of methods
+/// such as `toString`, so return `None` for keys you don't recognize.
+pub trait HostObject: Send + 'static {
+    /// Get the value of property `key`, or `None` if there is no such
+    /// property.
+    fn get(&mut self, key: &str) -> Option<Box<DuktapeEncodable + 'static>>;
+
+    /// Set property `key` to `value`.  Return false to refuse the
+    /// assignment, which is silently ignored in sloppy mode, and a
+    /// `TypeError` in strict mode.
+    fn set(&mut self, _key: &str, _value: &PropertyValue) ->
+        DuktapeResult<bool>
+    {
+        Ok(false)
+    }
+
+    /// Does property `key` exist?  This is used by the `in` operator.
+    fn has(&mut self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Delete property `key`.  Return false to refuse.
+    fn delete(&mut self, _key: &str) -> DuktapeResult<bool> {
+        Ok(false)
+    }
+
+    /// The keys visited by `for ... in` and `Object.keys`.
+    fn keys(&mut self) -> Vec<String> {
+        vec!()
+    }
+}
+
+/// A value being assigned to a property of a `HostObject`, which may be
+/// decoded as whatever type the host object expects.
+pub struct PropertyValue<'a> {
+    args: &'a Args,
+    idx: usize
+}
+
+impl<'a> PropertyValue<'a> {
+    /// Decode the value as type `T`.
+    pub fn decode<T: DuktapeDecodable>(&self) -> DuktapeResult<T> {
+        self.args.require(self.idx)
+    }
+
+    /// Is the value `undefined`?
+    pub fn is_undefined(&self) -> bool {
+        self.args.is_undefined(self.idx)
+    }
+}
+
+/// A host object owned by a proxy target, and whether a trap is using it.
+struct HostCell {
+    host: Box<HostObject>,
+    busy: bool
+}
+
+/// Call `f` with the host object for the proxy target passed as argument
+/// 0 of a trap.  If a trap further up the stack is already using the host
+/// object, fail instead of handing out a second `&mut` reference.
+unsafe fn with_host<R, F>(ctx: &mut Context<Borrowed>, f: F) ->
+    DuktapeResult<R>
+    where F: FnOnce(&mut HostObject) -> DuktapeResult<R>
+{
+    let ptr = ctx.as_mut_ptr();
+    duk_get_prop_string(ptr, 0, HOST_PTR_PROP.as_ptr());
+    let p = duk_get_pointer(ptr, -1);
+    duk_pop(ptr);
+    if p.is_null() {
+        return Err(DuktapeError::from_code_and_str(
+            ErrorCode::Type, "host object has been destroyed"));
+    }
+    let cell: &mut HostCell = transmute(p);
+    if cell.busy {
+        return Err(DuktapeError::from_code_and_str(
+            ErrorCode::Type, "host object is already in use"));
+    }
+    cell.busy = true;
+    let result = f(&mut *cell.host);
+    cell.busy = false;
+    result
+}
+
+fn trap_get(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    let key: String = try!(args.require(1));
+    let value = try!(unsafe { with_host(ctx, |h| Ok(h.get(&key[]))) });
+    Ok(value.unwrap_or_else(|| {
+        Box::new(Value::Undefined) as Box<DuktapeEncodable + 'static>
+    }))
+}
+
+fn trap_set(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    let key: String = try!(args.require(1));
+    let value = PropertyValue{args: args, idx: 2};
+    let ok = try!(unsafe { with_host(ctx, |h| h.set(&key[], &value)) });
+    Ok(Box::new(ok))
+}
+
+fn trap_has(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    let key: String = try!(args.require(1));
+    let found = try!(unsafe { with_host(ctx, |h| Ok(h.has(&key[]))) });
+    Ok(Box::new(found))
+}
+
+fn trap_delete(ctx: &mut Context<Borrowed>, args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    let key: String = try!(args.require(1));
+    let ok = try!(unsafe { with_host(ctx, |h| h.delete(&key[])) });
+    Ok(Box::new(ok))
+}
+
+fn trap_keys(ctx: &mut Context<Borrowed>, _args: &Args) ->
+    DuktapeResult<Box<DuktapeEncodable + 'static>>
+{
+    let keys = try!(unsafe { with_host(ctx, |h| Ok(h.keys())) });
+    Ok(Box::new(keys))
+}
+
+/// Drop the host object attached to a proxy target when it's collected.
+unsafe extern "C" fn finalize_host(ctx: *mut duk_context) -> duk_ret_t {
+    duk_get_prop_string(ctx, 0, HOST_PTR_PROP.as_ptr());
+    let p = duk_get_pointer(ctx, -1);
+    duk_pop(ctx);
+    if !p.is_null() {
+        duk_push_pointer(ctx, null_mut());
+        duk_put_prop_string(ctx, 0, HOST_PTR_PROP.as_ptr());
+        abort_on_panic!("unexpected panic while dropping a host object", {
+            let _cell: Box<HostCell> = transmute(p);
+        });
+    }
+    0
+}
+
+/// Call `new Proxy(target, handler)` with the three values on top of the
+/// stack.  Called using `duk_safe_call`.
+unsafe extern "C" fn new_proxy(ctx: *mut duk_context) -> duk_ret_t {
+    duk_new(ctx, 2);
+    1
+}
+
+/// Push our shared proxy handler, creating it if necessary.
+unsafe fn push_handler(ptr: *mut duk_context) {
+    let mut ctx = Context::from_borrowed_mut_ptr(ptr);
+    let handler = HANDLER_PROP.as_ptr() as *const i8;
+    duk_push_global_stash(ptr);
+    duk_get_prop_string(ptr, -1, handler);
+    if duk_is_object(ptr, -1) == 0 {
+        duk_pop(ptr);
+        duk_push_object(ptr);
+        let traps: [(&str, Callback, u16); 6] = [
+            ("get", trap_get, 3),
+            ("set", trap_set, 4),
+            ("has", trap_has, 2),
+            ("deleteProperty", trap_delete, 2),
+            ("enumerate", trap_keys, 1),
+            ("ownKeys", trap_keys, 1)
+        ];
+        for &(name, f, arg_count) in traps.iter() {
+            ctx.push_callback(f, Some(arg_count));
+            let c_name = CString::from_slice(name.as_bytes());
+            duk_put_prop_string(ptr, -2, c_name.as_ptr());
+        }
+        duk_dup_top(ptr);
+        duk_put_prop_string(ptr, -3, handler);
+    }
+    duk_remove(ptr, -2);
+}
+
+/// Push a proxy for `host`.
+unsafe fn push_host(ptr: *mut duk_context, host: Box<HostObject>) ->
+    DuktapeResult<()>
+{
+    if duk_check_stack(ptr, 6) == 0 {
+        return Err(DuktapeError::from_code(ErrorCode::Alloc));
+    }
+    try!(get_global(ptr, "Proxy"));
+
+    // Create our target, which owns the host object.
+    duk_push_object(ptr);
+    let cell: Box<HostCell> = Box::new(HostCell{host: host, busy: false});
+    duk_push_pointer(ptr, transmute(cell));
+    duk_put_prop_string(ptr, -2, HOST_PTR_PROP.as_ptr());
+    duk_push_c_function(ptr, Some(finalize_host), 1);
+    duk_set_finalizer(ptr, -2);
+
+    push_handler(ptr);
+    let status = duk_safe_call(ptr, Some(new_proxy), 3, 1);
+    if status != DUK_EXEC_SUCCESS {
+        let mut ctx = Context::from_borrowed_mut_ptr(ptr);
+        return Err(ctx.pop_result(status).err().unwrap());
+    }
+    Ok(())
+}
+
+/// A host object which can be returned from `HostObject::get`, or from a
+/// callback, so that scripts can walk a tree of host objects such as the
+/// sections of a configuration file.  Each `NestedHost` can only be
+/// encoded once, because the proxy we create takes ownership of it.
+pub struct NestedHost {
+    host: RefCell<Option<Box<HostObject>>>
+}
+
+impl NestedHost {
+    /// Wrap `host` so that it can be returned as a value.
+    pub fn new<H: HostObject>(host: H) -> NestedHost {
+        NestedHost{host: RefCell::new(Some(Box::new(host) as Box<HostObject>))}
+    }
+}
+
+impl DuktapeEncodable for NestedHost {
+    fn duktape_encode(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        match self.host.borrow_mut().take() {
+            Some(host) => unsafe { push_host(s.as_mut_ptr(), host) },
+            None => Err(DuktapeError::from_str(
+                "host object has already been encoded"))
+        }
+    }
+}
+
+impl<C> Context<C> {
+    /// Make `host` available to scripts as the global variable `name`.
+    pub fn register_host_object<H: HostObject>(&mut self, name: &str,
+                                               host: H) ->
+        DuktapeResult<()>
+    {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            try!(push_host(ptr, Box::new(host)));
+            put_global(ptr, name)
+        }
+    }
+}
+
+impl<'f> StackFrame<'f> {
+    /// Push a JavaScript object which forwards property access to `host`.
+    pub fn push_host_object<H: HostObject>(&mut self, host: H) ->
+        DuktapeResult<Slot<'f>>
+    {
+        unsafe { try!(push_host(self.as_mut_ptr(), Box::new(host))); }
+        Ok(self.top().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use errors::*;
+    use encoder::DuktapeEncodable;
+    use super::{HostObject, PropertyValue, NestedHost};
+
+    /// A writable map of numbers, which counts how often it's dropped.
+    pub struct Numbers {
+        pub map: BTreeMap<String, f64>,
+        pub drops: Arc<AtomicUsize>
+    }
+
+    impl Drop for Numbers {
+        fn drop(&mut self) { self.drops.fetch_add(1, Ordering::SeqCst); }
+    }
+
+    impl HostObject for Numbers {
+        fn get(&mut self, key: &str) ->
+            Option<Box<DuktapeEncodable + 'static>>
+        {
+            self.map.get(key).map(|&v| {
+                Box::new(v) as Box<DuktapeEncodable + 'static>
+            })
+        }
+
+        fn set(&mut self, key: &str, value: &PropertyValue) ->
+            DuktapeResult<bool>
+        {
+            self.map.insert(key.to_string(), try!(value.decode()));
+            Ok(true)
+        }
+
+        fn delete(&mut self, key: &str) -> DuktapeResult<bool> {
+            self.map.remove(key);
+            Ok(true)
+        }
+
+        fn keys(&mut self) -> Vec<String> {
+            self.map.keys().map(|k| k.clone()).collect()
+        }
+    }
+
+    /// A read-only object with a single computed property.
+    pub struct Clock;
+
+    impl HostObject for Clock {
+        fn get(&mut self, key: &str) ->
+            Option<Box<DuktapeEncodable + 'static>>
+        {
+            if key == "now" { Some(Box::new(12.0f64)) } else { None }
+        }
+    }
+
+    /// An endless tree of sections, each of which knows its depth.
+    pub struct Section(pub u32);
+
+    impl HostObject for Section {
+        fn get(&mut self, key: &str) ->
+            Option<Box<DuktapeEncodable + 'static>>
+        {
+            match key {
+                "depth" => Some(Box::new(self.0 as f64)),
+                "child" => Some(Box::new(NestedHost::new(Section(self.0 + 1)))),
+                _ => None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_host_objects() {
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let mut ctx = Context::new().unwrap();
+    let drops = Arc::new(AtomicUsize::new(0));
+    let mut map = BTreeMap::new();
+    map.insert("a".to_string(), 1.0f64);
+    ctx.register_host_object("nums", test::Numbers{
+        map: map, drops: drops.clone()
+    }).unwrap();
+
+    assert_eq!(Ok(Value::Number(1.0)), ctx.eval("nums.a"));
+    assert_eq!(Ok(Value::Undefined), ctx.eval("nums.missing"));
+    ctx.eval("nums.b = 2; nums.c = 3; delete nums.a;").unwrap();
+    assert_eq!(Ok(Value::Bool(false)), ctx.eval("'a' in nums"));
+    assert_eq!(Ok(Value::Bool(true)), ctx.eval("'b' in nums"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("b,c"))),
+               ctx.eval("Object.keys(nums).join(',')"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("bc"))),
+               ctx.eval("var ks = ''; for (var k in nums) ks += k; ks"));
+    assert!(ctx.eval("nums.d = 'not a number'").is_err());
+
+    // Read-only objects refuse changes.
+    ctx.with_frame(|frame| {
+        frame.push_host_object(test::Clock).unwrap();
+    });
+    ctx.register_host_object("clock", test::Clock).unwrap();
+    assert_eq!(Ok(Value::Number(12.0)), ctx.eval("clock.now"));
+    assert!(ctx.eval("'use strict'; clock.now = 1;").is_err());
+    assert_eq!(Ok(Value::Number(12.0)), ctx.eval("clock.now"));
+
+    // Host objects are dropped when they're collected.
+    assert_eq!(0, drops.load(Ordering::SeqCst));
+    assert!(ctx.delete_global("nums"));
+    ctx.gc();
+    assert_eq!(1, drops.load(Ordering::SeqCst));
+
+    // Host objects can return other host objects.
+    ctx.register_host_object("config", test::Section(0)).unwrap();
+    assert_eq!(Ok(Value::Number(2.0)), ctx.eval("config.child.child.depth"));
+    assert_eq!(Ok(Value::Number(0.0)), ctx.eval("config.depth"));
+}
+
+#[test]
+fn test_host_object_reentry() {
+    let mut ctx = Context::new().unwrap();
+    ctx.with_frame(|frame| {
+        unsafe {
+            // Our traps find the proxy target in argument 0.
+            let ptr = frame.as_mut_ptr();
+            let cell = Box::new(HostCell{host: Box::new(test::Clock),
+                                         busy: false});
+            let p: *mut ::libc::c_void = transmute(cell);
+            duk_push_object(ptr);
+            duk_push_pointer(ptr, p);
+            duk_put_prop_string(ptr, -2, HOST_PTR_PROP.as_ptr());
+
+            let mut outer = Context::from_borrowed_mut_ptr(ptr);
+            let mut inner = Context::from_borrowed_mut_ptr(ptr);
+            let result = with_host(&mut outer, |_| {
+                with_host(&mut inner, |_| Ok(()))
+            });
+            assert_eq!(Err(DuktapeError::from_code_and_str(
+                ErrorCode::Type, "host object is already in use")), result);
+            // The flag is cleared once the outer call returns.
+            assert_eq!(Ok(()), with_host(&mut outer, |_| Ok(())));
+            let _cell: Box<HostCell> = transmute(p);
+        }
+    });
+}
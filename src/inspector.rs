@@ -0,0 +1,625 @@
+//! An in-process debugger, which lets Rust code set breakpoints, step
+//! through scripts and inspect paused code without a network connection.
+//! This uses the same engine hooks as `Context::attach_debugger`, so it
+//! requires the `debugger` feature, and only one of the two may be
+//! attached at a time.
+//!
+//! ```
+//! use duktape::{Context, StepAction, Value};
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.enable_inspector(move |paused| {
+//!     println!("paused in {} at line {}", paused.stack[0].function,
+//!              paused.stack[0].line);
+//!     StepAction::Resume
+//! });
+//! ctx.add_breakpoint("rules.js", 2).unwrap();
+//! assert_eq!(Ok(Value::Number(2.0)),
+//!            ctx.eval_from("rules.js", "var x = 1;\nx += 1;\nx"));
+//! ```
+//!
+//! Duktape 1.2 can only report the local variables of the innermost
+//! paused function, so `PausedState::locals` describes that function.
+
+use std::borrow::Cow;
+use std::mem::transmute;
+use std::old_io::{IoResult, IoError, IoErrorKind};
+use std::sync::{Arc, Mutex};
+use cesu8::from_cesu8;
+use ffi::*;
+use errors::*;
+use types::Value;
+use context::Context;
+use debugger::DebugTransport;
+
+// Markers which begin and end messages.
+const EOM: u8 = 0x00;
+const REQ: u8 = 0x01;
+const REP: u8 = 0x02;
+const ERR: u8 = 0x03;
+const NFY: u8 = 0x04;
+
+// The commands we send, and the notification we care about.
+const CMD_STATUS: i32 = 0x01;
+const CMD_PAUSE: i32 = 0x12;
+const CMD_RESUME: i32 = 0x13;
+const CMD_STEP_INTO: i32 = 0x14;
+const CMD_STEP_OVER: i32 = 0x15;
+const CMD_STEP_OUT: i32 = 0x16;
+const CMD_ADD_BREAK: i32 = 0x18;
+const CMD_DEL_BREAK: i32 = 0x19;
+const CMD_GET_CALL_STACK: i32 = 0x1c;
+const CMD_GET_LOCALS: i32 = 0x1d;
+
+/// What a paused script should do next.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub enum StepAction {
+    /// Keep running until the next breakpoint.
+    Resume,
+    /// Run until the next line, entering any function which is called.
+    StepInto,
+    /// Run until the next line of the current function.
+    StepOver,
+    /// Run until the current function returns.
+    StepOut
+}
+
+/// A function call on the stack of a paused script.
+#[derive(Clone, Show, PartialEq)]
+pub struct CallFrame {
+    /// The file name passed to `eval_from`.
+    pub file: String,
+    /// The name of the function, or an empty string.
+    pub function: String,
+    /// The line currently being executed.
+    pub line: u32,
+    /// The bytecode offset currently being executed.
+    pub pc: u32
+}
+
+/// Information about a paused script.
+#[derive(Clone, Show, PartialEq)]
+pub struct PausedState {
+    /// The call stack, innermost call first.
+    pub stack: Vec<CallFrame>,
+    /// The local variables of the innermost call.  Objects are described
+    /// by strings such as `"[object Array]"`.
+    pub locals: Vec<(String, Value<'static>)>
+}
+
+/// A value in duktape's debugger protocol.
+#[derive(Clone, Show, PartialEq)]
+enum DValue {
+    Marker(u8),
+    Int(i32),
+    Str(String),
+    Undefined,
+    Null,
+    Bool(bool),
+    Number(f64),
+    Object(u8),
+    Other
+}
+
+impl DValue {
+    fn to_int(&self) -> i32 {
+        match self { &DValue::Int(i) => i, _ => 0 }
+    }
+
+    fn to_string(&self) -> String {
+        match self { &DValue::Str(ref s) => s.clone(), _ => String::new() }
+    }
+
+    fn to_value(&self) -> Value<'static> {
+        match self {
+            &DValue::Int(i) => Value::Number(i as f64),
+            &DValue::Str(ref s) => Value::String(Cow::Owned(s.clone())),
+            &DValue::Null => Value::Null,
+            &DValue::Bool(b) => Value::Bool(b),
+            &DValue::Number(n) => Value::Number(n),
+            &DValue::Object(class) => {
+                let name = match class {
+                    1 => "Arguments", 2 => "Array", 4 => "Date", 5 => "Error",
+                    6 => "Function", 11 => "RegExp", _ => "Object"
+                };
+                Value::String(Cow::Owned(format!("[object {}]", name)))
+            }
+            _ => Value::Undefined
+        }
+    }
+}
+
+fn encode_int(out: &mut Vec<u8>, i: i32) {
+    if i >= 0 && i < 0x40 {
+        out.push(0x80 + i as u8);
+    } else if i >= 0 && i < 0x4000 {
+        out.push(0xc0 + (i >> 8) as u8);
+        out.push(i as u8);
+    } else {
+        out.push(0x10);
+        for shift in [24, 16, 8, 0].iter() {
+            out.push((i >> *shift) as u8);
+        }
+    }
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &str) {
+    let bytes = s.as_bytes();
+    if bytes.len() < 0x20 {
+        out.push(0x60 + bytes.len() as u8);
+    } else {
+        out.push(0x11);
+        let len = bytes.len() as u32;
+        for shift in [24, 16, 8, 0].iter() {
+            out.push((len >> *shift) as u8);
+        }
+    }
+    out.push_all(bytes);
+}
+
+/// Read a big-endian unsigned integer of `len` bytes at `pos`.
+fn read_be(buf: &[u8], pos: usize, len: usize) -> Option<u64> {
+    if buf.len() < pos + len { return None; }
+    Some(buf[pos..pos+len].iter().fold(0u64, |acc, &b| acc << 8 | b as u64))
+}
+
+/// Decode the value at the start of `buf`, returning it along with its
+/// length, or `None` if `buf` doesn't contain all of it yet.
+fn decode_dvalue(buf: &[u8]) -> Option<(DValue, usize)> {
+    if buf.is_empty() { return None; }
+    let ib = buf[0];
+    let bytes = |start: usize, len: usize| -> Option<(DValue, usize)> {
+        if buf.len() < start + len { return None; }
+        let data = &buf[start..start+len];
+        let s = match from_cesu8(data) {
+            Ok(s) => s.into_owned(),
+            Err(_) => String::from_utf8_lossy(data).into_owned()
+        };
+        Some((DValue::Str(s), start + len))
+    };
+    match ib {
+        0x00...0x04 => Some((DValue::Marker(ib), 1)),
+        0x10 => read_be(buf, 1, 4).map(|v| {
+            (DValue::Int(v as u32 as i32), 5)
+        }),
+        0x11 | 0x13 => read_be(buf, 1, 4)
+            .and_then(|len| bytes(5, len as usize)),
+        0x12 | 0x14 => read_be(buf, 1, 2)
+            .and_then(|len| bytes(3, len as usize)),
+        0x15 => Some((DValue::Other, 1)),
+        0x16 => Some((DValue::Undefined, 1)),
+        0x17 => Some((DValue::Null, 1)),
+        0x18 => Some((DValue::Bool(true), 1)),
+        0x19 => Some((DValue::Bool(false), 1)),
+        0x1a => read_be(buf, 1, 8).map(|v| {
+            (DValue::Number(unsafe { transmute::<u64, f64>(v) }), 9)
+        }),
+        0x1b => read_be(buf, 2, 1).and_then(|len| {
+            let end = 3 + len as usize;
+            if buf.len() < end { None } else {
+                Some((DValue::Object(buf[1]), end))
+            }
+        }),
+        0x1c | 0x1e => read_be(buf, 1, 1).and_then(|len| {
+            let end = 2 + len as usize;
+            if buf.len() < end { None } else { Some((DValue::Other, end)) }
+        }),
+        0x1d => read_be(buf, 3, 1).and_then(|len| {
+            let end = 4 + len as usize;
+            if buf.len() < end { None } else { Some((DValue::Other, end)) }
+        }),
+        0x60...0x7f => bytes(1, (ib - 0x60) as usize),
+        0x80...0xbf => Some((DValue::Int((ib - 0x80) as i32), 1)),
+        0xc0...0xff => read_be(buf, 1, 1).map(|lo| {
+            (DValue::Int(((ib - 0xc0) as i32) << 8 | lo as i32), 2)
+        }),
+        _ => Some((DValue::Other, 1))
+    }
+}
+
+/// What we're expecting in the reply to a request.
+#[derive(Clone, Copy, PartialEq)]
+enum Expect {
+    Ignore,
+    CallStack,
+    Locals,
+    Result
+}
+
+/// The state shared between our transport, which duktape owns, and the
+/// `Context` methods which control it.
+struct Session {
+    on_pause: Box<FnMut(&PausedState) -> StepAction + Send>,
+    /// Polled while scripts are running, to decide whether to pause.
+    pause_when: Option<Box<FnMut() -> bool + Send>>,
+    /// Should we fetch local variables each time we pause?
+    want_locals: bool,
+    /// Bytes waiting to be read by duktape.
+    outgoing: Vec<u8>,
+    /// Bytes written by duktape which we haven't parsed yet.
+    incoming: Vec<u8>,
+    /// Have we seen the version line which begins the protocol?
+    handshake_done: bool,
+    /// The requests we've sent, in order, and the replies we expect.
+    expected: Vec<Expect>,
+    paused: bool,
+    /// Have we asked duktape to pause, without hearing that it has?
+    pause_requested: bool,
+    /// Information we've requested about the current pause.
+    snapshot: Option<PausedState>,
+    /// The reply to the last request made by a `Context` method.
+    result: Option<DuktapeResult<Vec<DValue>>>,
+    /// Our breakpoints, in the same order as duktape's list.
+    breakpoints: Vec<(String, u32)>
+}
+
+impl Session {
+    fn request(&mut self, expect: Expect, cmd: i32, args: &[DValue]) {
+        self.outgoing.push(REQ);
+        encode_int(&mut self.outgoing, cmd);
+        for arg in args.iter() {
+            match arg {
+                &DValue::Int(i) => encode_int(&mut self.outgoing, i),
+                &DValue::Str(ref s) => encode_str(&mut self.outgoing, &s[]),
+                _ => panic!("can't encode debugger argument {:?}", arg)
+            }
+        }
+        self.outgoing.push(EOM);
+        self.expected.push(expect);
+    }
+
+    /// Parse as many complete messages as we can from `incoming`.
+    fn parse_incoming(&mut self) {
+        if !self.handshake_done {
+            match self.incoming.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.incoming = self.incoming[end+1..].to_vec();
+                    self.handshake_done = true;
+                }
+                None => return
+            }
+        }
+        loop {
+            let mut pos = 0;
+            let mut values = vec!();
+            loop {
+                match decode_dvalue(&self.incoming[pos..]) {
+                    None => return,
+                    Some((DValue::Marker(EOM), len)) => {
+                        pos += len;
+                        break;
+                    }
+                    Some((value, len)) => {
+                        pos += len;
+                        values.push(value);
+                    }
+                }
+            }
+            self.incoming = self.incoming[pos..].to_vec();
+            self.handle_message(values);
+        }
+    }
+
+    fn handle_message(&mut self, mut values: Vec<DValue>) {
+        if values.is_empty() { return; }
+        match values.remove(0) {
+            DValue::Marker(NFY) => {
+                if values.len() >= 2 && values[0].to_int() == CMD_STATUS {
+                    self.paused = values[1].to_int() == 1;
+                    if self.paused { self.pause_requested = false; }
+                }
+            }
+            DValue::Marker(REP) => {
+                if self.expected.is_empty() { return; }
+                match self.expected.remove(0) {
+                    Expect::Ignore => {}
+                    Expect::CallStack => {
+                        let stack = values.chunks(4).filter(|c| c.len() == 4)
+                            .map(|c| CallFrame{
+                                file: c[0].to_string(),
+                                function: c[1].to_string(),
+                                line: c[2].to_int() as u32,
+                                pc: c[3].to_int() as u32
+                            }).collect();
+                        self.snapshot_mut().stack = stack;
+                    }
+                    Expect::Locals => {
+                        let locals = values.chunks(2).filter(|c| c.len() == 2)
+                            .map(|c| (c[0].to_string(), c[1].to_value()))
+                            .collect();
+                        self.snapshot_mut().locals = locals;
+                    }
+                    Expect::Result => { self.result = Some(Ok(values)); }
+                }
+            }
+            DValue::Marker(ERR) => {
+                if self.expected.is_empty() { return; }
+                if self.expected.remove(0) == Expect::Result {
+                    let msg = values.get(1).map(|v| v.to_string())
+                        .unwrap_or_else(|| "debugger error".to_string());
+                    self.result = Some(Err(DuktapeError::from_str(&msg[])));
+                }
+            }
+            // We never receive requests, and we ignore printing and
+            // logging notifications.
+            _ => {}
+        }
+    }
+
+    fn snapshot_mut(&mut self) -> &mut PausedState {
+        if self.snapshot.is_none() {
+            self.snapshot = Some(PausedState{stack: vec!(), locals: vec!()});
+        }
+        self.snapshot.as_mut().unwrap()
+    }
+
+    /// Decide what to send while duktape is paused and waiting for us.
+    fn on_paused(&mut self) {
+        if self.snapshot.is_none() {
+            // Find out where we are before asking what to do.
+            self.snapshot_mut();
+            self.request(Expect::CallStack, CMD_GET_CALL_STACK, &[]);
+            if self.want_locals {
+                self.request(Expect::Locals, CMD_GET_LOCALS, &[]);
+            }
+        } else {
+            let snapshot = self.snapshot.take().unwrap();
+            let cmd = match (self.on_pause)(&snapshot) {
+                StepAction::Resume => CMD_RESUME,
+                StepAction::StepInto => CMD_STEP_INTO,
+                StepAction::StepOver => CMD_STEP_OVER,
+                StepAction::StepOut => CMD_STEP_OUT
+            };
+            self.paused = false;
+            self.request(Expect::Ignore, cmd, &[]);
+        }
+    }
+
+    /// Ask duktape to pause, unless we already have.
+    fn request_pause(&mut self) {
+        if !self.paused && !self.pause_requested {
+            self.pause_requested = true;
+            self.request(Expect::Ignore, CMD_PAUSE, &[]);
+        }
+    }
+}
+
+/// A handle to our session, stored as heap user data.
+struct SessionHandle(Arc<Mutex<Session>>);
+
+/// The transport which connects duktape to our session.
+struct LocalTransport(Arc<Mutex<Session>>);
+
+impl DebugTransport for LocalTransport {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut session = self.0.lock().unwrap();
+        if session.outgoing.is_empty() && session.paused {
+            session.on_paused();
+        }
+        if session.outgoing.is_empty() {
+            // Duktape would wait forever, so detach instead.
+            return Err(IoError{kind: IoErrorKind::EndOfFile,
+                               desc: "inspector has nothing to send",
+                               detail: None});
+        }
+        let count = if buf.len() < session.outgoing.len() {
+            buf.len()
+        } else {
+            session.outgoing.len()
+        };
+        for (dst, src) in buf.iter_mut().zip(session.outgoing.iter()) {
+            *dst = *src;
+        }
+        session.outgoing = session.outgoing[count..].to_vec();
+        Ok(count)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut session = self.0.lock().unwrap();
+        session.incoming.push_all(buf);
+        session.parse_incoming();
+        Ok(buf.len())
+    }
+
+    fn peek(&mut self) -> IoResult<usize> {
+        let mut session = self.0.lock().unwrap();
+        let pause = session.outgoing.is_empty() &&
+            session.pause_when.as_mut().map_or(false, |f| f());
+        if pause { session.request_pause(); }
+        Ok(session.outgoing.len())
+    }
+}
+
+/// Attach an in-process debugger which calls `on_pause` whenever a script
+/// pauses.  While scripts are running, duktape periodically checks for
+/// commands, and we pause if `pause_when` returns true.
+pub fn attach_session(ctx: &mut Context,
+                      on_pause: Box<FnMut(&PausedState) -> StepAction + Send>,
+                      pause_when: Option<Box<FnMut() -> bool + Send>>,
+                      want_locals: bool) {
+    let session = Arc::new(Mutex::new(Session{
+        on_pause: on_pause,
+        pause_when: pause_when,
+        want_locals: want_locals,
+        outgoing: vec!(),
+        incoming: vec!(),
+        handshake_done: false,
+        expected: vec!(),
+        paused: false,
+        pause_requested: false,
+        snapshot: None,
+        result: None,
+        breakpoints: vec!()
+    }));
+    ctx.attach_debugger(LocalTransport(session.clone()));
+    ctx.set_user_data(SessionHandle(session));
+}
+
+/// Pause as soon as duktape begins running more code.  If the heap is
+/// `idle`, we send the request right away.  Otherwise, duktape is running
+/// a script, and will see the request the next time it checks.
+pub fn pause_soon<H>(ctx: &mut Context<H>, idle: bool) {
+    let session = match ctx.user_data::<SessionHandle>() {
+        Some(&mut SessionHandle(ref session)) => session.clone(),
+        None => return
+    };
+    session.lock().unwrap().request_pause();
+    if idle { unsafe { duk_debugger_cooperate(ctx.as_mut_ptr()); } }
+}
+
+// These are only implemented for owned contexts.  A callback gets a
+// `Context<Borrowed>` while a script is running, and duktape can't process
+// inspector requests until that script returns.
+impl Context {
+    /// Attach an in-process debugger, and call `on_pause` whenever a
+    /// script stops at a breakpoint, a `debugger` statement, or the end
+    /// of a step.  This replaces any attached debugger.
+    pub fn enable_inspector<F>(&mut self, on_pause: F)
+        where F: FnMut(&PausedState) -> StepAction + Send + 'static
+    {
+        attach_session(self, Box::new(on_pause), None, true);
+    }
+
+    /// Detach the in-process debugger, removing all breakpoints.  Like
+    /// `enable_inspector`, this affects any attached debugger, so it also
+    /// detaches one attached using `attach_debugger`.
+    pub fn disable_inspector(&mut self) {
+        self.remove_user_data::<SessionHandle>();
+        self.detach_debugger();
+    }
+
+    /// Send a request to duktape, and wait for the reply.
+    fn inspector_request(&mut self, cmd: i32, args: &[DValue]) ->
+        DuktapeResult<Vec<DValue>>
+    {
+        let session = match self.user_data::<SessionHandle>() {
+            Some(&mut SessionHandle(ref session)) => session.clone(),
+            None => return Err(DuktapeError::from_str(
+                "the inspector is not enabled"))
+        };
+        session.lock().unwrap().request(Expect::Result, cmd, args);
+        unsafe { duk_debugger_cooperate(self.as_mut_ptr()); }
+        let mut session = session.lock().unwrap();
+        session.result.take().unwrap_or_else(|| {
+            Err(DuktapeError::from_str("the debugger did not reply"))
+        })
+    }
+
+    /// Pause whenever execution reaches `line` of `file`.
+    pub fn add_breakpoint(&mut self, file: &str, line: u32) ->
+        DuktapeResult<()>
+    {
+        try!(self.inspector_request(CMD_ADD_BREAK, &[
+            DValue::Str(file.to_string()), DValue::Int(line as i32)
+        ]));
+        if let Some(&mut SessionHandle(ref session)) =
+            self.user_data::<SessionHandle>()
+        {
+            session.lock().unwrap().breakpoints.push((file.to_string(), line));
+        }
+        Ok(())
+    }
+
+    /// Remove a breakpoint, returning false if there was no breakpoint
+    /// at `line` of `file`.
+    pub fn remove_breakpoint(&mut self, file: &str, line: u32) ->
+        DuktapeResult<bool>
+    {
+        let index = self.breakpoints().iter().position(|&(ref f, l)| {
+            &f[] == file && l == line
+        });
+        let index = match index { Some(i) => i, None => return Ok(false) };
+        try!(self.inspector_request(CMD_DEL_BREAK,
+                                    &[DValue::Int(index as i32)]));
+        if let Some(&mut SessionHandle(ref session)) =
+            self.user_data::<SessionHandle>()
+        {
+            session.lock().unwrap().breakpoints.remove(index);
+        }
+        Ok(true)
+    }
+
+    /// List the current breakpoints as `(file, line)` pairs.
+    pub fn breakpoints(&mut self) -> Vec<(String, u32)> {
+        match self.user_data::<SessionHandle>() {
+            Some(&mut SessionHandle(ref session)) =>
+                session.lock().unwrap().breakpoints.clone(),
+            None => vec!()
+        }
+    }
+}
+
+#[test]
+fn test_dvalues() {
+    let long: String = range(0, 40).map(|_| 'x').collect();
+    let mut buf = vec!();
+    for &i in [0, 63, 64, 16383, 16384, -1].iter() { encode_int(&mut buf, i); }
+    encode_str(&mut buf, "short");
+    encode_str(&mut buf, &long[]);
+    let mut pos = 0;
+    let mut values = vec!();
+    while let Some((value, len)) = decode_dvalue(&buf[pos..]) {
+        values.push(value);
+        pos += len;
+    }
+    assert_eq!(vec!(DValue::Int(0), DValue::Int(63), DValue::Int(64),
+                    DValue::Int(16383), DValue::Int(16384), DValue::Int(-1),
+                    DValue::Str("short".to_string()),
+                    DValue::Str(long.clone())),
+               values);
+    // Incomplete values need more data.
+    assert_eq!(None, decode_dvalue(&[0x12, 0x00]));
+    assert_eq!(None, decode_dvalue(&[0x12, 0x00, 0x02, b'a']));
+}
+
+#[test]
+fn test_inspector() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use debugger::test::Recorder;
+
+    let mut ctx = Context::new().unwrap();
+    let (sender, receiver) = channel();
+    let mut steps = 0;
+    ctx.enable_inspector(move |paused| {
+        sender.send(paused.clone()).unwrap();
+        steps += 1;
+        if steps == 1 { StepAction::StepOver } else { StepAction::Resume }
+    });
+    assert!(ctx.add_breakpoint("test.js", 3).is_ok());
+    assert!(ctx.add_breakpoint("test.js", 9).is_ok());
+    assert_eq!(Ok(true), ctx.remove_breakpoint("test.js", 9));
+    assert_eq!(Ok(false), ctx.remove_breakpoint("test.js", 9));
+    assert_eq!(vec!(("test.js".to_string(), 3)), ctx.breakpoints());
+
+    ctx.eval_from("test.js", "function f(a) {\n\
+                               var b = a * 2;\n\
+                               b += 1;\n\
+                               return b;\n\
+                             }\n\
+                             f(20);").unwrap();
+
+    // We stop at our breakpoint, and then after stepping over a line.
+    let first = receiver.recv().unwrap();
+    assert_eq!("f", &first.stack[0].function[]);
+    assert_eq!(3, first.stack[0].line);
+    assert_eq!("test.js", &first.stack[0].file[]);
+    assert!(first.stack.len() >= 2);
+    assert!(first.locals.contains(&("b".to_string(), Value::Number(40.0))));
+    let second = receiver.recv().unwrap();
+    assert_eq!(4, second.stack[0].line);
+    assert!(second.locals.contains(&("b".to_string(), Value::Number(41.0))));
+    assert!(receiver.try_recv().is_err());
+
+    ctx.disable_inspector();
+    assert!(ctx.add_breakpoint("test.js", 1).is_err());
+    assert!(ctx.breakpoints().is_empty());
+
+    // Disabling the inspector also detaches other debuggers.
+    let written = Arc::new(AtomicUsize::new(0));
+    let detached = Arc::new(AtomicUsize::new(0));
+    ctx.attach_debugger(Recorder{written: written.clone(),
+                                 detached: detached.clone()});
+    ctx.disable_inspector();
+    assert_eq!(1, detached.load(Ordering::SeqCst));
+}
@@ -0,0 +1,338 @@
+//! `Int64` and `UInt64` objects, which allow JavaScript code to work with
+//! 64-bit integers without losing precision.  The value is stored as a
+//! decimal string in a hidden property, and the arithmetic methods are
+//! implemented in Rust.
+//!
+//! ```js
+//! var id = Int64("9007199254740993");
+//! id.add(1).toString();     // "9007199254740994"
+//! id.compare(Int64(0));     // 1
+//! ```
+
+use std::ffi::CString;
+use rustc_serialize::{Decodable, Decoder};
+use ffi::*;
+use errors::*;
+use context::{Context, Borrowed, Callback};
+use encoder::{Encoder, DuktapeEncodable};
+use decoder;
+use options::{DecoderOptions, Int64Layout};
+use state;
+
+/// The hidden property holding the decimal value of an `Int64` or
+/// `UInt64` object.
+pub const INT64_VALUE_PROP: [i8; 5] =
+    [-1, 'i' as i8, '6' as i8, '4' as i8, 0];
+
+/// A signed 64-bit integer, passed to JavaScript as an `Int64` object.
+#[derive(Copy, Clone, Show, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Int64(pub i64);
+
+/// An unsigned 64-bit integer, passed to JavaScript as a `UInt64` object.
+#[derive(Copy, Clone, Show, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UInt64(pub u64);
+
+impl DuktapeEncodable for Int64 {
+    fn duktape_encode(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        let &Int64(v) = self;
+        unsafe { push(s.as_mut_ptr(), true, &v.to_string()[]) }
+    }
+}
+
+impl DuktapeEncodable for UInt64 {
+    fn duktape_encode(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        let &UInt64(v) = self;
+        unsafe { push(s.as_mut_ptr(), false, &v.to_string()[]) }
+    }
+}
+
+impl Decodable for Int64 {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Int64, D::Error> {
+        d.read_i64().map(Int64)
+    }
+}
+
+impl Decodable for UInt64 {
+    fn decode<D: Decoder>(d: &mut D) -> Result<UInt64, D::Error> {
+        d.read_u64().map(UInt64)
+    }
+}
+
+/// The names of the hidden global stash properties holding our
+/// prototypes, as NUL-terminated byte strings.
+fn proto_name(signed: bool) -> *const i8 {
+    let name: &'static [u8] =
+        if signed { b"\xffInt64\0" } else { b"\xffUInt64\0" };
+    name.as_ptr() as *const i8
+}
+
+/// Install the `Int64` and `UInt64` constructors and prototypes, if we
+/// haven't already done so.
+pub unsafe fn install(ctx: *mut duk_context) -> DuktapeResult<()> {
+    if duk_check_stack(ctx, 4) == 0 {
+        return Err(DuktapeError::from_code_and_str(
+            ErrorCode::Alloc, "out of space on the value stack"));
+    }
+    duk_push_global_stash(ctx);
+    let installed = duk_has_prop_string(ctx, -1, proto_name(true)) != 0;
+    if !installed {
+        let mut context = Context::from_borrowed_mut_ptr(ctx);
+        install_kind(&mut context, true);
+        install_kind(&mut context, false);
+    }
+    duk_pop(ctx);
+    Ok(())
+}
+
+/// Create the prototype and constructor for one kind of integer.  Expects
+/// the global stash on top of the stack.
+unsafe fn install_kind(ctx: &mut Context<Borrowed>, signed: bool) {
+    let ptr = ctx.as_mut_ptr();
+    let methods: &[(&str, Callback, Option<u16>)] = if signed {
+        &signed_methods::METHODS
+    } else {
+        &unsigned_methods::METHODS
+    };
+
+    duk_push_object(ptr);
+    for &(name, f, nargs) in methods.iter() {
+        ctx.push_callback(f, nargs);
+        let c_name = CString::from_slice(name.as_bytes());
+        duk_put_prop_string(ptr, -2, c_name.as_ptr());
+    }
+    duk_put_prop_string(ptr, -2, proto_name(signed));
+
+    // Make the constructor available to scripts.
+    duk_push_global_object(ptr);
+    if signed {
+        ctx.push_callback(signed_methods::construct, Some(1));
+        duk_put_prop_string(ptr, -2, b"Int64\0".as_ptr() as *const i8);
+    } else {
+        ctx.push_callback(unsigned_methods::construct, Some(1));
+        duk_put_prop_string(ptr, -2, b"UInt64\0".as_ptr() as *const i8);
+    }
+    duk_pop(ptr);
+}
+
+/// Push a new `Int64` (if `signed`) or `UInt64` object with the specified
+/// decimal value.
+pub unsafe fn push(ctx: *mut duk_context, signed: bool, decimal: &str) ->
+    DuktapeResult<()>
+{
+    if duk_check_stack(ctx, 4) == 0 {
+        return Err(DuktapeError::from_code_and_str(
+            ErrorCode::Alloc, "out of space on the value stack"));
+    }
+    try!(install(ctx));
+
+    // Stack: stash proto obj
+    duk_push_global_stash(ctx);
+    duk_get_prop_string(ctx, -1, proto_name(signed));
+    duk_push_object(ctx);
+    duk_push_lstring(ctx, decimal.as_ptr() as *const i8,
+                     decimal.len() as duk_size_t);
+    duk_put_prop_string(ctx, -2, INT64_VALUE_PROP.as_ptr());
+    duk_swap_top(ctx, -2);
+    duk_set_prototype(ctx, -2);
+    duk_remove(ctx, -2);
+    Ok(())
+}
+
+/// If the value at `idx` is an `Int64` or `UInt64` object, push its
+/// decimal value and return true.  Otherwise push nothing and return false.
+pub unsafe fn push_decimal(ctx: *mut duk_context, idx: duk_idx_t) -> bool {
+    if duk_is_object(ctx, idx) == 0 { return false; }
+    duk_get_prop_string(ctx, idx, INT64_VALUE_PROP.as_ptr());
+    if duk_is_string(ctx, -1) != 0 {
+        true
+    } else {
+        duk_pop(ctx);
+        false
+    }
+}
+
+/// Decode the `this` binding (if `idx` is `None`) or argument `idx` of
+/// the current function call.  Decimal strings are accepted whatever
+/// the configured `Int64Layout`, so that scripts can write
+/// `Int64("9007199254740993")`.
+unsafe fn decode_arg<T: Decodable>(ctx: &mut Context<Borrowed>,
+                                   idx: Option<duk_idx_t>) ->
+    DuktapeResult<T>
+{
+    let ptr = ctx.as_mut_ptr();
+    let top = duk_get_top(ptr);
+    let label = match idx {
+        Some(i) if i >= top => {
+            let msg = format!("argument {}: missing", i);
+            return Err(DuktapeError::from_code_and_str(ErrorCode::Type,
+                                                       &msg[]));
+        }
+        Some(i) => { duk_dup(ptr, i); format!("argument {}", i) }
+        None => { duk_push_this(ptr); "this".to_string() }
+    };
+    let options = DecoderOptions{int64: Int64Layout::Object,
+                                 .. state::decoder_options(ptr)};
+    let result = {
+        let mut d = decoder::Decoder::with_options(ptr, options);
+        Decodable::decode(&mut d)
+    };
+    duk_set_top(ptr, top);
+    result.map_err(|err| {
+        let msg = format!("{}: {}", label, err);
+        DuktapeError::from_code_and_str(err_code(&err), &msg[])
+    })
+}
+
+/// Implements the methods for one kind of integer.
+macro_rules! int64_methods {
+    ($module:ident, $wrapper:ident, $ty:ident, $js_name:expr) => {
+        mod $module {
+            use std::cmp::Ordering;
+            use std::num::Int;
+            use errors::*;
+            use args::Args;
+            use context::{Context, Borrowed, Callback};
+            use encoder::DuktapeEncodable;
+            use super::{$wrapper, decode_arg};
+
+            type Ctx = Context<Borrowed>;
+            type CallbackResult = DuktapeResult<Box<DuktapeEncodable + 'static>>;
+
+            fn this(ctx: &mut Ctx) -> DuktapeResult<$ty> {
+                unsafe { decode_arg::<$ty>(ctx, None) }
+            }
+
+            fn other(ctx: &mut Ctx) -> DuktapeResult<$ty> {
+                unsafe { decode_arg::<$ty>(ctx, Some(0)) }
+            }
+
+            fn wrap(op: &str, result: Option<$ty>) -> CallbackResult {
+                match result {
+                    Some(v) => Ok(Box::new($wrapper(v))),
+                    None => {
+                        let msg = format!("{} overflow in {}", $js_name, op);
+                        Err(DuktapeError::from_code_and_str(ErrorCode::Range,
+                                                            &msg[]))
+                    }
+                }
+            }
+
+            fn nonzero(v: $ty) -> DuktapeResult<$ty> {
+                if v == 0 {
+                    Err(DuktapeError::from_code_and_str(ErrorCode::Range,
+                                                        "division by zero"))
+                } else {
+                    Ok(v)
+                }
+            }
+
+            pub fn construct(ctx: &mut Ctx, _args: &Args) ->
+                CallbackResult
+            {
+                Ok(Box::new($wrapper(try!(other(ctx)))))
+            }
+
+            fn add(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                wrap("add", try!(this(ctx)).checked_add(try!(other(ctx))))
+            }
+
+            fn sub(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                wrap("sub", try!(this(ctx)).checked_sub(try!(other(ctx))))
+            }
+
+            fn mul(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                wrap("mul", try!(this(ctx)).checked_mul(try!(other(ctx))))
+            }
+
+            fn div(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                let divisor = try!(nonzero(try!(other(ctx))));
+                wrap("div", try!(this(ctx)).checked_div(divisor))
+            }
+
+            fn rem(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                let divisor = try!(nonzero(try!(other(ctx))));
+                wrap("mod", try!(this(ctx)).checked_rem(divisor))
+            }
+
+            fn compare(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                let result = match try!(this(ctx)).cmp(&try!(other(ctx))) {
+                    Ordering::Less => -1.0f64,
+                    Ordering::Equal => 0.0,
+                    Ordering::Greater => 1.0
+                };
+                Ok(Box::new(result))
+            }
+
+            fn equals(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                Ok(Box::new(try!(this(ctx)) == try!(other(ctx))))
+            }
+
+            fn to_string(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                Ok(Box::new(try!(this(ctx)).to_string()))
+            }
+
+            fn to_number(ctx: &mut Ctx, _args: &Args) -> CallbackResult {
+                Ok(Box::new(try!(this(ctx)) as f64))
+            }
+
+            /// The methods of our prototype.  Note that `valueOf` loses
+            /// precision, but it allows `<` and `>` to work as expected
+            /// for most values.
+            pub const METHODS: [(&'static str, Callback, Option<u16>); 11] = [
+                ("add", add as Callback, Some(1)),
+                ("sub", sub as Callback, Some(1)),
+                ("mul", mul as Callback, Some(1)),
+                ("div", div as Callback, Some(1)),
+                ("mod", rem as Callback, Some(1)),
+                ("compare", compare as Callback, Some(1)),
+                ("equals", equals as Callback, Some(1)),
+                ("toString", to_string as Callback, Some(0)),
+                ("toJSON", to_string as Callback, Some(0)),
+                ("toNumber", to_number as Callback, Some(0)),
+                ("valueOf", to_number as Callback, Some(0))
+            ];
+        }
+    }
+}
+
+int64_methods!(signed_methods, Int64, i64, "Int64");
+int64_methods!(unsigned_methods, UInt64, u64, "UInt64");
+
+#[test]
+fn test_int64_objects() {
+    use std::borrow::Cow;
+    use std::default::Default;
+    use types::Value;
+    use options::EncoderOptions;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("function id(x) { return x; }").unwrap();
+    ctx.eval("function str(x) { return x.toString(); }").unwrap();
+    ctx.set_encoder_options(EncoderOptions{
+        int64: Int64Layout::Object, .. Default::default()
+    }).unwrap();
+
+    // Values survive a round trip through JavaScript.
+    assert_eq!(Ok(Value::String(Cow::Borrowed("9007199254740993"))),
+               ctx.call("str", &[&Int64(9007199254740993)]));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("18446744073709551615"))),
+               ctx.call("str", &[&UInt64(18446744073709551615)]));
+
+    // Arithmetic happens in Rust, without losing precision.
+    assert_eq!(Ok(Value::String(Cow::Borrowed("9007199254740994"))),
+               ctx.eval("Int64('9007199254740993').add(1).toString()"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("-3"))),
+               ctx.eval("Int64(7).sub(Int64(10)).toString()"));
+    assert_eq!(Ok(Value::Number(1.0)),
+               ctx.eval("Int64('9007199254740993')\
+                         .compare('9007199254740992')"));
+    assert_eq!(Ok(Value::Bool(true)),
+               ctx.eval("UInt64(6).div(3).equals(2)"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("\"42\""))),
+               ctx.eval("JSON.stringify(Int64(42))"));
+
+    // Errors are reported as exceptions.
+    assert!(ctx.eval("UInt64(0).sub(1)").is_err());
+    assert!(ctx.eval("Int64(1).div(0)").is_err());
+    assert!(ctx.eval("Int64(1.5)").is_err());
+}
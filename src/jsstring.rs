@@ -0,0 +1,286 @@
+//! JavaScript strings are sequences of UTF-16 code units, and they may
+//! contain unpaired surrogates which can't be represented in a Rust
+//! `String`.  Duktape stores these internally using an extended form of
+//! CESU-8, where each surrogate is encoded separately.
+
+use std::ffi::CString;
+use std::slice::from_raw_buf;
+use std::str::from_utf8_unchecked;
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use ffi::*;
+
+/// The name we use when serializing a `JsString` as a struct.  Other
+/// encoders will just see a struct named `JsString` with a `utf16` field.
+/// Our encoder and decoder recognize `JsString` by the address of this
+/// static rather than by its contents, so no other type can be mistaken
+/// for one just because it has the same name.
+static JS_STRING_STRUCT: [u8; 8] =
+    [b'J', b's', b'S', b't', b'r', b'i', b'n', b'g'];
+
+/// The struct name passed by `JsString`'s `Encodable` and `Decodable`
+/// implementations.
+fn js_string_struct() -> &'static str {
+    unsafe { from_utf8_unchecked(&JS_STRING_STRUCT) }
+}
+
+/// Is `name` the struct name used by `JsString` itself?
+pub fn is_js_string_struct(name: &str) -> bool {
+    name.as_ptr() == JS_STRING_STRUCT.as_ptr() &&
+        name.len() == JS_STRING_STRUCT.len()
+}
+
+/// The field of a serialized `JsString` holding its UTF-16 code units.
+const UTF16_FIELD: &'static str = "utf16";
+
+/// A JavaScript string, which may contain unpaired surrogates.  Use this
+/// instead of `String` when you need to pass strings through Rust code
+/// without changing them.
+///
+/// ```
+/// use duktape::{Context,JsString};
+///
+/// let mut ctx = Context::new().unwrap();
+/// ctx.eval("function broken() { return '\\ud800!'; }").unwrap();
+/// ctx.eval("function id(s) { return s; }").unwrap();
+/// let s: JsString = ctx.call_as("broken", &[]).unwrap();
+/// assert_eq!(&[0xd800, 0x21], s.as_utf16());
+/// assert_eq!("\u{fffd}!", s.to_string_lossy());
+/// ```
+#[derive(Clone, Show, PartialEq, Eq, Hash)]
+pub struct JsString {
+    units: Vec<u16>
+}
+
+impl JsString {
+    /// Create a `JsString` from a sequence of UTF-16 code units.
+    pub fn from_utf16(units: Vec<u16>) -> JsString {
+        JsString{units: units}
+    }
+
+    /// Create a `JsString` from the extended CESU-8 bytes used internally
+    /// by duktape.  Invalid byte sequences are replaced by U+FFFD.
+    pub fn from_cesu8(bytes: &[u8]) -> JsString {
+        JsString{units: cesu8_to_utf16(bytes)}
+    }
+
+    /// Create a `JsString` containing a copy of `s`.
+    pub fn from_str(s: &str) -> JsString {
+        JsString{units: s.utf16_units().collect()}
+    }
+
+    /// The UTF-16 code units making up this string.
+    pub fn as_utf16(&self) -> &[u16] { &self.units[] }
+
+    /// Consume this string, returning its UTF-16 code units.
+    pub fn into_utf16(self) -> Vec<u16> { self.units }
+
+    /// The extended CESU-8 bytes which duktape uses to represent this
+    /// string internally.
+    pub fn to_cesu8(&self) -> Vec<u8> { utf16_to_cesu8(&self.units[]) }
+
+    /// Convert to a Rust string, or return `None` if this string contains
+    /// unpaired surrogates.
+    pub fn to_string_checked(&self) -> Option<String> {
+        String::from_utf16(&self.units[]).ok()
+    }
+
+    /// Convert to a Rust string, replacing unpaired surrogates with
+    /// U+FFFD.
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf16_lossy(&self.units[])
+    }
+}
+
+impl Encodable for JsString {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct(js_string_struct(), 1, |s| {
+            s.emit_struct_field(UTF16_FIELD, 0, |s| self.units.encode(s))
+        })
+    }
+}
+
+impl Decodable for JsString {
+    fn decode<D: Decoder>(d: &mut D) -> Result<JsString, D::Error> {
+        d.read_struct(js_string_struct(), 1, |d| {
+            d.read_struct_field(UTF16_FIELD, 0, Decodable::decode)
+                .map(JsString::from_utf16)
+        })
+    }
+}
+
+/// Convert duktape's extended CESU-8 into UTF-16 code units.  Four-byte
+/// UTF-8 sequences are accepted, too, and split into surrogate pairs.
+pub fn cesu8_to_utf16(bytes: &[u8]) -> Vec<u16> {
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    'outer: while i < bytes.len() {
+        let b0 = bytes[i];
+        let (len, mut cp) =
+            if b0 < 0x80 { (1, b0 as u32) }
+            else if b0 & 0xe0 == 0xc0 { (2, (b0 & 0x1f) as u32) }
+            else if b0 & 0xf0 == 0xe0 { (3, (b0 & 0x0f) as u32) }
+            else if b0 & 0xf8 == 0xf0 { (4, (b0 & 0x07) as u32) }
+            else { units.push(0xfffd); i += 1; continue };
+        if i + len > bytes.len() {
+            units.push(0xfffd);
+            i += 1;
+            continue;
+        }
+        for j in range(1, len) {
+            let b = bytes[i + j];
+            if b & 0xc0 != 0x80 {
+                units.push(0xfffd);
+                i += j;
+                continue 'outer;
+            }
+            cp = (cp << 6) | (b & 0x3f) as u32;
+        }
+        if cp >= 0x10000 {
+            let v = cp - 0x10000;
+            units.push((0xd800 | (v >> 10)) as u16);
+            units.push((0xdc00 | (v & 0x3ff)) as u16);
+        } else {
+            units.push(cp as u16);
+        }
+        i += len;
+    }
+    units
+}
+
+/// Convert UTF-16 code units into duktape's extended CESU-8, encoding
+/// each surrogate separately.
+pub fn utf16_to_cesu8(units: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(units.len());
+    for &u in units.iter() {
+        if u < 0x80 {
+            bytes.push(u as u8);
+        } else if u < 0x800 {
+            bytes.push(0xc0 | (u >> 6) as u8);
+            bytes.push(0x80 | (u & 0x3f) as u8);
+        } else {
+            bytes.push(0xe0 | (u >> 12) as u8);
+            bytes.push(0x80 | ((u >> 6) & 0x3f) as u8);
+            bytes.push(0x80 | (u & 0x3f) as u8);
+        }
+    }
+    bytes
+}
+
+/// Convert a duktape-format string into a Rust `String`, replacing
+/// unpaired surrogates and invalid bytes with U+FFFD.
+pub unsafe fn from_lstring_lossy(data: *const i8, len: duk_size_t) -> String {
+    let ptr = data as *const u8;
+    let bytes = from_raw_buf(&ptr, len as usize);
+    String::from_utf16_lossy(&cesu8_to_utf16(bytes)[])
+}
+
+/// Replace the string on top of the stack with the object our decoder
+/// expects for a `JsString`: `{utf16: [units...]}`.
+pub unsafe fn string_to_struct(ctx: *mut duk_context) {
+    let mut len = 0;
+    let data = duk_get_lstring(ctx, -1, &mut len) as *const u8;
+    let units = cesu8_to_utf16(from_raw_buf(&data, len as usize));
+    duk_push_object(ctx);
+    duk_push_array(ctx);
+    for (i, &u) in units.iter().enumerate() {
+        duk_push_number(ctx, u as f64);
+        duk_put_prop_index(ctx, -2, i as u32);
+    }
+    let field = CString::from_slice(UTF16_FIELD.as_bytes());
+    duk_put_prop_string(ctx, -2, field.as_ptr());
+    duk_replace(ctx, -2);
+}
+
+/// Replace the `{utf16: [units...]}` object on top of the stack with the
+/// corresponding string.
+pub unsafe fn struct_to_string(ctx: *mut duk_context) {
+    let field = CString::from_slice(UTF16_FIELD.as_bytes());
+    duk_get_prop_string(ctx, -1, field.as_ptr());
+    let len = duk_get_length(ctx, -1);
+    let mut units = Vec::with_capacity(len as usize);
+    for i in range(0, len) {
+        duk_get_prop_index(ctx, -1, i as u32);
+        units.push(duk_get_number(ctx, -1) as u16);
+        duk_pop(ctx);
+    }
+    duk_pop(ctx);
+    let bytes = utf16_to_cesu8(&units[]);
+    duk_push_lstring(ctx, bytes.as_ptr() as *const i8,
+                     bytes.len() as duk_size_t);
+    duk_replace(ctx, -2);
+}
+
+#[test]
+fn test_cesu8_conversion() {
+    let units = vec!(0x41, 0xe9, 0x20ac, 0xd800, 0xd83d, 0xde00);
+    let bytes = utf16_to_cesu8(&units[]);
+    assert_eq!(units, cesu8_to_utf16(&bytes[]));
+    // A four-byte UTF-8 sequence becomes a surrogate pair.
+    assert_eq!(vec!(0xd83d, 0xde00), cesu8_to_utf16("\u{1f600}".as_bytes()));
+    assert_eq!(vec!(0xfffd, 0x41), cesu8_to_utf16(&[0xff, 0x41]));
+}
+
+#[test]
+fn test_invalid_utf16() {
+    use std::borrow::Cow;
+    use std::default::Default;
+    use errors::*;
+    use types::Value;
+    use context::Context;
+    use options::{DecoderOptions, StringMode};
+
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("function broken() { return 'a\\udc00b'; }").unwrap();
+    ctx.eval("function id(s) { return s; }").unwrap();
+    ctx.eval("function units(s) { \
+                var r = []; \
+                for (var i = 0; i < s.length; i++) r.push(s.charCodeAt(i)); \
+                return r.join(','); }").unwrap();
+
+    // By default, strings which can't be converted are errors.
+    assert!(ctx.call("broken", &[]).is_err());
+    assert!(ctx.call_as::<String>("broken", &[]).is_err());
+
+    // `JsString` preserves unpaired surrogates across round trips.
+    let s: JsString = ctx.call_as("broken", &[]).unwrap();
+    assert_eq!(&[0x61, 0xdc00, 0x62], s.as_utf16());
+    assert_eq!(None, s.to_string_checked());
+    assert_eq!(Ok(Value::String(Cow::Borrowed("97,56320,98"))),
+               ctx.call("units", &[&s]));
+    let v: Vec<JsString> = ctx.call_as("id", &[&vec!(s.clone())]).unwrap();
+    assert_eq!(vec!(s), v);
+
+    // Other structs named `JsString` aren't mistaken for the real thing.
+    struct Imposter;
+    impl Encodable for Imposter {
+        fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+            s.emit_struct("JsString", 1, |s| {
+                s.emit_struct_field("utf16", 0, |s| vec!(0x61u16).encode(s))
+            })
+        }
+    }
+    ctx.eval("function kind(x) { return typeof x; }").unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("object"))),
+               ctx.call("kind", (&Imposter,)));
+
+    // Lossy mode can be used for a single call.
+    let lossy = DecoderOptions{strings: StringMode::Lossy,
+                               .. Default::default()};
+    let result: DuktapeResult<String> =
+        ctx.call_as_with(&lossy, "broken", ());
+    assert_eq!(Ok("a\u{fffd}b".to_string()), result);
+    assert!(ctx.call_as::<String>("broken", &[]).is_err());
+
+    // Or for every call.
+    ctx.set_decoder_options(DecoderOptions{
+        strings: StringMode::Lossy, .. Default::default()
+    }).unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("a\u{fffd}b"))),
+               ctx.call("broken", &[]));
+    assert_eq!(Ok("a\u{fffd}b".to_string()),
+               ctx.call_as::<String>("broken", &[]));
+
+    // Error messages are always converted lossily.
+    assert_eq!(Err(DuktapeError::from_str("Error: \u{fffd}")),
+               ctx.eval("throw new Error('\\ud800')"));
+}
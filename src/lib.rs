@@ -21,8 +21,24 @@
 //! assert_eq!(Ok(Value::Number(3.0)), add_example());
 //! ```
 //!
-//! We also have preliminary support for defining JavaScript functions
-//! using Rust, but it's still too ugly to show off.
+//! JavaScript functions can be defined in Rust using `js_fn!`, and
+//! JavaScript functions can be called from Rust using `js_call!`:
+//!
+//! ```
+//! #[macro_use] extern crate duktape;
+//! use duktape::{Context,Value};
+//!
+//! js_fn! {
+//!     fn mul(x: f64, y: f64) -> f64 { x * y }
+//! }
+//!
+//! fn main() {
+//!     let mut ctx = Context::new().unwrap();
+//!     ctx.register_fn("mul", mul);
+//!     ctx.eval("function square(x) { return mul(x, x); }").unwrap();
+//!     assert_eq!(Ok(Value::Number(9.0)), js_call!(ctx, "square", 3.0f64));
+//! }
+//! ```
 //!
 //! [Duktape]: http://duktape.org/
 
@@ -30,6 +46,7 @@
 #![feature(collections)]
 #![feature(core)]
 #![feature(libc)]
+#![feature(unsafe_destructor)]
 
 #![warn(missing_docs)]
 
@@ -37,15 +54,61 @@
 extern crate "rustc-serialize" as rustc_serialize;
 extern crate libc;
 extern crate cesu8;
+extern crate time;
 #[macro_use] extern crate abort_on_panic;
 extern crate "duktape_sys" as ffi;
 
 pub use errors::{ErrorCode, DuktapeError, DuktapeResult};
-pub use types::Value;
-pub use context::{Context, Callback};
+pub use types::{Value, Buffer, PropertyAttributes};
+pub use int64::{Int64, UInt64};
+pub use jsstring::JsString;
+pub use encoder::DuktapeEncodable;
+pub use decoder::DuktapeDecodable;
+pub use args::Args;
+pub use stack::{StackFrame, Slot};
+pub use context::{Context, Owned, Borrowed, Callback, NativeFunction};
+pub use arg_list::IntoArgs;
+pub use executor::Executor;
+pub use pool::{ContextPool, PooledContext, PoolOptions, ResetMode};
+pub use event_loop::EventLoop;
+pub use promise::Promise;
+pub use heap::HeapStats;
+pub use stash::Stash;
+pub use module::Module;
+pub use host::{HostObject, PropertyValue, NestedHost};
+#[cfg(feature = "debugger")]
+pub use debugger::{DebugTransport, TcpTransport, DEFAULT_DEBUG_PORT};
+#[cfg(feature = "debugger")]
+pub use inspector::{StepAction, CallFrame, PausedState};
+#[cfg(feature = "debugger")]
+pub use profiler::{Profile, Coverage};
+pub use options::{EncoderOptions, DecoderOptions, EnumLayout, MapLayout,
+                  NoneValue, Int64Layout, StringMode, CompileMode,
+                  EvalOptions};
 
+#[macro_use] #[doc(hidden)] pub mod macros;
 mod errors;
 mod types;
+mod args;
+mod stack;
+mod options;
+mod state;
+mod int64;
+mod jsstring;
 mod encoder;
 mod decoder;
+mod arg_list;
 mod context;
+mod executor;
+mod pool;
+mod event_loop;
+mod promise;
+mod heap;
+mod stash;
+mod module;
+mod host;
+mod source;
+mod loader;
+#[cfg(feature = "debugger")] mod debugger;
+#[cfg(feature = "debugger")] mod inspector;
+#[cfg(feature = "debugger")] mod profiler;
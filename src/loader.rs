@@ -0,0 +1,136 @@
+//! Evaluating scripts stored in files, directories and other readers.
+//! Scripts may begin with a UTF-8 byte order mark or a `#!` line, which
+//! are skipped.  Problems reading a script are reported as errors with an
+//! `io_error`, so they can be told apart from errors in the script.
+//!
+//! ```no_run
+//! use duktape::Context;
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.load_script_dir(&Path::new("rules")).unwrap();
+//! match ctx.eval_file(&Path::new("main.js")) {
+//!     Err(ref err) if err.io_error().is_some() =>
+//!         println!("could not read main.js: {}", err),
+//!     Err(err) => println!("main.js failed: {}", err),
+//!     Ok(value) => println!("main.js returned {:?}", value)
+//! }
+//! ```
+
+use std::old_io::{File, Reader};
+use std::old_io::fs;
+use errors::*;
+use types::Value;
+use context::Context;
+
+/// Remove a leading byte order mark, and blank out a leading `#!` line
+/// without changing the line numbers of the rest of the script.
+fn strip_preamble(source: &str) -> &str {
+    let source = if source.starts_with("\u{feff}") {
+        &source[3..]
+    } else {
+        source
+    };
+    if source.starts_with("#!") {
+        match source.find('\n') {
+            Some(end) => &source[end..],
+            None => ""
+        }
+    } else {
+        source
+    }
+}
+
+impl<H> Context<H> {
+    /// Read a script from `reader` and evaluate it, using `filename` in
+    /// error messages.
+    pub fn eval_reader<R: Reader>(&mut self, reader: &mut R, filename: &str)
+                                  -> DuktapeResult<Value<'static>>
+    {
+        let source = try!(reader.read_to_string()
+                          .map_err(DuktapeError::from_io_error));
+        self.eval_from(filename, strip_preamble(&source[]))
+    }
+
+    /// Read the script at `path` and evaluate it, using the path in error
+    /// messages.
+    pub fn eval_file(&mut self, path: &Path) ->
+        DuktapeResult<Value<'static>>
+    {
+        let mut file = try!(File::open(path)
+                            .map_err(DuktapeError::from_io_error));
+        self.eval_reader(&mut file, &path.display().to_string()[])
+    }
+
+    /// Evaluate every `.js` file in `dir`, in order by name, and return
+    /// their paths.  Subdirectories are ignored.  We stop at the first
+    /// script which fails.
+    pub fn load_script_dir(&mut self, dir: &Path) -> DuktapeResult<Vec<Path>> {
+        let mut paths: Vec<Path> = try!(fs::readdir(dir)
+                                        .map_err(DuktapeError::from_io_error))
+            .into_iter()
+            .filter(|p| p.extension_str() == Some("js") && p.is_file())
+            .collect();
+        paths.sort();
+        for path in paths.iter() {
+            try!(self.eval_file(path));
+        }
+        Ok(paths)
+    }
+}
+
+#[test]
+fn test_strip_preamble() {
+    assert_eq!("x", strip_preamble("x"));
+    assert_eq!("x", strip_preamble("\u{feff}x"));
+    assert_eq!("\nx", strip_preamble("#!/usr/bin/env duk\nx"));
+    assert_eq!("\nx", strip_preamble("\u{feff}#!duk\nx"));
+    assert_eq!("", strip_preamble("#!duk"));
+}
+
+#[test]
+fn test_loader() {
+    use std::borrow::Cow;
+    use std::old_io::{TempDir, IoErrorKind};
+    use std::old_io::fs::mkdir;
+    use std::old_io::USER_RWX;
+
+    let dir = TempDir::new("duktape-loader").unwrap();
+    let write = |name: &str, source: &str| {
+        File::create(&dir.path().join(name)).write_str(source).unwrap();
+    };
+    write("b.js", "#!/usr/bin/env duk\nloaded.push('b');\nnull.x;\n");
+    write("a.js", "\u{feff}var loaded = ['a'];");
+    write("notes.txt", "not a script");
+    mkdir(&dir.path().join("sub.js"), USER_RWX).unwrap();
+
+    let mut ctx = Context::new().unwrap();
+
+    // Script errors keep their file names and line numbers.
+    let err = ctx.load_script_dir(dir.path()).unwrap_err();
+    assert!(err.io_error().is_none());
+    assert_eq!(Some(3), err.line_number());
+    assert!(err.file_name().unwrap().ends_with("b.js"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("a,b"))),
+               ctx.eval("loaded.join(',')"));
+
+    write("b.js", "loaded.push('b2');");
+    let paths = ctx.load_script_dir(dir.path()).unwrap();
+    let names: Vec<&str> =
+        paths.iter().map(|p| p.filename_str().unwrap()).collect();
+    assert_eq!(vec!("a.js", "b.js"), names);
+    assert_eq!(Ok(Value::String(Cow::Borrowed("a,b2"))),
+               ctx.eval("loaded.join(',')"));
+
+    // Missing files are I/O errors, not script errors.
+    let err = ctx.eval_file(&dir.path().join("missing.js")).unwrap_err();
+    assert_eq!(IoErrorKind::FileNotFound, err.io_error().unwrap().kind);
+    assert!(ctx.load_script_dir(&dir.path().join("missing")).is_err());
+
+    // Readers work, too, but must contain valid UTF-8.
+    let mut source: &[u8] = b"#!duk\n6 * 7";
+    assert_eq!(Ok(Value::Number(42.0)),
+               ctx.eval_reader(&mut source, "reader.js"));
+    let mut invalid: &[u8] = b"'\xff'";
+    let err = ctx.eval_reader(&mut invalid, "invalid.js").unwrap_err();
+    assert!(err.io_error().is_some());
+}
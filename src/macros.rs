@@ -0,0 +1,240 @@
+//! Macros for defining and calling JavaScript functions.  The functions in
+//! this module are used by the expanded macros, and aren't intended to be
+//! called directly.
+
+use errors::*;
+use args::{Args, arg_labeled};
+use decoder::DuktapeDecodable;
+use encoder::DuktapeEncodable;
+
+/// Define a Rust function which can be called from JavaScript.  The
+/// arguments are decoded automatically, and any argument which can't be
+/// decoded will raise a `TypeError` naming the argument.
+///
+/// ```
+/// #[macro_use] extern crate duktape;
+/// use duktape::{Context,Value,DuktapeResult,DuktapeError};
+///
+/// js_fn! {
+///     fn add(x: f64, y: f64 = 1.0) -> f64 { x + y }
+/// }
+///
+/// js_fn! {
+///     fn checked_div(x: f64, y: f64) -> DuktapeResult<f64> {
+///         if y == 0.0 {
+///             Err(DuktapeError::from_str("division by zero"))
+///         } else {
+///             Ok(x / y)
+///         }
+///     }
+/// }
+///
+/// fn main() {
+///     let mut ctx = Context::new().unwrap();
+///     ctx.register_fn("add", add);
+///     ctx.register_fn("checked_div", checked_div);
+///     assert_eq!(Ok(Value::Number(5.0)), ctx.eval("add(2, 3)"));
+///     assert_eq!(Ok(Value::Number(3.0)), ctx.eval("add(2)"));
+///     assert!(ctx.eval("checked_div(1, 0)").is_err());
+/// }
+/// ```
+///
+/// Arguments may be given default values, which are used when the
+/// argument is `undefined`.  Arguments of type `Option<T>` will be `None`
+/// when they're missing.  A function may return either a value of any
+/// encodable type, a `DuktapeResult`, or nothing at all.
+#[macro_export]
+macro_rules! js_fn {
+    (pub fn $($rest:tt)*) => { js_fn!(@parse [pub] fn $($rest)*); };
+    (fn $($rest:tt)*) => { js_fn!(@parse [] fn $($rest)*); };
+
+    (@parse [$($vis:tt)*]
+     fn $name:ident($($arg:ident: $ty:ty $(= $default:expr)*),*)
+         -> DuktapeResult<$ret:ty> $body:block) => {
+        js_fn!(@define [$($vis)*] $name [$($arg: $ty $(= $default)*),*]
+               $crate::DuktapeResult<$ret>, result, $body);
+    };
+    (@parse [$($vis:tt)*]
+     fn $name:ident($($arg:ident: $ty:ty $(= $default:expr)*),*)
+         -> $ret:ty $body:block) => {
+        js_fn!(@define [$($vis)*] $name [$($arg: $ty $(= $default)*),*]
+               $ret, value, $body);
+    };
+    (@parse [$($vis:tt)*]
+     fn $name:ident($($arg:ident: $ty:ty $(= $default:expr)*),*)
+         $body:block) => {
+        js_fn!(@define [$($vis)*] $name [$($arg: $ty $(= $default)*),*]
+               (), unit, $body);
+    };
+
+    (@define [$($vis:tt)*] $name:ident
+     [$($arg:ident: $ty:ty $(= $default:expr)*),*]
+     $ret:ty, $kind:ident, $body:block) => {
+        #[allow(non_camel_case_types)]
+        $($vis)* struct $name;
+
+        impl $crate::NativeFunction for $name {
+            fn arg_count(&self) -> Option<u16> {
+                Some(js_fn!(@count $($arg)*))
+            }
+
+            fn callback(&self) -> $crate::Callback {
+                #[allow(unused_assignments, unused_mut, unused_variables)]
+                fn callback(ctx: &mut $crate::Context<$crate::Borrowed>,
+                            args: &$crate::Args) ->
+                    $crate::DuktapeResult<Box<$crate::DuktapeEncodable +
+                                              'static>>
+                {
+                    fn body($($arg: $ty),*) -> $ret $body
+
+                    let mut idx = 0us;
+                    $(
+                        let default: Option<$ty> =
+                            None $(.or(Some($default)))*;
+                        let $arg: $ty = try!($crate::macros::arg(
+                            args, idx, stringify!($arg), default));
+                        idx += 1;
+                    )*
+                    js_fn!(@return $kind, body($($arg),*))
+                }
+                callback
+            }
+        }
+    };
+
+    (@return value, $call:expr) => {
+        Ok($crate::macros::ret($call))
+    };
+    (@return result, $call:expr) => {
+        $call.map($crate::macros::ret)
+    };
+    (@return unit, $call:expr) => {
+        { $call; Ok($crate::macros::ret($crate::Value::Undefined)) }
+    };
+
+    (@count) => { 0u16 };
+    (@count $head:ident $($tail:ident)*) => {
+        1u16 + js_fn!(@count $($tail)*)
+    };
+}
+
+/// Call a global JavaScript function by name, encoding each of the
+/// arguments.
+///
+/// ```
+/// #[macro_use] extern crate duktape;
+/// use duktape::{Context,Value};
+///
+/// fn main() {
+///     let mut ctx = Context::new().unwrap();
+///     ctx.eval("function join(a, b) { return a + b; }").unwrap();
+///     assert_eq!(Ok(Value::Number(3.0)), js_call!(ctx, "join", 1.0f64, 2.0f64));
+/// }
+/// ```
+#[macro_export]
+macro_rules! js_call {
+    ($ctx:expr, $fn_name:expr) => {
+        $ctx.call($fn_name, &[])
+    };
+    ($ctx:expr, $fn_name:expr, $($arg:expr),+) => {
+        $ctx.call($fn_name, &[$(&$arg as &$crate::DuktapeEncodable),+])
+    };
+}
+
+/// Decode argument `idx` of the current function call, using `default`
+/// if the argument is `undefined`.
+pub fn arg<T: DuktapeDecodable>(args: &Args, idx: usize, name: &str,
+                                default: Option<T>) -> DuktapeResult<T> {
+    if default.is_some() && args.is_undefined(idx) {
+        return Ok(default.unwrap());
+    }
+    arg_labeled(args, idx, &format!("argument {} ({})", idx, name)[])
+}
+
+/// Box up the return value of a function.
+pub fn ret<T: DuktapeEncodable + 'static>(value: T) ->
+    Box<DuktapeEncodable + 'static>
+{
+    Box::new(value)
+}
+
+#[cfg(test)]
+mod test {
+    use errors::*;
+
+    js_fn! {
+        pub fn add(x: f64, y: f64) -> f64 { x + y }
+    }
+
+    js_fn! {
+        pub fn greet(name: String, greeting: String = "Hello".to_string())
+            -> String
+        {
+            format!("{}, {}!", greeting, name)
+        }
+    }
+
+    js_fn! {
+        pub fn maybe(x: Option<f64>) -> bool { x.is_some() }
+    }
+
+    js_fn! {
+        pub fn fail(msg: String) -> DuktapeResult<f64> {
+            Err(DuktapeError::from_str(&msg[]))
+        }
+    }
+
+    js_fn! {
+        pub fn nothing() {}
+    }
+
+    js_fn! {
+        pub fn pair(x: f64) -> Vec<f64> { vec!(x, x) }
+    }
+}
+
+#[test]
+fn test_js_fn() {
+    use std::borrow::Cow;
+    use types::Value;
+    use context::Context;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.register_fn("add", test::add);
+    ctx.register_fn("greet", test::greet);
+    ctx.register_fn("maybe", test::maybe);
+    ctx.register_fn("fail", test::fail);
+    ctx.register_fn("nothing", test::nothing);
+
+    assert_eq!(Ok(Value::Number(5.0)), ctx.eval("add(2, 3)"));
+    assert_eq!(Ok(Value::Number(2.0)), ctx.eval("add.length"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("Hello, world!"))),
+               ctx.eval("greet('world')"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("Hi, world!"))),
+               ctx.eval("greet('world', 'Hi')"));
+    assert_eq!(Ok(Value::Bool(false)), ctx.eval("maybe()"));
+    assert_eq!(Ok(Value::Bool(true)), ctx.eval("maybe(1)"));
+    assert_eq!(Err(DuktapeError::from_str("Error: oops")),
+               ctx.eval("fail('oops')"));
+    assert_eq!(Ok(Value::Undefined), ctx.eval("nothing()"));
+    ctx.register_fn("pair", test::pair);
+    assert_eq!(Ok(Value::String(Cow::Borrowed("[2,2]"))),
+               ctx.eval("JSON.stringify(pair(2))"));
+
+    // Type errors name the offending argument.
+    assert_eq!(Err(DuktapeError::from_str(
+        "TypeError: argument 1 (y): Expected number, got string")),
+        ctx.eval("add(1, 'two')"));
+}
+
+#[test]
+fn test_js_call() {
+    use types::Value;
+    use context::Context;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("function add(x, y) { return x+y; }").unwrap();
+    ctx.eval("function answer() { return 42; }").unwrap();
+    assert_eq!(Ok(Value::Number(3.0)), js_call!(ctx, "add", 1.0f64, 2.0f64));
+    assert_eq!(Ok(Value::Number(42.0)), js_call!(ctx, "answer"));
+}
@@ -0,0 +1,292 @@
+//! Build JavaScript objects full of Rust functions and numeric constants,
+//! such as `fs` or `crypto`, in one step.  All the functions in a module
+//! share a single native trampoline, and are told apart using duktape's
+//! per-function "magic" values.
+//!
+//! ```
+//! use duktape::{Context, Borrowed, Args, DuktapeEncodable, DuktapeResult,
+//!               Module, Value};
+//!
+//! fn square(_ctx: &mut Context<Borrowed>, args: &Args) ->
+//!     DuktapeResult<Box<DuktapeEncodable + 'static>>
+//! {
+//!     let x: f64 = try!(args.require(0));
+//!     Ok(Box::new(x * x))
+//! }
+//!
+//! let mut ctx = Context::new().unwrap();
+//! Module::new()
+//!     .function("square", square, Some(1))
+//!     .number("TAU", 6.283185307179586)
+//!     .register(&mut ctx, "geometry").unwrap();
+//! assert_eq!(Ok(Value::Number(9.0)), ctx.eval("geometry.square(3)"));
+//! ```
+
+use std::mem::transmute;
+use libc::c_void;
+use ffi::*;
+use errors::*;
+use context::{Context, Callback, NativeFunction, rust_duk_magic_callback,
+              get_global, put_global};
+use stack::{StackFrame, Slot};
+use state;
+
+/// The largest number of distinct module callbacks a heap may contain,
+/// limited by the size of duktape's magic values.
+const MAX_MAGIC: usize = 0x8000;
+
+/// What `put_members` installs, passed to it through the value stack.
+struct Members<'a> {
+    module: &'a Module,
+    /// The magic value of each function.
+    magic: &'a [duk_int_t]
+}
+
+/// Push `name` as a property key.  Unlike duktape's function and number
+/// lists, this allows names which contain NUL characters.
+unsafe fn push_key(ctx: *mut duk_context, name: &str) {
+    duk_push_lstring(ctx, name.as_ptr() as *const i8,
+                     name.len() as duk_size_t);
+}
+
+/// Add the `Members` whose address is on top of the stack to the object
+/// below it.  Called using `duk_safe_call`.
+unsafe extern "C" fn put_members(ctx: *mut duk_context) -> duk_ret_t {
+    let members: &Members = transmute(duk_get_pointer(ctx, -1));
+    duk_pop(ctx);
+    let functions = members.module.functions.iter();
+    for (&(ref name, _, arg_count), &magic) in
+        functions.zip(members.magic.iter())
+    {
+        push_key(ctx, &name[]);
+        duk_push_c_function(ctx, Some(duk_rust_magic_trampoline),
+                            arg_count.map(|n| n as duk_idx_t)
+                                .unwrap_or(DUK_VARARGS));
+        duk_set_magic(ctx, -1, magic);
+        duk_put_prop(ctx, -3);
+    }
+    for &(ref name, value) in members.module.numbers.iter() {
+        push_key(ctx, &name[]);
+        duk_push_number(ctx, value);
+        duk_put_prop(ctx, -3);
+    }
+    0
+}
+
+/// A set of functions and constants which can be installed as the
+/// properties of a JavaScript object.
+pub struct Module {
+    functions: Vec<(String, Callback, Option<u16>)>,
+    numbers: Vec<(String, f64)>
+}
+
+impl Module {
+    /// Create an empty module.
+    pub fn new() -> Module {
+        Module{functions: vec!(), numbers: vec!()}
+    }
+
+    /// Add a Rust callback which expects `arg_count` arguments, or any
+    /// number of arguments if `arg_count` is `None`.
+    pub fn function(mut self, name: &str, f: Callback,
+                    arg_count: Option<u16>) -> Module {
+        self.functions.push((name.to_string(), f, arg_count));
+        self
+    }
+
+    /// Add a `NativeFunction`, typically defined using `js_fn!`.
+    pub fn native_fn<F: NativeFunction>(self, name: &str, f: F) -> Module {
+        self.function(name, f.callback(), f.arg_count())
+    }
+
+    /// Add a numeric constant.
+    pub fn number(mut self, name: &str, value: f64) -> Module {
+        self.numbers.push((name.to_string(), value));
+        self
+    }
+
+    /// Add our functions and constants to the object on top of the stack.
+    unsafe fn put(&self, ctx: *mut duk_context) -> DuktapeResult<()> {
+        // Give each function the magic value of its callback, reusing
+        // slots so that registering a module again doesn't use up more.
+        let state = state::get_or_install(ctx);
+        let callbacks = &mut state.magic_callbacks;
+        let mut magic = Vec::with_capacity(self.functions.len());
+        for &(_, f, _) in self.functions.iter() {
+            let found = callbacks.iter()
+                .position(|&g| g as usize == f as usize);
+            let idx = match found {
+                Some(idx) => idx,
+                None if callbacks.len() < MAX_MAGIC => {
+                    callbacks.push(f);
+                    callbacks.len() - 1
+                }
+                None => {
+                    return Err(DuktapeError::from_code_and_str(
+                        ErrorCode::Range,
+                        "too many module callbacks in one heap"));
+                }
+            };
+            magic.push(idx as duk_int_t);
+        }
+        if duk_check_stack(ctx, 3) == 0 {
+            return Err(DuktapeError::from_code(ErrorCode::Alloc));
+        }
+
+        // Make sure our shared trampoline can find its handler.
+        duk_push_heap_stash(ctx);
+        duk_push_pointer(ctx, rust_duk_magic_callback as *mut c_void);
+        duk_put_prop_string(ctx, -2, DUK_RUST_HANDLER_PROP.as_ptr());
+        duk_pop(ctx);
+
+        // The target may be any object, including one whose setters
+        // throw, so install everything inside a protected call.
+        let members = Members{module: self, magic: &magic[]};
+        duk_dup_top(ctx);
+        duk_push_pointer(ctx, &members as *const Members as *mut c_void);
+        let status = duk_safe_call(ctx, Some(put_members), 2, 1);
+        let mut borrowed = Context::from_borrowed_mut_ptr(ctx);
+        borrowed.pop_result(status).map(|_| ())
+    }
+
+    /// Install this module as the global object `name`.  If `name` is
+    /// already an object, our functions and constants are added to it.
+    pub fn register<H>(&self, ctx: &mut Context<H>, name: &str) ->
+        DuktapeResult<()>
+    {
+        unsafe {
+            let ptr = ctx.as_mut_ptr();
+            if duk_check_stack(ptr, 2) == 0 {
+                return Err(DuktapeError::from_code(ErrorCode::Alloc));
+            }
+            let top = duk_get_top(ptr);
+            let mut result = get_global(ptr, name);
+            if result.is_ok() && duk_is_object(ptr, -1) == 0 {
+                duk_pop(ptr);
+                duk_push_object(ptr);
+                duk_dup_top(ptr);
+                result = put_global(ptr, name);
+            }
+            let result = result.and_then(|()| self.put(ptr));
+            duk_set_top(ptr, top);
+            result
+        }
+    }
+
+    /// Push a new object containing this module's functions and
+    /// constants onto `frame`.
+    pub fn push<'f>(&self, frame: &mut StackFrame<'f>) ->
+        DuktapeResult<Slot<'f>>
+    {
+        unsafe {
+            let ptr = frame.as_mut_ptr();
+            if duk_check_stack(ptr, 1) == 0 {
+                return Err(DuktapeError::from_code(ErrorCode::Alloc));
+            }
+            duk_push_object(ptr);
+            if let Err(err) = self.put(ptr) {
+                duk_pop(ptr);
+                return Err(err);
+            }
+        }
+        Ok(frame.top().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use errors::*;
+    use args::Args;
+    use context::{Context, Borrowed};
+    use encoder::DuktapeEncodable;
+
+    pub fn add(_ctx: &mut Context<Borrowed>, args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        let xs: Vec<f64> = try!(args.rest(0));
+        Ok(Box::new(xs.iter().fold(0.0, |a, &b| a + b)))
+    }
+
+    pub fn name(_ctx: &mut Context<Borrowed>, _args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        Ok(Box::new("name"))
+    }
+
+    pub fn fail(_ctx: &mut Context<Borrowed>, _args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        Err(DuktapeError::from_str("failed"))
+    }
+}
+
+#[test]
+fn test_module() {
+    use std::borrow::Cow;
+    use types::Value;
+
+    let mut ctx = Context::new().unwrap();
+    let math = Module::new()
+        .function("add", test::add, None)
+        .function("name", test::name, Some(0))
+        .number("ANSWER", 42.0);
+    math.register(&mut ctx, "math").unwrap();
+    Module::new()
+        .function("fail", test::fail, Some(0))
+        .function("name", test::name, Some(0))
+        .register(&mut ctx, "other").unwrap();
+
+    assert_eq!(Ok(Value::Number(6.0)), ctx.eval("math.add(1, 2, 3)"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("name"))),
+               ctx.eval("math.name()"));
+    assert_eq!(Ok(Value::Number(42.0)), ctx.eval("math.ANSWER"));
+    assert_eq!(Ok(Value::Number(0.0)), ctx.eval("math.name.length"));
+    assert_eq!(Err(DuktapeError::from_str("Error: failed")),
+               ctx.eval("other.fail()"));
+
+    // Registering into an existing object extends it.
+    ctx.eval("var ext = { old: true };").unwrap();
+    math.register(&mut ctx, "ext").unwrap();
+    assert_eq!(Ok(Value::Number(3.0)),
+               ctx.eval("ext.old ? ext.add(1, 2) : 0"));
+
+    // Modules can also be pushed onto a stack frame.
+    let len = ctx.with_frame(|frame| {
+        frame.push(&0.0f64).unwrap();
+        let obj = math.push(frame).unwrap();
+        assert_eq!(Some(obj), frame.top());
+        frame.len()
+    });
+    assert_eq!(2, len);
+
+    // Registering the same callbacks again reuses their magic values.
+    let used = unsafe { state::get(ctx.as_mut_ptr()).unwrap()
+                        .magic_callbacks.len() };
+    assert_eq!(3, used);
+    for _ in range(0, 100) {
+        math.register(&mut ctx, "math").unwrap();
+    }
+    assert_eq!(used, unsafe { state::get(ctx.as_mut_ptr()).unwrap()
+                              .magic_callbacks.len() });
+    assert_eq!(Ok(Value::Number(3.0)), ctx.eval("math.add(1, 2)"));
+
+    // Errors from duktape are returned instead of thrown.
+    ctx.eval("var frozen = Object.freeze({});\
+              Object.defineProperty(this, 'locked', {\
+                get: function () { return undefined; },\
+                set: function () { throw new Error('locked'); } });")
+        .unwrap();
+    assert!(math.register(&mut ctx, "frozen").is_err());
+    assert_eq!(Err(DuktapeError::from_str("Error: locked")),
+               math.register(&mut ctx, "locked"));
+
+    // Names may contain NUL characters.
+    Module::new()
+        .function("a\0b", test::name, Some(0))
+        .number("c\0d", 7.0)
+        .register(&mut ctx, "nul").unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("name"))),
+               ctx.eval("nul['a\\u0000b']()"));
+    assert_eq!(Ok(Value::Number(7.0)), ctx.eval("nul['c\\u0000d']"));
+    assert_eq!(Ok(Value::Bool(false)), ctx.eval("'a' in nul"));
+}
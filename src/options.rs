@@ -0,0 +1,175 @@
+//! Options controlling how Rust values are represented in JavaScript.
+//! The encoder and decoder understand the same layouts, so a value
+//! encoded using one set of options can be decoded using the matching
+//! set.
+
+use std::default::Default;
+
+/// How to represent an enum variant with data.  Variants without any
+/// data are always represented as a string containing the variant name,
+/// except when using `EnumLayout::Internal`.
+#[derive(Clone, Show, PartialEq)]
+pub enum EnumLayout {
+    /// `{"Variant": [fields...]}`.
+    External,
+    /// `{tag: "Variant", "0": field0, "1": field1, ...}`, where the fields
+    /// are stored as numbered properties next to the tag.  Variants
+    /// without any data are represented as `{tag: "Variant"}`.
+    Internal{
+        /// The name of the property holding the variant name.
+        tag: String
+    },
+    /// `{tag: "Variant", content: [fields...]}`.
+    Adjacent{
+        /// The name of the property holding the variant name.
+        tag: String,
+        /// The name of the property holding the variant's fields.
+        content: String
+    }
+}
+
+impl EnumLayout {
+    /// The layout used by `rustc_serialize::json`, which is our default:
+    /// `{"variant": "Variant", "fields": [fields...]}`.
+    pub fn json() -> EnumLayout {
+        EnumLayout::Adjacent{tag: "variant".to_string(),
+                             content: "fields".to_string()}
+    }
+}
+
+/// How to represent maps.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub enum MapLayout {
+    /// A JavaScript object.  Keys are converted to strings, and converted
+    /// back to numbers when decoding maps with numeric keys.
+    Object,
+    /// An array of `[key, value]` pairs, which preserves the type of each
+    /// key.  This is the format accepted by the ES6 `Map` constructor.
+    Array
+}
+
+/// How to represent `Option::None`.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub enum NoneValue {
+    /// JavaScript `null`.
+    Null,
+    /// JavaScript `undefined`.
+    Undefined
+}
+
+/// How to represent 64-bit integers (including `isize` and `usize`),
+/// which may be too large to store exactly in a JavaScript number.
+/// Smaller integer types are always represented as numbers, and the
+/// decoder always checks that they're integral and in range.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub enum Int64Layout {
+    /// A JavaScript number, silently losing precision above 2^53.
+    Number,
+    /// A JavaScript number, or an error if it can't be represented
+    /// exactly.  This is the default.
+    Checked,
+    /// A string containing the decimal representation of the integer.
+    /// The decoder also accepts numbers which can be represented exactly.
+    String,
+    /// An `Int64` or `UInt64` object, which supports exact arithmetic
+    /// using methods like `add` and `compare`.  Setting this layout
+    /// makes the `Int64` and `UInt64` constructors available as globals.
+    /// The decoder also accepts decimal strings and exact numbers.
+    Object
+}
+
+/// How to decode JavaScript strings which contain unpaired surrogates,
+/// and which therefore can't be represented as a Rust `String`.  To
+/// preserve such strings exactly, decode them as a `JsString` instead.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub enum StringMode {
+    /// Return an error.  This is the default.
+    Strict,
+    /// Replace each unpaired surrogate with U+FFFD.
+    Lossy
+}
+
+/// Options controlling how the encoder represents Rust values.
+#[derive(Clone, Show, PartialEq)]
+pub struct EncoderOptions {
+    /// How to represent enum variants.
+    pub enums: EnumLayout,
+    /// How to represent maps.
+    pub maps: MapLayout,
+    /// How to represent `Option::None`.
+    pub none: NoneValue,
+    /// How to represent 64-bit integers.
+    pub int64: Int64Layout
+}
+
+impl Default for EncoderOptions {
+    fn default() -> EncoderOptions {
+        EncoderOptions{
+            enums: EnumLayout::json(),
+            maps: MapLayout::Object,
+            none: NoneValue::Null,
+            int64: Int64Layout::Checked
+        }
+    }
+}
+
+/// Options controlling how the decoder interprets JavaScript values.
+/// Both `null` and `undefined` are always accepted as `Option::None`.
+#[derive(Clone, Show, PartialEq)]
+pub struct DecoderOptions {
+    /// How enum variants are represented.
+    pub enums: EnumLayout,
+    /// How maps are represented.
+    pub maps: MapLayout,
+    /// How 64-bit integers are represented.
+    pub int64: Int64Layout,
+    /// How to handle strings which aren't valid UTF-16.
+    pub strings: StringMode
+}
+
+impl Default for DecoderOptions {
+    fn default() -> DecoderOptions {
+        DecoderOptions{
+            enums: EnumLayout::json(),
+            maps: MapLayout::Object,
+            int64: Int64Layout::Checked,
+            strings: StringMode::Strict
+        }
+    }
+}
+
+/// How `Context::eval_with` compiles source code.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub enum CompileMode {
+    /// As if passed to JavaScript's `eval`, returning the value of the
+    /// last statement.  This is the default, and what `Context::eval`
+    /// uses.
+    Eval,
+    /// As a global program.
+    Program,
+    /// As a single function expression, such as `function () { ... }`,
+    /// which is called with no arguments.
+    Function
+}
+
+/// Options controlling how `Context::eval_with` compiles and runs code.
+#[derive(Clone, Show, PartialEq)]
+pub struct EvalOptions {
+    /// The file name used in error messages.
+    pub filename: String,
+    /// How to compile the code.
+    pub mode: CompileMode,
+    /// Compile the code in strict mode, even without a `"use strict"`
+    /// directive.
+    pub strict: bool
+}
+
+impl Default for EvalOptions {
+    fn default() -> EvalOptions {
+        EvalOptions{
+            filename: "<eval>".to_string(),
+            mode: CompileMode::Eval,
+            strict: false
+        }
+    }
+}
@@ -0,0 +1,341 @@
+//! A pool of pre-initialized contexts.  Creating a heap and loading a
+//! library into it can be slow, so the pool keeps initialized contexts
+//! around and hands them out as needed.
+//!
+//! ```
+//! use std::default::Default;
+//! use duktape::ContextPool;
+//!
+//! let pool = ContextPool::new(Default::default(), |ctx| {
+//!     ctx.eval("function greet(name) { return 'Hello, ' + name; }")
+//!         .map(|_| ())
+//! }).unwrap();
+//! let mut ctx = pool.get().unwrap();
+//! assert!(ctx.eval("greet('pool')").is_ok());
+//! ```
+
+use std::default::Default;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::slice::from_raw_buf;
+use std::time::Duration;
+use time::precise_time_ns;
+use ffi::*;
+use errors::*;
+use context::{Context, delete_global_bytes};
+
+/// What to do with a context when it's returned to the pool.
+#[derive(Clone, Copy, Show, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Return the context unchanged, including any global variables set
+    /// by the last user.
+    Keep,
+    /// Delete any global variables which didn't exist after the context
+    /// was initialized.  This is cheap, but globals which were modified
+    /// rather than added won't be restored.
+    Globals,
+    /// Throw the context away and initialize a new one.
+    Recreate
+}
+
+/// Options controlling the size and behavior of a `ContextPool`.
+#[derive(Clone, Show, PartialEq)]
+pub struct PoolOptions {
+    /// How many contexts to create up front, and to keep around even when
+    /// they're idle.
+    pub min_idle: usize,
+    /// The most contexts which may exist at once.  When they're all in
+    /// use, `get` will wait for one to be returned.
+    pub max_size: usize,
+    /// How long contexts beyond `min_idle` may remain unused before
+    /// they're destroyed, or `None` to keep them forever.
+    pub idle_timeout: Option<Duration>,
+    /// What to do with contexts when they're returned to the pool.
+    pub reset: ResetMode
+}
+
+impl Default for PoolOptions {
+    fn default() -> PoolOptions {
+        PoolOptions{
+            min_idle: 1,
+            max_size: 8,
+            idle_timeout: Some(Duration::minutes(5)),
+            reset: ResetMode::Globals
+        }
+    }
+}
+
+/// The function used to initialize new contexts.
+type Init = Box<Fn(&mut Context) -> DuktapeResult<()> + Send + Sync>;
+
+/// A context managed by the pool.
+struct Entry {
+    ctx: Context,
+    /// The names of the global variables which existed after
+    /// initialization, as raw duktape strings.
+    globals: Vec<Vec<u8>>,
+    /// When this entry was last returned to the pool.
+    idle_since: u64
+}
+
+struct State {
+    idle: Vec<Entry>,
+    /// The number of contexts in existence, including those in use and
+    /// those being created.
+    total: usize
+}
+
+struct Shared {
+    state: Mutex<State>,
+    returned: Condvar,
+    options: PoolOptions,
+    init: Init
+}
+
+/// A thread-safe pool of initialized contexts.  Cloning a pool gives you
+/// another handle to the same pool.
+#[derive(Clone)]
+pub struct ContextPool {
+    shared: Arc<Shared>
+}
+
+/// A context borrowed from a `ContextPool`, which will be returned to the
+/// pool when it goes out of scope.
+pub struct PooledContext {
+    shared: Arc<Shared>,
+    entry: Option<Entry>
+}
+
+/// The names of the own properties of the global object.  We keep the
+/// raw bytes, because names which aren't valid UTF-8, such as those
+/// containing unpaired surrogates, must still be deleted on reset.
+unsafe fn global_names(ctx: &mut Context) -> Vec<Vec<u8>> {
+    let ptr = ctx.as_mut_ptr();
+    let mut names = vec!();
+    duk_push_global_object(ptr);
+    duk_enum(ptr, -1, DUK_ENUM_OWN_PROPERTIES_ONLY |
+             DUK_ENUM_INCLUDE_NONENUMERABLE);
+    while duk_next(ptr, -1, 0) != 0 {
+        let mut len = 0;
+        let key = duk_get_lstring(ptr, -1, &mut len) as *const u8;
+        names.push(from_raw_buf(&key, len as usize).to_vec());
+        duk_pop(ptr);
+    }
+    duk_pop_2(ptr);
+    names
+}
+
+/// Delete any global variables not listed in `keep`.
+unsafe fn delete_globals(ctx: &mut Context, keep: &[Vec<u8>]) {
+    let extra: Vec<Vec<u8>> = global_names(ctx).into_iter()
+        .filter(|name| !keep.contains(name))
+        .collect();
+    for name in extra.iter() {
+        // This fails for non-configurable properties, which we leave.
+        delete_global_bytes(ctx.as_mut_ptr(), &name[]);
+    }
+}
+
+fn now() -> u64 { precise_time_ns() }
+
+impl Shared {
+    /// Create and initialize a new context.
+    fn create(&self) -> DuktapeResult<Entry> {
+        let mut ctx = try!(Context::new());
+        try!((self.init)(&mut ctx));
+        let globals = unsafe { global_names(&mut ctx) };
+        Ok(Entry{ctx: ctx, globals: globals, idle_since: now()})
+    }
+
+    /// Destroy any contexts which have been idle for too long.
+    fn evict(&self, state: &mut MutexGuard<State>) {
+        let timeout = match self.options.idle_timeout {
+            Some(t) => t.num_nanoseconds().unwrap_or(::std::i64::MAX) as u64,
+            None => return
+        };
+        let now = now();
+        // The least recently used contexts are at the start of `idle`.
+        while state.idle.len() > self.options.min_idle &&
+            now - state.idle[0].idle_since >= timeout
+        {
+            state.idle.remove(0);
+            state.total -= 1;
+        }
+    }
+
+    /// Return `entry` to the pool.
+    fn release(&self, mut entry: Entry) {
+        let entry = match self.options.reset {
+            ResetMode::Keep => Some(entry),
+            ResetMode::Globals => {
+                unsafe { delete_globals(&mut entry.ctx, &entry.globals[]); }
+                Some(entry)
+            }
+            ResetMode::Recreate => {
+                drop(entry);
+                self.create().ok()
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        match entry {
+            Some(mut entry) => {
+                entry.idle_since = now();
+                state.idle.push(entry);
+            }
+            None => { state.total -= 1; }
+        }
+        self.evict(&mut state);
+        self.returned.notify_one();
+    }
+}
+
+impl ContextPool {
+    /// Create a new pool, using `init` to initialize each context.  We
+    /// create `options.min_idle` contexts immediately, and return an
+    /// error if any of them can't be initialized.
+    pub fn new<F>(options: PoolOptions, init: F) -> DuktapeResult<ContextPool>
+        where F: Fn(&mut Context) -> DuktapeResult<()> + Send + Sync + 'static
+    {
+        let shared = Shared{
+            state: Mutex::new(State{idle: vec!(), total: 0}),
+            returned: Condvar::new(),
+            options: options,
+            init: Box::new(init)
+        };
+        let mut idle = vec!();
+        for _ in range(0, shared.options.min_idle) {
+            idle.push(try!(shared.create()));
+        }
+        {
+            let mut state = shared.state.lock().unwrap();
+            state.total = idle.len();
+            state.idle = idle;
+        }
+        Ok(ContextPool{shared: Arc::new(shared)})
+    }
+
+    /// Get a context from the pool, creating one if none are idle.  If
+    /// `max_size` contexts are already in use, wait for one to be
+    /// returned.
+    pub fn get(&self) -> DuktapeResult<PooledContext> {
+        self.get_or_wait(true).map(|ctx| ctx.unwrap())
+    }
+
+    /// Get a context from the pool, or return `None` if `max_size`
+    /// contexts are already in use.
+    pub fn try_get(&self) -> DuktapeResult<Option<PooledContext>> {
+        self.get_or_wait(false)
+    }
+
+    fn get_or_wait(&self, wait: bool) ->
+        DuktapeResult<Option<PooledContext>>
+    {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            shared.evict(&mut state);
+            if let Some(entry) = state.idle.pop() {
+                return Ok(Some(self.guard(entry)));
+            }
+            if state.total < shared.options.max_size {
+                // Create the context without holding the lock.
+                state.total += 1;
+                drop(state);
+                return match shared.create() {
+                    Ok(entry) => Ok(Some(self.guard(entry))),
+                    Err(err) => {
+                        shared.state.lock().unwrap().total -= 1;
+                        shared.returned.notify_one();
+                        Err(err)
+                    }
+                };
+            }
+            if !wait { return Ok(None); }
+            state = shared.returned.wait(state).unwrap();
+        }
+    }
+
+    fn guard(&self, entry: Entry) -> PooledContext {
+        PooledContext{shared: self.shared.clone(), entry: Some(entry)}
+    }
+
+    /// Destroy any contexts which have been idle for longer than
+    /// `idle_timeout`.  This happens automatically whenever a context is
+    /// requested or returned, but you may also call it periodically.
+    pub fn evict_idle(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        self.shared.evict(&mut state);
+    }
+
+    /// The number of contexts which currently exist, including those in
+    /// use.
+    pub fn size(&self) -> usize {
+        self.shared.state.lock().unwrap().total
+    }
+
+    /// The number of idle contexts waiting in the pool.
+    pub fn idle(&self) -> usize {
+        self.shared.state.lock().unwrap().idle.len()
+    }
+}
+
+impl Deref for PooledContext {
+    type Target = Context;
+    fn deref(&self) -> &Context { &self.entry.as_ref().unwrap().ctx }
+}
+
+impl DerefMut for PooledContext {
+    fn deref_mut(&mut self) -> &mut Context {
+        &mut self.entry.as_mut().unwrap().ctx
+    }
+}
+
+impl Drop for PooledContext {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.shared.release(entry);
+        }
+    }
+}
+
+#[test]
+fn test_context_pool() {
+    use std::borrow::Cow;
+    use types::Value;
+
+    let options = PoolOptions{
+        min_idle: 1, max_size: 2, idle_timeout: Some(Duration::zero()),
+        reset: ResetMode::Globals
+    };
+    let pool = ContextPool::new(options, |ctx| {
+        ctx.eval("var lib = { answer: 42 };").map(|_| ())
+    }).unwrap();
+    assert_eq!(1, pool.size());
+
+    {
+        let mut a = pool.get().unwrap();
+        let mut b = pool.get().unwrap();
+        assert!(pool.try_get().unwrap().is_none());
+        assert_eq!(Ok(Value::Number(42.0)), a.eval("lib.answer"));
+        a.eval("var leaked = 1; this['\\ud800'] = 2;").unwrap();
+        b.eval("lib.answer = 43;").unwrap();
+    }
+    assert_eq!(1, pool.size());
+    assert_eq!(1, pool.idle());
+
+    // New globals are removed, but the library is still there.
+    let mut c = pool.get().unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("undefined"))),
+               c.eval("typeof leaked"));
+    assert_eq!(Ok(Value::String(Cow::Borrowed("object"))),
+               c.eval("typeof lib"));
+    assert_eq!(Ok(Value::Bool(false)), c.eval("'\\ud800' in this"));
+}
+
+#[test]
+fn test_context_pool_init_error() {
+    let result = ContextPool::new(Default::default(), |ctx| {
+        ctx.eval("syntax error").map(|_| ())
+    });
+    assert!(result.is_err());
+}
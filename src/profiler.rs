@@ -0,0 +1,323 @@
+//! A sampling profiler and a line coverage collector for scripts, built
+//! on the same engine hooks as `Context::enable_inspector`.  Both require
+//! the `debugger` feature, and only one of the profiler, the coverage
+//! collector and the inspector may be enabled at a time.
+//!
+//! The profiler can only take a sample when duktape checks for debugger
+//! commands.  Duktape rate-limits those checks to about once every 200ms
+//! while running code, so the profiler takes at most about five samples
+//! per second, however short the requested interval.  It is best suited
+//! to finding hot spots in long-running scripts.
+//!
+//! ```
+//! use std::time::Duration;
+//! use duktape::Context;
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.start_profiler(Duration::milliseconds(200));
+//! ctx.eval_from("hot.js", "for (var i = 0, t = 0; i < 100000; i++) \
+//!                          t += i;").unwrap();
+//! let profile = ctx.stop_profiler().unwrap();
+//! // One line per stack, ready for flamegraph.pl.
+//! println!("{}", profile.to_folded());
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::precise_time_ns;
+use context::Context;
+use inspector::{self, StepAction, PausedState};
+
+/// Samples collected by the profiler.
+#[derive(Clone, Show, PartialEq)]
+pub struct Profile {
+    /// The number of times each stack was seen, keyed by stacks in
+    /// "folded" form: frames separated by `;`, outermost first.
+    pub samples: BTreeMap<String, u64>
+}
+
+impl Profile {
+    /// Format our samples in the folded-stack format read by flame graph
+    /// tools, with one `stack count` line per distinct stack.
+    pub fn to_folded(&self) -> String {
+        let mut out = String::new();
+        for (stack, count) in self.samples.iter() {
+            out.push_str(&format!("{} {}\n", stack, count)[]);
+        }
+        out
+    }
+}
+
+/// Line coverage collected for scripts evaluated using `eval_from`.
+#[derive(Clone, Show, PartialEq)]
+pub struct Coverage {
+    /// How many times execution entered each line of each file, moving
+    /// from another line.  This isn't the same as how many times the
+    /// code on a line ran: a loop written on a single line is entered
+    /// once, however many times its body runs.
+    pub hits: BTreeMap<String, BTreeMap<u32, u64>>,
+    /// The source of each file we evaluated, used to find lines which
+    /// were never reached.
+    sources: BTreeMap<String, String>
+}
+
+impl Coverage {
+    /// Guess which lines of `file` contain code.  We don't have access to
+    /// duktape's line tables, so we skip lines which contain nothing but
+    /// whitespace, punctuation and comments.
+    fn code_lines(&self, file: &str) -> Vec<u32> {
+        let source = match self.sources.get(file) {
+            Some(source) => source,
+            None => return vec!()
+        };
+        let mut in_comment = false;
+        source.lines().enumerate().filter(|&(_, line)| {
+            has_code(line, &mut in_comment)
+        }).map(|(i, _)| (i + 1) as u32).collect()
+    }
+
+    /// Format our coverage as an LCOV tracefile.  Each `DA` count is the
+    /// number of times execution entered that line, as in `hits`.
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        let empty = BTreeMap::new();
+        let mut files: Vec<&String> = self.sources.keys().collect();
+        for file in self.hits.keys() {
+            if !self.sources.contains_key(file) { files.push(file); }
+        }
+        for file in files.into_iter() {
+            let hits = self.hits.get(file).unwrap_or(&empty);
+            let mut lines: BTreeMap<u32, u64> = self.code_lines(&file[])
+                .into_iter().map(|line| (line, 0)).collect();
+            for (&line, &count) in hits.iter() { lines.insert(line, count); }
+            out.push_str(&format!("SF:{}\n", file)[]);
+            for (line, count) in lines.iter() {
+                out.push_str(&format!("DA:{},{}\n", line, count)[]);
+            }
+            let hit = lines.values().filter(|&&count| count > 0).count();
+            out.push_str(&format!("LH:{}\nLF:{}\nend_of_record\n",
+                                  hit, lines.len())[]);
+        }
+        out
+    }
+}
+
+/// Does `line` contain anything besides whitespace, punctuation and
+/// comments?  `in_comment` tracks whether we're inside a `/* */` comment,
+/// which may span several lines.
+fn has_code(line: &str, in_comment: &mut bool) -> bool {
+    let chars: Vec<char> = line.chars().collect();
+    let mut quote = None;
+    let mut found = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).map(|&c| c);
+        if *in_comment {
+            if c == '*' && next == Some('/') {
+                *in_comment = false;
+                i += 1;
+            }
+        } else if let Some(q) = quote {
+            // Comment markers inside strings don't count.
+            if c == '\\' { i += 1; } else if c == q { quote = None; }
+        } else if c == '/' && next == Some('/') {
+            break;
+        } else if c == '/' && next == Some('*') {
+            *in_comment = true;
+            i += 1;
+        } else {
+            if c == '"' || c == '\'' { quote = Some(c); }
+            if !c.is_whitespace() && !"{}();,".contains_char(c) {
+                found = true;
+            }
+        }
+        i += 1;
+    }
+    found
+}
+
+/// Heap user data pointing at a running profiler.
+struct ProfilerHandle(Arc<Mutex<Profile>>);
+
+/// Heap user data pointing at a running coverage collector.
+struct CoverageHandle(Arc<Mutex<Coverage>>);
+
+/// Format `paused`'s call stack as a single folded line.
+fn fold_stack(paused: &PausedState) -> String {
+    let frames: Vec<String> = paused.stack.iter().rev().map(|frame| {
+        let name = if frame.function.is_empty() {
+            "(anonymous)"
+        } else {
+            &frame.function[]
+        };
+        format!("{} ({}:{})", name, frame.file, frame.line)
+    }).collect();
+    frames.connect(";")
+}
+
+/// Record the source of a script we're about to evaluate, and make sure
+/// we see its first line.  `idle` is false if a script is already running.
+pub fn before_eval<H>(ctx: &mut Context<H>, filename: &str, code: &str,
+                      idle: bool) {
+    let recorded = match ctx.user_data::<CoverageHandle>() {
+        Some(&mut CoverageHandle(ref coverage)) => {
+            coverage.lock().unwrap().sources
+                .insert(filename.to_string(), code.to_string());
+            true
+        }
+        None => false
+    };
+    if recorded { inspector::pause_soon(ctx, idle); }
+}
+
+impl Context {
+    /// Start sampling the call stack of running scripts, waiting at least
+    /// `interval` between samples.  Duktape limits how often we can take
+    /// samples, so samples are at least about 200ms apart, however short
+    /// `interval` is.  A `debugger` statement also takes a sample.  This
+    /// replaces any attached debugger.
+    pub fn start_profiler(&mut self, interval: Duration) {
+        let profile = Arc::new(Mutex::new(Profile{samples: BTreeMap::new()}));
+        let samples = profile.clone();
+        let interval_ns = interval.num_nanoseconds().unwrap_or(0) as u64;
+        let mut last_sample = precise_time_ns();
+        inspector::attach_session(self, Box::new(move |paused| {
+            if !paused.stack.is_empty() {
+                // Any pause is a sample, including `debugger` statements.
+                let mut profile = samples.lock().unwrap();
+                let count = profile.samples.entry(fold_stack(paused))
+                    .get().unwrap_or_else(|v| v.insert(0));
+                *count += 1;
+            }
+            StepAction::Resume
+        }), Some(Box::new(move || {
+            let now = precise_time_ns();
+            if now - last_sample >= interval_ns {
+                last_sample = now;
+                true
+            } else {
+                false
+            }
+        })), false);
+        self.set_user_data(ProfilerHandle(profile));
+    }
+
+    /// Stop the profiler and return its samples, or `None` if it isn't
+    /// running.
+    pub fn stop_profiler(&mut self) -> Option<Profile> {
+        let profile = match self.user_data::<ProfilerHandle>() {
+            Some(&mut ProfilerHandle(ref profile)) =>
+                profile.lock().unwrap().clone(),
+            None => return None
+        };
+        self.remove_user_data::<ProfilerHandle>();
+        self.disable_inspector();
+        Some(profile)
+    }
+
+    /// Start recording which lines of each script are executed.  Only
+    /// scripts evaluated after this call are included.  This replaces any
+    /// attached debugger, and slows down execution considerably.
+    pub fn start_coverage(&mut self) {
+        let coverage = Arc::new(Mutex::new(Coverage{
+            hits: BTreeMap::new(), sources: BTreeMap::new()
+        }));
+        let hits = coverage.clone();
+        inspector::attach_session(self, Box::new(move |paused| {
+            if let Some(frame) = paused.stack.first() {
+                let mut coverage = hits.lock().unwrap();
+                let lines = coverage.hits.entry(frame.file.clone()).get()
+                    .unwrap_or_else(|v| v.insert(BTreeMap::new()));
+                let count = lines.entry(frame.line).get()
+                    .unwrap_or_else(|v| v.insert(0));
+                *count += 1;
+            }
+            // Stop at every line we reach.
+            StepAction::StepInto
+        }), None, false);
+        self.set_user_data(CoverageHandle(coverage));
+    }
+
+    /// Return the coverage collected so far, or `None` if coverage isn't
+    /// being recorded.
+    pub fn coverage(&mut self) -> Option<Coverage> {
+        match self.user_data::<CoverageHandle>() {
+            Some(&mut CoverageHandle(ref coverage)) =>
+                Some(coverage.lock().unwrap().clone()),
+            None => None
+        }
+    }
+
+    /// Stop recording coverage, and return what was collected.
+    pub fn stop_coverage(&mut self) -> Option<Coverage> {
+        let coverage = self.coverage();
+        if coverage.is_some() {
+            self.remove_user_data::<CoverageHandle>();
+            self.disable_inspector();
+        }
+        coverage
+    }
+}
+
+#[test]
+fn test_profiler() {
+    let mut ctx = Context::new().unwrap();
+    // Timed samples depend on how fast we run, so we take our samples
+    // using `debugger` statements, which always pause.
+    ctx.start_profiler(Duration::days(1));
+    ctx.eval_from("busy.js", "function spin() {\n\
+                                var t = 0;\n\
+                                for (var i = 0; i < 3; i++) {\n\
+                                  debugger; t += i;\n\
+                                }\n\
+                                return t;\n\
+                              }\n\
+                              spin();").unwrap();
+    let profile = ctx.stop_profiler().unwrap();
+    let spinning: Vec<(&String, &u64)> = profile.samples.iter()
+        .filter(|&(stack, _)| stack.ends_with("spin (busy.js:4)"))
+        .collect();
+    assert_eq!(1, spinning.len());
+    assert_eq!(3, *spinning[0].1);
+    let folded = profile.to_folded();
+    assert!(folded.lines().any(|line| {
+        line.ends_with("spin (busy.js:4) 3")
+    }));
+
+    // Once stopped, there's nothing more to collect.
+    assert_eq!(None, ctx.stop_profiler());
+}
+
+#[test]
+fn test_coverage() {
+    let mut ctx = Context::new().unwrap();
+    ctx.start_coverage();
+    ctx.eval_from("rules.js", "function check(x) {\n\
+                                 if (x > 0) {\n\
+                                   return 'positive';\n\
+                                 }\n\
+                                 // Never reached by our test.\n\
+                                 return 'other';\n\
+                               }\n\
+                               /* Not code,\n\
+                                  even with a `;` in it. */\n\
+                               check(1);\n\
+                               check(2);").unwrap();
+    let coverage = ctx.stop_coverage().unwrap();
+    let lines = coverage.hits.get("rules.js").unwrap();
+    assert_eq!(Some(&2), lines.get(&3));
+    assert_eq!(None, lines.get(&6));
+
+    let lcov = coverage.to_lcov();
+    assert!(lcov.starts_with("SF:rules.js\n"));
+    assert!(lcov.contains("DA:3,2\n"));
+    assert!(lcov.contains("DA:6,0\n"));
+    assert!(!lcov.contains("DA:4,"));
+    assert!(!lcov.contains("DA:5,"));
+    assert!(!lcov.contains("DA:8,"));
+    assert!(!lcov.contains("DA:9,"));
+    assert!(lcov.ends_with("end_of_record\n"));
+    assert_eq!(None, ctx.coverage());
+}
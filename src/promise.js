@@ -0,0 +1,145 @@
+// A minimal Promises/A+ implementation, installed by `EventLoop` because
+// duktape 1.x has no native `Promise`.  Reactions are run using
+// `setImmediate`, so they only happen while the event loop is running.
+//
+// Evaluating this file returns a function which creates a "deferred"
+// object, `{promise, resolve, reject}`, for use by Rust code.
+(function (global) {
+    var PENDING = 0, FULFILLED = 1, REJECTED = 2;
+
+    function isFunction(f) { return typeof f === 'function'; }
+
+    function Promise(executor) {
+        if (!(this instanceof Promise))
+            throw new TypeError('Promise must be called with new');
+        if (!isFunction(executor))
+            throw new TypeError('Promise executor is not a function');
+        var self = this, done = false;
+        this._state = PENDING;
+        this._value = undefined;
+        this._reactions = [];
+        try {
+            executor(function (value) {
+                if (!done) { done = true; resolve(self, value); }
+            }, function (reason) {
+                if (!done) { done = true; settle(self, REJECTED, reason); }
+            });
+        } catch (e) {
+            if (!done) { done = true; settle(self, REJECTED, e); }
+        }
+    }
+
+    function settle(promise, state, value) {
+        if (promise._state !== PENDING) return;
+        promise._state = state;
+        promise._value = value;
+        var reactions = promise._reactions;
+        promise._reactions = null;
+        for (var i = 0; i < reactions.length; i++)
+            schedule(promise, reactions[i]);
+    }
+
+    function resolve(promise, value) {
+        if (value === promise)
+            return settle(promise, REJECTED,
+                          new TypeError('Promise resolved with itself'));
+        if (value !== null &&
+            (typeof value === 'object' || isFunction(value))) {
+            var then, called = false;
+            try {
+                then = value.then;
+                if (isFunction(then)) {
+                    then.call(value, function (v) {
+                        if (!called) { called = true; resolve(promise, v); }
+                    }, function (r) {
+                        if (!called) {
+                            called = true; settle(promise, REJECTED, r);
+                        }
+                    });
+                    return;
+                }
+            } catch (e) {
+                if (!called) { called = true; settle(promise, REJECTED, e); }
+                return;
+            }
+        }
+        settle(promise, FULFILLED, value);
+    }
+
+    function schedule(promise, reaction) {
+        setImmediate(function () {
+            var fulfilled = promise._state === FULFILLED;
+            var handler = fulfilled ? reaction.onFulfilled
+                                    : reaction.onRejected;
+            if (!isFunction(handler)) {
+                if (fulfilled) resolve(reaction.promise, promise._value);
+                else settle(reaction.promise, REJECTED, promise._value);
+                return;
+            }
+            var result;
+            try {
+                result = handler(promise._value);
+            } catch (e) {
+                settle(reaction.promise, REJECTED, e);
+                return;
+            }
+            resolve(reaction.promise, result);
+        });
+    }
+
+    Promise.prototype.then = function (onFulfilled, onRejected) {
+        var reaction = {
+            onFulfilled: onFulfilled,
+            onRejected: onRejected,
+            promise: new Promise(function () {})
+        };
+        if (this._state === PENDING) this._reactions.push(reaction);
+        else schedule(this, reaction);
+        return reaction.promise;
+    };
+
+    Promise.prototype['catch'] = function (onRejected) {
+        return this.then(undefined, onRejected);
+    };
+
+    Promise.resolve = function (value) {
+        if (value instanceof Promise) return value;
+        return new Promise(function (res) { res(value); });
+    };
+
+    Promise.reject = function (reason) {
+        return new Promise(function (res, rej) { rej(reason); });
+    };
+
+    Promise.all = function (items) {
+        return new Promise(function (res, rej) {
+            var results = [], remaining = items.length;
+            if (remaining === 0) return res(results);
+            for (var i = 0; i < items.length; i++) (function (i) {
+                Promise.resolve(items[i]).then(function (value) {
+                    results[i] = value;
+                    if (--remaining === 0) res(results);
+                }, rej);
+            })(i);
+        });
+    };
+
+    Promise.race = function (items) {
+        return new Promise(function (res, rej) {
+            for (var i = 0; i < items.length; i++)
+                Promise.resolve(items[i]).then(res, rej);
+        });
+    };
+
+    if (!isFunction(global.Promise)) global.Promise = Promise;
+
+    var P = global.Promise;
+    return function () {
+        var deferred = {};
+        deferred.promise = new P(function (res, rej) {
+            deferred.resolve = res;
+            deferred.reject = rej;
+        });
+        return deferred;
+    };
+})(this)
@@ -0,0 +1,314 @@
+//! JavaScript promises which are settled by Rust computations.  Since
+//! duktape 1.x has no `Promise`, `EventLoop` installs a small polyfill,
+//! and then settles our promises as the underlying Rust work completes.
+//!
+//! ```
+//! use duktape::{Context, Borrowed, EventLoop, Args, DuktapeEncodable,
+//!               DuktapeResult, Promise, Value};
+//!
+//! fn slow_double(ctx: &mut Context<Borrowed>, args: &Args) ->
+//!     DuktapeResult<Box<DuktapeEncodable + 'static>>
+//! {
+//!     let x: f64 = try!(args.require(0));
+//!     // This runs on a background thread.
+//!     let promise = try!(Promise::spawn(ctx, move || Ok(x * 2.0)));
+//!     Ok(Box::new(promise))
+//! }
+//!
+//! let mut ev = EventLoop::new(Context::new().unwrap()).unwrap();
+//! ev.register("slowDouble", slow_double, Some(1));
+//! ev.eval("var result; \
+//!          slowDouble(21).then(function (x) { result = x; });").unwrap();
+//! ev.run_until_idle().unwrap();
+//! assert_eq!(Ok(Value::Number(42.0)), ev.eval("result"));
+//! ```
+
+use std::ffi::CString;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use ffi::*;
+use errors::*;
+use context::Context;
+use encoder::{Encoder, DuktapeEncodable};
+use event_loop;
+
+/// The source of our `Promise` polyfill.
+const PROMISE_JS: &'static str = include_str!("promise.js");
+
+/// The hidden global stash property holding the function which creates
+/// deferred objects, as a NUL-terminated byte string.
+const DEFERRED_FN_PROP: &'static [u8] = b"\xffdeferred\0";
+
+/// The hidden global stash property holding an object which maps promise
+/// IDs to the deferred objects of promises we haven't settled yet, as a
+/// NUL-terminated byte string.
+const PROMISES_PROP: &'static [u8] = b"\xffpromises\0";
+
+/// The result of an asynchronous operation, ready to be encoded.
+pub type BoxedResult = DuktapeResult<Box<DuktapeEncodable + 'static>>;
+
+/// A result which will be available at some point in the future.
+trait Pending {
+    /// Return the result if it's ready, without blocking.
+    fn poll(&mut self) -> Option<BoxedResult>;
+}
+
+impl<T: DuktapeEncodable + Send + 'static> Pending
+    for Receiver<DuktapeResult<T>>
+{
+    fn poll(&mut self) -> Option<BoxedResult> {
+        match self.try_recv() {
+            Ok(result) => Some(result.map(|value| {
+                Box::new(value) as Box<DuktapeEncodable + 'static>
+            })),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) =>
+                Some(Err(DuktapeError::from_str(
+                    "asynchronous operation was abandoned")))
+        }
+    }
+}
+
+/// A promise waiting for its Rust result.  These are stored by the event
+/// loop.
+pub struct PendingPromise {
+    id: u32,
+    result: Box<Pending + 'static>
+}
+
+impl PendingPromise {
+    /// Return the result if it's ready, without blocking.
+    pub fn poll(&mut self) -> Option<BoxedResult> {
+        self.result.poll()
+    }
+
+    /// Resolve or reject our promise using `result`.
+    pub unsafe fn settle(&self, ctx: *mut duk_context, result: BoxedResult)
+        -> DuktapeResult<()>
+    {
+        let top = duk_get_top(ctx);
+        if duk_check_stack(ctx, 5) == 0 {
+            return Err(DuktapeError::from_code(ErrorCode::Alloc));
+        }
+        event_loop::push_stash_table(ctx, PROMISES_PROP);
+        duk_get_prop_index(ctx, -1, self.id);
+        duk_del_prop_index(ctx, -2, self.id);
+        if duk_is_object(ctx, -1) == 0 {
+            duk_set_top(ctx, top);
+            return Ok(());
+        }
+
+        // Push the appropriate settle function and its argument.  If we
+        // can't encode our value, we reject the promise instead.
+        let pushed = result.and_then(|value| {
+            let c_resolve = CString::from_slice(b"resolve");
+            duk_get_prop_string(ctx, -1, c_resolve.as_ptr());
+            let mut encoder = Encoder::new(ctx);
+            value.duktape_encode(&mut encoder).map_err(|err| {
+                duk_pop(ctx);
+                err
+            })
+        });
+        if let Err(err) = pushed {
+            let c_reject = CString::from_slice(b"reject");
+            let c_error = CString::from_slice(b"Error");
+            duk_get_prop_string(ctx, -1, c_reject.as_ptr());
+            duk_push_global_object(ctx);
+            duk_get_prop_string(ctx, -1, c_error.as_ptr());
+            duk_remove(ctx, -2);
+            let msg = err.to_string();
+            duk_push_lstring(ctx, msg.as_ptr() as *const i8,
+                             msg.len() as duk_size_t);
+            duk_new(ctx, 1);
+        }
+
+        let status = duk_pcall(ctx, 1);
+        let mut borrowed = Context::from_borrowed_mut_ptr(ctx);
+        let result = borrowed.pop_result(status);
+        duk_set_top(ctx, top);
+        result.map(|_| ())
+    }
+}
+
+/// Install our `Promise` polyfill, unless `Promise` is already defined.
+/// Re-exported within the crate, but not outside.
+pub fn install<H>(ctx: &mut Context<H>) -> DuktapeResult<()> {
+    unsafe {
+        let ptr = ctx.as_mut_ptr();
+        if duk_check_stack(ptr, 2) == 0 {
+            return Err(DuktapeError::from_code(ErrorCode::Alloc));
+        }
+        let status = ctx.eval_raw("promise.js", PROMISE_JS);
+        if status != DUK_EXEC_SUCCESS {
+            return Err(ctx.pop_result(status).err().unwrap());
+        }
+        duk_push_global_stash(ptr);
+        duk_swap_top(ptr, -2);
+        duk_put_prop_string(ptr, -2, DEFERRED_FN_PROP.as_ptr() as *const i8);
+        duk_pop(ptr);
+        Ok(())
+    }
+}
+
+/// A JavaScript promise which will be resolved with the result of a Rust
+/// computation.  Return this from a callback, and the promise will be
+/// settled by the `EventLoop` once the result is available.  If the
+/// result is an error, the promise is rejected with an `Error` object.
+pub struct Promise {
+    id: u32
+}
+
+impl Promise {
+    /// Create a promise which will be settled with the value sent over
+    /// `receiver`.  If the sender is dropped without sending anything,
+    /// the promise is rejected.  This requires an `EventLoop`.
+    pub fn from_receiver<H, T>(ctx: &mut Context<H>,
+                                  receiver: Receiver<DuktapeResult<T>>) ->
+        DuktapeResult<Promise>
+        where T: DuktapeEncodable + Send + 'static
+    {
+        unsafe {
+            let ptr = ctx.as_mut_ptr();
+            try!(event_loop::loop_state(ptr));
+            if duk_check_stack(ptr, 3) == 0 {
+                return Err(DuktapeError::from_code(ErrorCode::Alloc));
+            }
+
+            // Create a deferred object and remember it until we're ready
+            // to settle it.
+            duk_push_global_stash(ptr);
+            duk_get_prop_string(ptr, -1,
+                                DEFERRED_FN_PROP.as_ptr() as *const i8);
+            duk_remove(ptr, -2);
+            let status = duk_pcall(ptr, 0);
+            if status != DUK_EXEC_SUCCESS {
+                return Err(ctx.pop_result(status).err().unwrap());
+            }
+            let state = try!(event_loop::loop_state(ptr));
+            let id = state.next_id();
+            event_loop::push_stash_table(ptr, PROMISES_PROP);
+            duk_swap_top(ptr, -2);
+            duk_put_prop_index(ptr, -2, id);
+            duk_pop(ptr);
+
+            state.futures.push(PendingPromise{
+                id: id, result: Box::new(receiver)
+            });
+            Ok(Promise{id: id})
+        }
+    }
+
+    /// Run `f` on a new thread, and create a promise which will be
+    /// settled with its result.  This requires an `EventLoop`.
+    pub fn spawn<H, T, F>(ctx: &mut Context<H>, f: F) ->
+        DuktapeResult<Promise>
+        where T: DuktapeEncodable + Send + 'static,
+              F: FnOnce() -> DuktapeResult<T> + Send + 'static
+    {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            // The promise may have been discarded along with its context.
+            let _ = sender.send(f());
+        });
+        Promise::from_receiver(ctx, receiver)
+    }
+}
+
+impl DuktapeEncodable for Promise {
+    fn duktape_encode(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        unsafe {
+            let ctx = s.as_mut_ptr();
+            if duk_check_stack(ctx, 2) == 0 {
+                return Err(DuktapeError::from_code(ErrorCode::Alloc));
+            }
+            event_loop::push_stash_table(ctx, PROMISES_PROP);
+            duk_get_prop_index(ctx, -1, self.id);
+            duk_remove(ctx, -2);
+            if duk_is_object(ctx, -1) == 0 {
+                duk_pop(ctx);
+                return Err(DuktapeError::from_str(
+                    "promise was already settled"));
+            }
+            let c_promise = CString::from_slice(b"promise");
+            duk_get_prop_string(ctx, -1, c_promise.as_ptr());
+            duk_remove(ctx, -2);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use errors::*;
+    use args::Args;
+    use context::{Context, Borrowed};
+    use encoder::DuktapeEncodable;
+    use super::Promise;
+
+    pub fn double_later(ctx: &mut Context<Borrowed>, args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        let x: f64 = try!(args.require(0));
+        Ok(Box::new(try!(Promise::spawn(ctx, move || {
+            if x < 0.0 {
+                Err(DuktapeError::from_str("negative"))
+            } else {
+                Ok(x * 2.0)
+            }
+        }))))
+    }
+
+    pub fn abandoned(ctx: &mut Context<Borrowed>, _args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        let (_, receiver) = channel::<DuktapeResult<f64>>();
+        Ok(Box::new(try!(Promise::from_receiver(ctx, receiver))))
+    }
+}
+
+#[test]
+fn test_promises() {
+    use std::borrow::Cow;
+    use types::Value;
+    use event_loop::EventLoop;
+
+    let mut ev = EventLoop::new(Context::new().unwrap()).unwrap();
+    ev.register("doubleLater", test::double_later, Some(1));
+    ev.register("abandoned", test::abandoned, Some(0));
+    ev.eval("var log = [];\
+             function note(x) { log.push(x); }\
+             doubleLater(2).then(function (x) { return x + 1; }).then(note);\
+             doubleLater(-1).then(note, function (e) { note(e.message); });\
+             abandoned()['catch'](function (e) { note(e.message); });\
+             Promise.all([doubleLater(1), 7, Promise.resolve(8)])\
+               .then(function (xs) { note(xs.join('+')); });\
+             note('sync');").unwrap();
+    // Our four promises, plus anything the polyfill has scheduled.
+    assert!(ev.pending() >= 4);
+    ev.run_until_idle().unwrap();
+    assert_eq!(0, ev.pending());
+
+    // The order in which background threads finish may vary.
+    let log = match ev.eval("log.join('|')") {
+        Ok(Value::String(log)) => log.into_owned(),
+        other => panic!("unexpected result: {:?}", other)
+    };
+    let mut log: Vec<&str> = log.split('|').collect();
+    assert_eq!("sync", log.remove(0));
+    log.sort();
+    assert_eq!(vec!("2+7+8", "5", "asynchronous operation was abandoned",
+                    "negative"),
+               log);
+
+    // Our polyfill follows the usual rules for nested promises.
+    ev.eval("var r; Promise.resolve(Promise.reject(new Error('x')))\
+               .then(null, function (e) { r = e.message; });").unwrap();
+    ev.run_until_idle().unwrap();
+    assert_eq!(Ok(Value::String(Cow::Borrowed("x"))), ev.eval("r"));
+
+    // Promises need an event loop.
+    let mut ctx = Context::new().unwrap();
+    ctx.register("doubleLater", test::double_later, Some(1));
+    assert!(ctx.eval("doubleLater(1)").is_err());
+}
@@ -0,0 +1,372 @@
+//! Keeping script source around for better error reports.  When the
+//! source of a script is kept, errors thrown by it include a code frame
+//! showing the failing line, and source maps can translate locations in
+//! bundled scripts back to their original files.
+//!
+//! ```
+//! use duktape::Context;
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.set_keep_source(true);
+//! let err = ctx.eval_from("app.js", "var x = 1;\nnull.boom;\n")
+//!     .unwrap_err();
+//! assert_eq!(Some("app.js"), err.file_name());
+//! assert_eq!(Some(2), err.line_number());
+//! assert!(err.code_frame().unwrap().contains("> 2 | null.boom;"));
+//! ```
+//!
+//! Sources are kept by file name, because that's all an error tells us
+//! about where it came from.  We keep the sources of the most recent
+//! `MAX_KEPT_SOURCES` files, and we don't show code frames for a file
+//! which was compiled more than once with different source, such as
+//! `<eval>`, because we can't tell which version an error came from.
+
+use std::cmp::{min, max};
+use std::collections::HashMap;
+use rustc_serialize::json;
+use ffi::*;
+use errors::*;
+use context::Context;
+use state;
+
+/// A decoded version 3 source map.  We only track lines, because duktape
+/// 1.x doesn't report the columns where errors occur.
+pub struct SourceMap {
+    sources: Vec<String>,
+    sources_content: Vec<Option<String>>,
+    /// The original source index and line of the first mapped segment on
+    /// each generated line.
+    lines: Vec<Option<(usize, u32)>>
+}
+
+/// The JSON representation of a source map.
+#[allow(non_snake_case)]
+#[derive(RustcDecodable)]
+struct RawSourceMap {
+    version: u32,
+    sources: Vec<String>,
+    sourcesContent: Option<Vec<Option<String>>>,
+    mappings: String
+}
+
+/// Decode one base64 VLQ value from `chars`.
+fn decode_vlq<I: Iterator<Item=u8>>(chars: &mut I) -> DuktapeResult<i64> {
+    let mut result = 0i64;
+    let mut shift = 0;
+    loop {
+        let digit = match chars.next() {
+            Some(c @ b'A'...b'Z') => c - b'A',
+            Some(c @ b'a'...b'z') => c - b'a' + 26,
+            Some(c @ b'0'...b'9') => c - b'0' + 52,
+            Some(b'+') => 62,
+            Some(b'/') => 63,
+            _ => return Err(DuktapeError::from_str(
+                "invalid source map mappings"))
+        } as i64;
+        // Anything longer would overflow our result.
+        if shift > 60 {
+            return Err(DuktapeError::from_str(
+                "invalid source map mappings"));
+        }
+        result += (digit & 0x1f) << shift;
+        shift += 5;
+        if digit & 0x20 == 0 { break; }
+    }
+    Ok(if result & 1 == 1 { -(result >> 1) } else { result >> 1 })
+}
+
+impl SourceMap {
+    /// Parse a source map from its JSON representation.
+    pub fn from_json(text: &str) -> DuktapeResult<SourceMap> {
+        let raw: RawSourceMap = try!(json::decode(text).map_err(|err| {
+            DuktapeError::from_str(&format!("invalid source map: {:?}",
+                                            err)[])
+        }));
+        if raw.version != 3 {
+            return Err(DuktapeError::from_str(
+                "only version 3 source maps are supported"));
+        }
+
+        // Source indices and original lines are relative to the previous
+        // segment, even across generated lines.
+        let (mut source, mut orig_line) = (0i64, 0i64);
+        let mut lines = vec!();
+        for line in raw.mappings.split(';') {
+            let mut first = None;
+            for segment in line.split(',').filter(|s| !s.is_empty()) {
+                let mut chars = segment.bytes().peekable();
+                try!(decode_vlq(&mut chars));
+                if chars.peek().is_none() { continue; }
+                source += try!(decode_vlq(&mut chars));
+                orig_line += try!(decode_vlq(&mut chars));
+                if first.is_none() && source >= 0 && orig_line >= 0 {
+                    first = Some((source as usize, (orig_line + 1) as u32));
+                }
+            }
+            lines.push(first);
+        }
+
+        let count = raw.sources.len();
+        Ok(SourceMap{
+            sources: raw.sources,
+            sources_content: raw.sourcesContent
+                .unwrap_or_else(|| range(0, count).map(|_| None).collect()),
+            lines: lines
+        })
+    }
+
+    /// Find the original file, line and source, if known, for `line` of
+    /// the generated file.  Lines are numbered from 1.
+    pub fn lookup(&self, line: u32) -> Option<(&str, u32, Option<&str>)> {
+        if line == 0 { return None; }
+        self.lines.get((line - 1) as usize).and_then(|l| *l)
+            .and_then(|(source, orig_line)| {
+                self.sources.get(source).map(|file| {
+                    let content = self.sources_content.get(source)
+                        .and_then(|c| c.as_ref()).map(|c| &c[]);
+                    (&file[], orig_line, content)
+                })
+            })
+    }
+}
+
+/// The maximum number of files whose source we keep at once.
+pub const MAX_KEPT_SOURCES: usize = 64;
+
+/// The source of the files compiled most recently while keeping source.
+pub struct KeptSources {
+    /// The source of each file, or `None` if it was compiled more than
+    /// once with different source.
+    by_file: HashMap<String, Option<String>>,
+    /// The files in `by_file`, oldest first.
+    order: Vec<String>
+}
+
+impl KeptSources {
+    /// Create an empty collection of sources.
+    pub fn new() -> KeptSources {
+        KeptSources{by_file: HashMap::new(), order: vec!()}
+    }
+
+    /// Remember that `file` was compiled from `code`, forgetting the
+    /// oldest file if we have too many.
+    pub fn insert(&mut self, file: &str, code: &str) {
+        let known = match self.by_file.get(file) {
+            Some(&Some(ref old)) => Some(&old[] == code),
+            Some(&None) => Some(false),
+            None => None
+        };
+        match known {
+            Some(true) => {}
+            Some(false) => { self.by_file.insert(file.to_string(), None); }
+            None => {
+                if self.order.len() >= MAX_KEPT_SOURCES {
+                    let oldest = self.order.remove(0);
+                    self.by_file.remove(&oldest);
+                }
+                self.order.push(file.to_string());
+                self.by_file.insert(file.to_string(), Some(code.to_string()));
+            }
+        }
+    }
+
+    /// Get the source of `file`, if we know it unambiguously.
+    pub fn get(&self, file: &str) -> Option<&str> {
+        self.by_file.get(file).and_then(|s| s.as_ref()).map(|s| &s[])
+    }
+
+    /// Forget all our sources.
+    pub fn clear(&mut self) {
+        self.by_file.clear();
+        self.order.clear();
+    }
+}
+
+/// Format the lines of `source` around `line`, marking `line`.
+pub fn code_frame(source: &str, line: u32) -> Option<String> {
+    let lines: Vec<&str> = source.lines().collect();
+    if line == 0 || line as usize > lines.len() { return None; }
+    let first = max(line as usize, 3) - 2;
+    let last = min(line as usize + 2, lines.len());
+    let width = last.to_string().len();
+    let mut out = String::new();
+    for n in range(first, last + 1) {
+        let text = lines[n - 1];
+        let marker = if n == line as usize { ">" } else { " " };
+        out.push_str(&format!("{0} {1:>2$} | {3}\n",
+                              marker, n, width, text)[]);
+        if n == line as usize {
+            // We don't know the column, so point at the first token.
+            let indent = text.len() - text.trim_left().len();
+            out.push_str(&format!("  {0:>1$} | {2}^\n", "", width,
+                                  &text[..indent])[]);
+        }
+    }
+    Some(out)
+}
+
+/// Record where `err` was thrown, translating the location using any
+/// source map, and adding a code frame if we kept the source.
+pub unsafe fn locate_error(ctx: *mut duk_context, err: &mut DuktapeError,
+                           file: &str, line: u32) {
+    let state = state::get_or_install(ctx);
+    if let Some(map) = state.source_maps.get(file) {
+        if let Some((orig_file, orig_line, content)) = map.lookup(line) {
+            let frame = content.and_then(|c| code_frame(c, orig_line));
+            set_err_location(err, orig_file.to_string(), orig_line, frame);
+            return;
+        }
+    }
+    let frame = state.sources.get(file)
+        .and_then(|source| code_frame(source, line));
+    set_err_location(err, file.to_string(), line, frame);
+}
+
+/// Remember the source of a script if we're keeping sources, and return
+/// the compile flags to use.
+pub unsafe fn prepare_compile(ctx: *mut duk_context, filename: &str,
+                              code: &str) -> duk_uint_t {
+    let state = state::get_or_install(ctx);
+    if state.keep_source {
+        state.sources.insert(filename, code);
+        0
+    } else {
+        DUK_COMPILE_NOSOURCE
+    }
+}
+
+impl<H> Context<H> {
+    /// Keep the source of scripts compiled from now on, so that
+    /// functions retain their source and errors include code frames.
+    /// This uses more memory, so it's off by default.  We only keep the
+    /// sources of the most recent `MAX_KEPT_SOURCES` files for code
+    /// frames, and turning this off forgets them.
+    pub fn set_keep_source(&mut self, keep: bool) {
+        unsafe {
+            let state = state::get_or_install(self.as_mut_ptr());
+            state.keep_source = keep;
+            if !keep { state.sources.clear(); }
+        }
+    }
+
+    /// Are we keeping the source of scripts?
+    pub fn keep_source(&mut self) -> bool {
+        unsafe { state::get_or_install(self.as_mut_ptr()).keep_source }
+    }
+
+    /// Use the source map `json` to translate error locations in `file`,
+    /// typically a bundle of several original files.  Code frames are
+    /// shown for original files if the map includes `sourcesContent`.
+    pub fn add_source_map(&mut self, file: &str, json: &str) ->
+        DuktapeResult<()>
+    {
+        let map = try!(SourceMap::from_json(json));
+        unsafe {
+            let state = state::get_or_install(self.as_mut_ptr());
+            state.source_maps.insert(file.to_string(), map);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_code_frame() {
+    let source = "a();\nb();\n  c();\nd();\ne();\nf();\n";
+    assert_eq!(Some(concat!("  1 | a();\n",
+                            "  2 | b();\n",
+                            "> 3 |   c();\n",
+                            "    |   ^\n",
+                            "  4 | d();\n",
+                            "  5 | e();\n").to_string()),
+               code_frame(source, 3));
+    assert_eq!(Some(concat!("> 1 | a();\n",
+                            "    | ^\n",
+                            "  2 | b();\n",
+                            "  3 |   c();\n").to_string()),
+               code_frame(source, 1));
+    assert_eq!(None, code_frame(source, 7));
+}
+
+#[test]
+fn test_source() {
+    let mut ctx = Context::new().unwrap();
+
+    // By default, we don't keep source.
+    let err = ctx.eval_from("plain.js", "\nnull.x;").unwrap_err();
+    assert_eq!(Some("plain.js"), err.file_name());
+    assert_eq!(Some(2), err.line_number());
+    assert_eq!(None, err.code_frame());
+    assert!(!ctx.keep_source());
+
+    ctx.set_keep_source(true);
+    let err = ctx.eval_from("kept.js", "var a = 1;\n  null.x;\nvar b;")
+        .unwrap_err();
+    assert_eq!(Some("kept.js"), err.file_name());
+    assert_eq!(Some(concat!("  1 | var a = 1;\n",
+                            "> 2 |   null.x;\n",
+                            "    |   ^\n",
+                            "  3 | var b;\n")),
+               err.code_frame());
+    // Locations don't affect whether errors are equal.
+    assert_eq!(DuktapeError::from_str(&err.to_string()[]), err);
+
+    // Errors in a bundle point at the original files.  Generated line 2
+    // maps to line 3 of b.js, and line 3 to line 1 of a.js.
+    let map = r#"{"version": 3, "sources": ["a.js", "b.js"],
+                  "sourcesContent": [null, "x;\ny;\nnull.z;\n"],
+                  "mappings": "AAAA;ACEA;ADFA"}"#;
+    ctx.add_source_map("bundle.js", map).unwrap();
+    let err = ctx.eval_from("bundle.js", "1;\nnull.z;\n").unwrap_err();
+    assert_eq!(Some("b.js"), err.file_name());
+    assert_eq!(Some(3), err.line_number());
+    assert!(err.code_frame().unwrap().contains("> 3 | null.z;"));
+    let err = ctx.eval_from("bundle.js", "1;\n2;\nnull.y;").unwrap_err();
+    assert_eq!(Some("a.js"), err.file_name());
+    assert_eq!(Some(1), err.line_number());
+    assert_eq!(None, err.code_frame());
+
+    assert!(ctx.add_source_map("bad.js", "{}").is_err());
+
+    // We don't guess which version of a reused file name threw.
+    ctx.eval_from("reused.js", "function f() { null.x; }").unwrap();
+    ctx.eval_from("reused.js", "function g() {}\n\n").unwrap();
+    let err = ctx.eval("f()").unwrap_err();
+    assert_eq!(Some("reused.js"), err.file_name());
+    assert_eq!(None, err.code_frame());
+    ctx.eval_from("reused.js", "function f() { null.x; }").unwrap();
+    assert_eq!(None, ctx.eval("f()").unwrap_err().code_frame());
+}
+
+#[test]
+fn test_kept_sources() {
+    let mut sources = KeptSources::new();
+    sources.insert("a.js", "a();");
+    sources.insert("a.js", "a();");
+    assert_eq!(Some("a();"), sources.get("a.js"));
+    sources.insert("a.js", "b();");
+    assert_eq!(None, sources.get("a.js"));
+
+    // Old files are forgotten.
+    for i in range(0, MAX_KEPT_SOURCES) {
+        sources.insert(&format!("{}.js", i)[], "x;");
+    }
+    assert_eq!(MAX_KEPT_SOURCES, sources.by_file.len());
+    assert_eq!(MAX_KEPT_SOURCES, sources.order.len());
+    assert_eq!(Some("x;"), sources.get("0.js"));
+    assert!(!sources.by_file.contains_key("a.js"));
+    sources.clear();
+    assert_eq!(None, sources.get("0.js"));
+}
+
+#[test]
+fn test_decode_vlq() {
+    let mut digits = b"gB".iter().map(|&c| c);
+    assert_eq!(Ok(16), decode_vlq(&mut digits));
+    let mut digits = b"D".iter().map(|&c| c);
+    assert_eq!(Ok(-1), decode_vlq(&mut digits));
+
+    // Long runs of continuation digits are rejected, not overflowed.
+    let long: Vec<u8> = range(0, 20).map(|_| b'g').collect();
+    let mut digits = long.into_iter();
+    assert!(decode_vlq(&mut digits).is_err());
+}
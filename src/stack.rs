@@ -0,0 +1,497 @@
+//! A safe interface to the duktape value stack.  Values are pushed onto a
+//! `StackFrame`, which hands back `Slot` indices that can only be used
+//! while the frame exists.  When the frame goes away, everything pushed
+//! onto it is popped.
+//!
+//! ```
+//! use duktape::Context;
+//!
+//! let mut ctx = Context::new().unwrap();
+//! let total = ctx.with_frame(|frame| {
+//!     let a = frame.push(&2.0f64).unwrap();
+//!     let b = frame.push(&"3").unwrap();
+//!     frame.swap(a, b).unwrap();
+//!     let x: f64 = frame.require(b).unwrap();
+//!     x + frame.to_number(a).unwrap()
+//! });
+//! assert_eq!(5.0, total);
+//! ```
+
+use std::marker::InvariantLifetime;
+use std::string::CowString;
+use rustc_serialize::Decodable;
+use ffi::*;
+use errors::*;
+use context::{Context, get_global, from_lstring_borrowed};
+use encoder::{Encoder, DuktapeEncodable};
+use decoder::{Decoder, DuktapeDecodable, type_name, expected_err};
+use arg_list::IntoArgs;
+use jsstring::from_lstring_lossy;
+
+/// The index of a value pushed onto a `StackFrame`.  The lifetime
+/// parameter ties each slot to the frame which created it, so slots can't
+/// be used once their frame has been popped.  A slot may still refer to
+/// the wrong value if you move values around using `insert` or `remove`,
+/// but this will never cause memory unsafety.
+#[derive(Copy, Show)]
+pub struct Slot<'f> {
+    idx: duk_idx_t,
+    _marker: InvariantLifetime<'f>
+}
+
+impl<'f> PartialEq for Slot<'f> {
+    fn eq(&self, other: &Slot<'f>) -> bool { self.idx == other.idx }
+}
+
+/// Encoding a slot pushes a copy of the value it refers to, so slots can
+/// be passed as arguments to `StackFrame::call`.
+impl<'f> DuktapeEncodable for Slot<'f> {
+    fn duktape_encode(&self, s: &mut Encoder) -> DuktapeResult<()> {
+        unsafe {
+            let ptr = s.as_mut_ptr();
+            if self.idx < 0 || self.idx >= duk_get_top(ptr) {
+                return Err(invalid_slot());
+            }
+            if duk_check_stack(ptr, 1) == 0 {
+                return Err(DuktapeError::from_code_and_str(
+                    ErrorCode::Alloc, "out of space on the value stack"));
+            }
+            duk_dup(ptr, self.idx);
+        }
+        Ok(())
+    }
+}
+
+/// A region of the duktape value stack.  Create one using
+/// `Context::with_frame`.
+pub struct StackFrame<'f> {
+    ctx: *mut duk_context,
+    base: duk_idx_t,
+    _marker: InvariantLifetime<'f>
+}
+
+/// Create a new frame starting at the current top of the stack, and pass
+/// it to `f`.  Re-exported within the crate, but not outside.
+pub fn with_frame<H, R, F>(ctx: &mut Context<H>, f: F) -> R
+    where F: for<'f> FnOnce(&mut StackFrame<'f>) -> R
+{
+    unsafe { with_frame_ptr(ctx.as_mut_ptr(), f) }
+}
+
+unsafe fn with_frame_ptr<R, F>(ctx: *mut duk_context, f: F) -> R
+    where F: for<'f> FnOnce(&mut StackFrame<'f>) -> R
+{
+    let mut frame = StackFrame{ctx: ctx, base: duk_get_top(ctx),
+                               _marker: InvariantLifetime};
+    f(&mut frame)
+}
+
+impl<'f> Drop for StackFrame<'f> {
+    fn drop(&mut self) {
+        unsafe {
+            if duk_get_top(self.ctx) > self.base {
+                duk_set_top(self.ctx, self.base);
+            }
+        }
+    }
+}
+
+/// Build the error returned when a slot doesn't refer to a value in the
+/// current frame.
+fn invalid_slot() -> DuktapeError {
+    DuktapeError::from_code_and_str(ErrorCode::Api, "invalid stack slot")
+}
+
+impl<'f> StackFrame<'f> {
+    fn slot(&self, idx: duk_idx_t) -> Slot<'f> {
+        Slot{idx: idx, _marker: InvariantLifetime}
+    }
+
+    /// Check that `slot` still refers to a value in this frame, and
+    /// return its absolute index.
+    fn check(&self, slot: Slot<'f>) -> DuktapeResult<duk_idx_t> {
+        let top = unsafe { duk_get_top(self.ctx) };
+        if self.base <= slot.idx && slot.idx < top {
+            Ok(slot.idx)
+        } else {
+            Err(invalid_slot())
+        }
+    }
+
+    /// Make sure we have room for `count` more values.
+    fn reserve(&mut self, count: duk_idx_t) -> DuktapeResult<()> {
+        if unsafe { duk_check_stack(self.ctx, count) } != 0 {
+            Ok(())
+        } else {
+            Err(DuktapeError::from_code_and_str(
+                ErrorCode::Alloc, "out of space on the value stack"))
+        }
+    }
+
+    /// Get the underlying context pointer, for use by low-level add-ons
+    /// which need to push values directly.
+    pub unsafe fn as_mut_ptr(&mut self) -> *mut duk_context { self.ctx }
+
+    /// The number of values in this frame.
+    pub fn len(&self) -> usize {
+        unsafe { (duk_get_top(self.ctx) - self.base) as usize }
+    }
+
+    /// The slot of the value on top of this frame, if there is one.
+    pub fn top(&self) -> Option<Slot<'f>> {
+        let top = unsafe { duk_get_top(self.ctx) };
+        if top > self.base { Some(self.slot(top - 1)) } else { None }
+    }
+
+    /// The slot of value `n` in this frame, counting from the bottom.
+    pub fn nth(&self, n: usize) -> Option<Slot<'f>> {
+        let idx = self.base + n as duk_idx_t;
+        self.check(self.slot(idx)).ok().map(|idx| self.slot(idx))
+    }
+
+    /// Run `f` with a new frame nested inside this one.  Values in this
+    /// frame can't be accessed from the nested frame, so `dup` anything
+    /// you need first.
+    pub fn with_frame<R, F>(&mut self, f: F) -> R
+        where F: for<'g> FnOnce(&mut StackFrame<'g>) -> R
+    {
+        unsafe { with_frame_ptr(self.ctx, f) }
+    }
+
+    /// Encode `value` and push it onto the stack.  If the value can't be
+    /// encoded, nothing is pushed.
+    pub fn push<T: DuktapeEncodable>(&mut self, value: &T) ->
+        DuktapeResult<Slot<'f>>
+    {
+        let idx = unsafe { duk_get_top(self.ctx) };
+        let mut encoder = unsafe { Encoder::new(self.ctx) };
+        try!(value.duktape_encode(&mut encoder));
+        Ok(self.slot(idx))
+    }
+
+    /// Call the function in `func` with `args`, and push the result onto
+    /// the stack.  Errors thrown by the function are caught and returned,
+    /// and nothing is pushed.
+    pub fn call<A: IntoArgs>(&mut self, func: Slot<'f>, args: A) ->
+        DuktapeResult<Slot<'f>>
+    {
+        let idx = try!(self.check(func));
+        try!(self.reserve(1));
+        unsafe {
+            let top = duk_get_top(self.ctx);
+            duk_dup(self.ctx, idx);
+            let encoded = {
+                let mut encoder = Encoder::new(self.ctx);
+                args.encode_args(&mut encoder)
+            };
+            if let Err(err) = encoded {
+                duk_set_top(self.ctx, top);
+                return Err(err);
+            }
+            if duk_pcall(self.ctx, args.arg_count() as duk_idx_t) ==
+                DUK_EXEC_SUCCESS
+            {
+                Ok(self.slot(top))
+            } else {
+                Err(self.pop_error())
+            }
+        }
+    }
+
+    /// Push the global variable `name` onto the stack.
+    pub fn push_global(&mut self, name: &str) -> DuktapeResult<Slot<'f>> {
+        unsafe {
+            let idx = duk_get_top(self.ctx);
+            try!(get_global(self.ctx, name));
+            Ok(self.slot(idx))
+        }
+    }
+
+    /// Remove the value on top of this frame.
+    pub fn pop(&mut self) -> DuktapeResult<()> {
+        if self.len() == 0 { return Err(invalid_slot()); }
+        unsafe { duk_pop(self.ctx); }
+        Ok(())
+    }
+
+    /// Remove the value on top of this frame, decoding it as type `T`.
+    /// The value is removed even if it can't be decoded.
+    pub fn pop_as<T: DuktapeDecodable>(&mut self) -> DuktapeResult<T> {
+        let top = try!(self.top().ok_or_else(invalid_slot));
+        let result = self.require(top);
+        unsafe { duk_pop(self.ctx); }
+        result
+    }
+
+    /// Push a copy of the value in `slot`.
+    pub fn dup(&mut self, slot: Slot<'f>) -> DuktapeResult<Slot<'f>> {
+        let idx = try!(self.check(slot));
+        try!(self.reserve(1));
+        unsafe {
+            duk_dup(self.ctx, idx);
+            Ok(self.slot(duk_get_top(self.ctx) - 1))
+        }
+    }
+
+    /// Swap the values in two slots.
+    pub fn swap(&mut self, a: Slot<'f>, b: Slot<'f>) -> DuktapeResult<()> {
+        let (a, b) = (try!(self.check(a)), try!(self.check(b)));
+        unsafe { duk_swap(self.ctx, a, b); }
+        Ok(())
+    }
+
+    /// Move the value on top of this frame into `slot`, shifting the
+    /// values above it upwards.
+    pub fn insert(&mut self, slot: Slot<'f>) -> DuktapeResult<()> {
+        let idx = try!(self.check(slot));
+        unsafe { duk_insert(self.ctx, idx); }
+        Ok(())
+    }
+
+    /// Remove the value in `slot`, shifting the values above it
+    /// downwards.
+    pub fn remove(&mut self, slot: Slot<'f>) -> DuktapeResult<()> {
+        let idx = try!(self.check(slot));
+        unsafe { duk_remove(self.ctx, idx); }
+        Ok(())
+    }
+
+    /// Decode the value in `slot` as type `T`, or return `None` if it has
+    /// the wrong type.  This is analogous to duktape's `duk_get_*`
+    /// functions.
+    pub fn get<T: DuktapeDecodable>(&mut self, slot: Slot<'f>) -> Option<T> {
+        self.require(slot).ok()
+    }
+
+    /// Decode the value in `slot` as type `T`, or return an error if it
+    /// has the wrong type.  This is analogous to duktape's `duk_require_*`
+    /// functions, except that it never throws a JavaScript error.
+    pub fn require<T: DuktapeDecodable>(&mut self, slot: Slot<'f>) ->
+        DuktapeResult<T>
+    {
+        let idx = try!(self.check(slot));
+        try!(self.reserve(1));
+        unsafe {
+            let top = duk_get_top(self.ctx);
+            duk_dup(self.ctx, idx);
+            let result = {
+                let mut decoder = Decoder::new(self.ctx);
+                Decodable::decode(&mut decoder)
+            };
+            duk_set_top(self.ctx, top);
+            result
+        }
+    }
+
+    /// Get the string in `slot` without copying it, unless it contains
+    /// characters outside the Basic Multilingual Plane.  The string is
+    /// borrowed from the frame, so the frame can't be changed while it's
+    /// in use.  Returns an error if the value isn't a string.
+    pub fn get_str<'a>(&'a self, slot: Slot<'f>) ->
+        DuktapeResult<CowString<'a>>
+    {
+        let idx = try!(self.check(slot));
+        unsafe {
+            if duk_is_string(self.ctx, idx) == 0 {
+                return Err(expected_err("string", type_name(self.ctx, idx)));
+            }
+            let mut len = 0;
+            let data = duk_get_lstring(self.ctx, idx, &mut len);
+            from_lstring_borrowed(data, len)
+        }
+    }
+
+    /// Push a copy of the value in `slot`, and convert it in place using
+    /// `convert`, catching any errors thrown by `toString` or `valueOf`.
+    /// This leaves the result on the stack.
+    fn coerce(&mut self, slot: Slot<'f>, convert: duk_safe_call_function) ->
+        DuktapeResult<()>
+    {
+        let idx = try!(self.check(slot));
+        try!(self.reserve(1));
+        unsafe {
+            duk_dup(self.ctx, idx);
+            if duk_safe_call(self.ctx, convert, 1, 1) == DUK_EXEC_SUCCESS {
+                Ok(())
+            } else {
+                Err(self.pop_error())
+            }
+        }
+    }
+
+    /// Pop the error on top of the stack, and convert it to a
+    /// `DuktapeError`.
+    unsafe fn pop_error(&mut self) -> DuktapeError {
+        // Like `Context`, we'd rather report a slightly mangled error
+        // message than a conversion failure.
+        let mut len = 0;
+        let msg = duk_safe_to_lstring(self.ctx, -1, &mut len);
+        let msg = from_lstring_lossy(msg, len);
+        duk_pop(self.ctx);
+        DuktapeError::from_str(&msg[])
+    }
+
+    /// Shrink the property storage of the object in `slot` to fit its
+    /// current properties.  Values which aren't objects are left alone.
+    pub fn compact(&mut self, slot: Slot<'f>) -> DuktapeResult<()> {
+        let idx = try!(self.check(slot));
+        unsafe { duk_compact(self.ctx, idx); }
+        Ok(())
+    }
+
+    /// Convert the value in `slot` to a string, the way JavaScript's
+    /// `String(value)` would.
+    pub fn to_string(&mut self, slot: Slot<'f>) -> DuktapeResult<String> {
+        try!(self.coerce(slot, Some(to_string_top)));
+        self.pop_as()
+    }
+
+    /// Convert the value in `slot` to a number, the way JavaScript's
+    /// `Number(value)` would.
+    pub fn to_number(&mut self, slot: Slot<'f>) -> DuktapeResult<f64> {
+        try!(self.coerce(slot, Some(to_number_top)));
+        self.pop_as()
+    }
+
+    /// Convert the value in `slot` to a boolean, the way JavaScript's
+    /// `Boolean(value)` would.
+    pub fn to_boolean(&mut self, slot: Slot<'f>) -> DuktapeResult<bool> {
+        try!(self.coerce(slot, Some(to_boolean_top)));
+        self.pop_as()
+    }
+}
+
+// Conversions for `StackFrame::coerce`, called using `duk_safe_call`.
+// These use duktape's own conversions, not the `String`, `Number` and
+// `Boolean` globals, which scripts may replace.
+
+unsafe extern "C" fn to_string_top(ctx: *mut duk_context) -> duk_ret_t {
+    duk_to_string(ctx, -1);
+    1
+}
+
+unsafe extern "C" fn to_number_top(ctx: *mut duk_context) -> duk_ret_t {
+    duk_to_number(ctx, -1);
+    1
+}
+
+unsafe extern "C" fn to_boolean_top(ctx: *mut duk_context) -> duk_ret_t {
+    duk_to_boolean(ctx, -1);
+    1
+}
+
+#[test]
+fn test_stack_frame() {
+    use std::u64;
+    use std::borrow::Cow;
+    use types::Value;
+
+    let mut ctx = Context::new().unwrap();
+    ctx.eval("var bad = { toString: function () { throw new Error('no'); } };")
+        .unwrap();
+    ctx.eval("function add(x, y) { return x+y; }").unwrap();
+    ctx.eval("Object.defineProperty(this, 'trap', {\
+                get: function () { throw new Error('trap'); } });\
+              this['a\\u0000b'] = 5;\
+              String = Number = Boolean = function () { return 'hijacked'; };")
+        .unwrap();
+    ctx.eval("var worse = { valueOf: function () { \
+                throw new Error('\\ud800'); } };").unwrap();
+    let top = unsafe { duk_get_top(ctx.as_mut_ptr()) };
+
+    ctx.with_frame(|frame| {
+        let a = frame.push(&1.0f64).unwrap();
+        let b = frame.push(&"two").unwrap();
+        assert_eq!(2, frame.len());
+        assert_eq!(Some(a), frame.nth(0));
+        assert_eq!(Some(b), frame.top());
+
+        // Typed access.
+        assert_eq!(Ok(1.0f64), frame.require(a));
+        assert_eq!(None, frame.get::<f64>(b));
+        assert!(frame.require::<f64>(b).is_err());
+        assert_eq!(Ok("1".to_string()), frame.to_string(a));
+        assert_eq!(Ok(true), frame.to_boolean(b));
+        assert_eq!(Ok(Cow::Borrowed("two")), frame.get_str(b));
+        assert_eq!(Err(DuktapeError::from_code_and_str(
+                       ErrorCode::Type, "Expected string, got number")),
+                   frame.get_str(a));
+        let astral = frame.push(&"\u{13000}").unwrap();
+        match frame.get_str(astral) {
+            Ok(Cow::Owned(s)) => assert_eq!("\u{13000}", &s[]),
+            other => panic!("expected an owned string, got {:?}", other)
+        }
+        frame.pop().unwrap();
+
+        // Rearranging values.
+        let c = frame.dup(a).unwrap();
+        frame.swap(a, b).unwrap();
+        assert_eq!(Ok("two".to_string()), frame.require(a));
+        frame.remove(a).unwrap();
+        assert_eq!(2, frame.len());
+        frame.push(&Value::Null).unwrap();
+        let bottom = frame.nth(0).unwrap();
+        frame.insert(bottom).unwrap();
+        assert_eq!(Ok(None), frame.require::<Option<f64>>(bottom));
+        assert_eq!(Ok(1.0f64), frame.pop_as());
+        assert_eq!(Ok(1.0f64), frame.pop_as());
+        assert!(frame.require::<f64>(c).is_err());
+
+        // Conversion errors are caught.
+        let bad = frame.push_global("bad").unwrap();
+        assert_eq!(Err(DuktapeError::from_str("Error: no")),
+                   frame.to_string(bad));
+        let worse = frame.push_global("worse").unwrap();
+        assert_eq!(Err(DuktapeError::from_str("Error: \u{fffd}")),
+                   frame.to_number(worse));
+        frame.remove(worse).unwrap();
+
+        // Conversions don't use the script's globals.
+        let seven = frame.push(&7.0f64).unwrap();
+        assert_eq!(Ok("7".to_string()), frame.to_string(seven));
+        let zero = frame.push(&0.0f64).unwrap();
+        assert_eq!(Ok(false), frame.to_boolean(zero));
+        frame.pop().unwrap();
+        frame.pop().unwrap();
+
+        // Globals are looked up safely, by their exact names.
+        assert_eq!(Err(DuktapeError::from_str("Error: trap")),
+                   frame.push_global("trap").map(|_| ()));
+        let exact = frame.push_global("a\0b").unwrap();
+        assert_eq!(Ok(5.0f64), frame.require(exact));
+        frame.pop().unwrap();
+
+        // Calling functions, with slots as arguments.
+        let add = frame.push_global("add").unwrap();
+        let x = frame.push(&2.0f64).unwrap();
+        let sum = frame.call(add, &[x, x][]).unwrap();
+        assert_eq!(Ok(4.0f64), frame.require(sum));
+        let sum = frame.call(add, (sum, 1.0f64)).unwrap();
+        assert_eq!(Ok(5.0f64), frame.require(sum));
+        let thrower = frame.push_global("bad").unwrap();
+        assert!(frame.call(thrower, ()).is_err());
+        assert!(frame.call(add, (x, 1.0f64, u64::MAX)).is_err());
+        frame.with_frame(|inner| {
+            let y = inner.push(&0.5f64).unwrap();
+            let add = inner.push_global("add").unwrap();
+            let sum = inner.call(add, &[&y, &y]).unwrap();
+            assert_eq!(Ok(1.0f64), inner.require(sum));
+        });
+        assert_eq!(7, frame.len());
+        for _ in range(0, 5) { frame.pop().unwrap(); }
+
+        // Nested frames clean up after themselves.
+        frame.with_frame(|inner| {
+            inner.push(&3.0f64).unwrap();
+            inner.push(&4.0f64).unwrap();
+        });
+        assert_eq!(2, frame.len());
+        assert!(frame.pop().is_ok());
+        assert!(frame.pop().is_ok());
+        assert!(frame.pop().is_err());
+        frame.push(&5.0f64).unwrap();
+    });
+
+    // Everything we pushed has been removed.
+    assert_eq!(top, unsafe { duk_get_top(ctx.as_mut_ptr()) });
+}
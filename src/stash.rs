@@ -0,0 +1,313 @@
+//! Places to keep native state which scripts can't see.  Duktape provides
+//! three "stashes": one per heap, one per global environment, and one per
+//! thread.  We can store either JavaScript values or boxed Rust values in
+//! any of them.
+//!
+//! ```
+//! use duktape::{Context, Stash};
+//!
+//! struct Counter { hits: u32 }
+//!
+//! let mut ctx = Context::new().unwrap();
+//! ctx.put_stash(Stash::Heap, "limit", &10.0f64).unwrap();
+//! ctx.put_stash_box(Stash::Global, "counter", Counter{hits: 0}).unwrap();
+//! ctx.stash_box::<Counter>(Stash::Global, "counter").unwrap().hits += 1;
+//! assert_eq!(Ok(Some(10.0)), ctx.get_stash::<f64>(Stash::Heap, "limit"));
+//! ```
+
+use std::any::{Any, TypeId};
+use std::ffi::CString;
+use std::mem::transmute;
+use std::ptr::null_mut;
+use rustc_serialize::Decodable;
+use ffi::*;
+use errors::*;
+use context::Context;
+use encoder::{Encoder, DuktapeEncodable};
+use decoder::{Decoder, DuktapeDecodable};
+use state;
+
+/// The hidden property of a box object which points to its Rust value.
+const BOX_PTR_PROP: [i8; 5] = [-1, 'b' as i8, 'o' as i8, 'x' as i8, 0];
+
+/// Which of duktape's stashes to use.
+#[derive(Copy, Clone, Show, PartialEq, Eq)]
+pub enum Stash {
+    /// Shared by everything using the heap.
+    Heap,
+    /// Specific to the global environment of the current context.
+    Global,
+    /// Specific to the current duktape thread.
+    Thread
+}
+
+/// Push the stash `stash`.
+unsafe fn push_stash(ctx: *mut duk_context, stash: Stash) {
+    match stash {
+        Stash::Heap => duk_push_heap_stash(ctx),
+        Stash::Global => duk_push_global_stash(ctx),
+        Stash::Thread => duk_push_thread_stash(ctx, ctx)
+    }
+}
+
+/// Make sure we have room for `count` more values.
+unsafe fn reserve(ctx: *mut duk_context, count: duk_idx_t) ->
+    DuktapeResult<()>
+{
+    if duk_check_stack(ctx, count) != 0 {
+        Ok(())
+    } else {
+        Err(DuktapeError::from_code(ErrorCode::Alloc))
+    }
+}
+
+/// Push the value of `key` in `stash`.
+unsafe fn push_entry(ctx: *mut duk_context, stash: Stash, key: &str) {
+    push_stash(ctx, stash);
+    duk_push_lstring(ctx, key.as_ptr() as *const i8,
+                     key.len() as duk_size_t);
+    duk_get_prop(ctx, -2);
+    duk_remove(ctx, -2);
+}
+
+/// Drop the Rust value attached to a box object when it's collected.
+unsafe extern "C" fn finalize_box(ctx: *mut duk_context) -> duk_ret_t {
+    duk_get_prop_string(ctx, 0, BOX_PTR_PROP.as_ptr());
+    let p = duk_get_pointer(ctx, -1);
+    duk_pop(ctx);
+    if !p.is_null() {
+        // Finalizers may run more than once if an object is resurrected.
+        duk_push_pointer(ctx, null_mut());
+        duk_put_prop_string(ctx, 0, BOX_PTR_PROP.as_ptr());
+        abort_on_panic!("unexpected panic while dropping a stashed value", {
+            let _value: Box<Box<Any>> = transmute(p);
+        });
+    }
+    0
+}
+
+/// Get the Rust value attached to the box object on top of the stack, if
+/// it has type `T`.
+unsafe fn get_box<'a, T: Any>(ctx: *mut duk_context) -> Option<&'a mut T> {
+    if duk_is_object(ctx, -1) == 0 { return None; }
+    duk_get_prop_string(ctx, -1, BOX_PTR_PROP.as_ptr());
+    let p = duk_get_pointer(ctx, -1);
+    duk_pop(ctx);
+    if p.is_null() { return None; }
+    let value: &'a mut Box<Any> = transmute(p);
+    value.downcast_mut::<T>()
+}
+
+impl<H> Context<H> {
+    /// Store a JavaScript value under `key` in `stash`.
+    pub fn put_stash<T: DuktapeEncodable>(&mut self, stash: Stash, key: &str,
+                                          value: &T) -> DuktapeResult<()> {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            try!(reserve(ptr, 3));
+            let top = duk_get_top(ptr);
+            push_stash(ptr, stash);
+            duk_push_lstring(ptr, key.as_ptr() as *const i8,
+                             key.len() as duk_size_t);
+            let result = {
+                let mut encoder = Encoder::new(ptr);
+                value.duktape_encode(&mut encoder)
+            };
+            if result.is_ok() { duk_put_prop(ptr, -3); }
+            duk_set_top(ptr, top);
+            result
+        }
+    }
+
+    /// Get the JavaScript value stored under `key` in `stash`, or `None`
+    /// if there is no such value.
+    pub fn get_stash<T: DuktapeDecodable>(&mut self, stash: Stash,
+                                          key: &str) ->
+        DuktapeResult<Option<T>>
+    {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            try!(reserve(ptr, 3));
+            let top = duk_get_top(ptr);
+            push_entry(ptr, stash, key);
+            let result = if duk_is_undefined(ptr, -1) != 0 {
+                Ok(None)
+            } else {
+                let mut decoder = Decoder::new(ptr);
+                Decodable::decode(&mut decoder).map(Some)
+            };
+            duk_set_top(ptr, top);
+            result
+        }
+    }
+
+    /// Does `stash` contain a value under `key`?
+    pub fn has_stash(&mut self, stash: Stash, key: &str) -> bool {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            if reserve(ptr, 2).is_err() { return false; }
+            push_stash(ptr, stash);
+            duk_push_lstring(ptr, key.as_ptr() as *const i8,
+                             key.len() as duk_size_t);
+            let found = duk_has_prop(ptr, -2) != 0;
+            duk_pop(ptr);
+            found
+        }
+    }
+
+    /// Remove `key` from `stash`.  Any boxed Rust value stored there will
+    /// be dropped once the garbage collector notices.
+    pub fn remove_stash(&mut self, stash: Stash, key: &str) {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            if reserve(ptr, 2).is_err() { return; }
+            push_stash(ptr, stash);
+            duk_push_lstring(ptr, key.as_ptr() as *const i8,
+                             key.len() as duk_size_t);
+            duk_del_prop(ptr, -2);
+            duk_pop(ptr);
+        }
+    }
+
+    /// Store a Rust value under `key` in `stash`.  The value is dropped
+    /// when it's removed from the stash and garbage collected, or when
+    /// the heap is destroyed.
+    pub fn put_stash_box<T: Any + Send>(&mut self, stash: Stash, key: &str,
+                                        value: T) -> DuktapeResult<()> {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            try!(reserve(ptr, 4));
+            push_stash(ptr, stash);
+            duk_push_lstring(ptr, key.as_ptr() as *const i8,
+                             key.len() as duk_size_t);
+            duk_push_object(ptr);
+            let boxed: Box<Box<Any>> = Box::new(Box::new(value));
+            duk_push_pointer(ptr, transmute(boxed));
+            duk_put_prop_string(ptr, -2, BOX_PTR_PROP.as_ptr());
+            duk_push_c_function(ptr, Some(finalize_box), 1);
+            duk_set_finalizer(ptr, -2);
+            duk_put_prop(ptr, -3);
+            duk_pop(ptr);
+            Ok(())
+        }
+    }
+
+    /// Borrow the Rust value stored under `key` in `stash`, or return
+    /// `None` if there is no value of type `T` there.
+    pub fn stash_box<T: Any>(&mut self, stash: Stash, key: &str) ->
+        Option<&mut T>
+    {
+        unsafe {
+            let ptr = self.as_mut_ptr();
+            if reserve(ptr, 3).is_err() { return None; }
+            push_entry(ptr, stash, key);
+            let value = get_box::<T>(ptr);
+            duk_pop(ptr);
+            value
+        }
+    }
+
+    /// Attach a Rust value to this context's heap, replacing any value of
+    /// the same type attached earlier.  Callbacks can borrow it using
+    /// `user_data`.  The value is dropped when the heap is destroyed.
+    pub fn set_user_data<T: Any + Send>(&mut self, value: T) {
+        unsafe {
+            let state = state::get_or_install(self.as_mut_ptr());
+            state.user_data.insert(TypeId::of::<T>(), Box::new(value));
+        }
+    }
+
+    /// Borrow the value of type `T` attached using `set_user_data`.
+    pub fn user_data<T: Any>(&mut self) -> Option<&mut T> {
+        unsafe {
+            let state = state::get_or_install(self.as_mut_ptr());
+            match state.user_data.get_mut(&TypeId::of::<T>()) {
+                Some(value) => value.downcast_mut::<T>(),
+                None => None
+            }
+        }
+    }
+
+    /// Detach and drop the value of type `T` attached using
+    /// `set_user_data`.  Returns true if there was such a value.
+    pub fn remove_user_data<T: Any>(&mut self) -> bool {
+        unsafe {
+            let state = state::get_or_install(self.as_mut_ptr());
+            state.user_data.remove(&TypeId::of::<T>()).is_some()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use errors::*;
+    use args::Args;
+    use context::{Context, Borrowed};
+    use encoder::DuktapeEncodable;
+
+    pub struct Hits(pub u32);
+
+    pub fn hit(ctx: &mut Context<Borrowed>, _args: &Args) ->
+        DuktapeResult<Box<DuktapeEncodable + 'static>>
+    {
+        let hits = ctx.user_data::<Hits>().unwrap();
+        hits.0 += 1;
+        Ok(Box::new(hits.0 as f64))
+    }
+
+    /// Counts how many times it has been dropped.
+    pub struct DropCounter(pub Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) { self.0.fetch_add(1, Ordering::SeqCst); }
+    }
+}
+
+#[test]
+fn test_stash() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use types::Value;
+
+    let mut ctx = Context::new().unwrap();
+
+    // JavaScript values are invisible to scripts.
+    ctx.put_stash(Stash::Global, "config", &vec!(1.0f64, 2.0)).unwrap();
+    assert_eq!(Ok(Some(vec!(1.0f64, 2.0))),
+               ctx.get_stash::<Vec<f64>>(Stash::Global, "config"));
+    assert_eq!(Ok(None), ctx.get_stash::<f64>(Stash::Heap, "config"));
+    assert!(ctx.has_stash(Stash::Global, "config"));
+    ctx.remove_stash(Stash::Global, "config");
+    assert!(!ctx.has_stash(Stash::Global, "config"));
+    ctx.put_stash(Stash::Thread, "x", &1.0f64).unwrap();
+    assert_eq!(Ok(Some(1.0f64)), ctx.get_stash(Stash::Thread, "x"));
+
+    // Boxed Rust values are dropped when they're collected.
+    let drops = Arc::new(AtomicUsize::new(0));
+    ctx.put_stash_box(Stash::Heap, "box",
+                      test::DropCounter(drops.clone())).unwrap();
+    assert!(ctx.stash_box::<test::DropCounter>(Stash::Heap, "box").is_some());
+    assert!(ctx.stash_box::<u32>(Stash::Heap, "box").is_none());
+    ctx.put_stash_box(Stash::Heap, "box", 7u32).unwrap();
+    ctx.gc();
+    assert_eq!(1, drops.load(Ordering::SeqCst));
+    *ctx.stash_box::<u32>(Stash::Heap, "box").unwrap() += 1;
+    assert_eq!(Some(&mut 8u32), ctx.stash_box::<u32>(Stash::Heap, "box"));
+
+    // User data is available to callbacks.
+    ctx.set_user_data(test::Hits(0));
+    ctx.register("hit", test::hit, Some(0));
+    ctx.eval("hit(); hit();").unwrap();
+    assert_eq!(Ok(Value::Number(3.0)), ctx.eval("hit()"));
+    assert_eq!(3, ctx.user_data::<test::Hits>().unwrap().0);
+    assert!(ctx.remove_user_data::<test::Hits>());
+    assert!(ctx.user_data::<test::Hits>().is_none());
+
+    // Anything left is dropped with the heap.
+    ctx.put_stash_box(Stash::Global, "last",
+                      test::DropCounter(drops.clone())).unwrap();
+    drop(ctx);
+    assert_eq!(2, drops.load(Ordering::SeqCst));
+}
@@ -0,0 +1,102 @@
+//! Rust-side state attached to a duktape heap, so that it can be found
+//! by every `Context` which points at that heap, including the borrowed
+//! contexts passed to callbacks.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::default::Default;
+use std::mem::transmute;
+use std::ptr::null_mut;
+use ffi::*;
+use options::{EncoderOptions, DecoderOptions};
+use event_loop::LoopState;
+use heap::HeapCounters;
+use context::Callback;
+use source::{SourceMap, KeptSources};
+
+/// The hidden heap stash property where we keep a pointer to our
+/// `HeapState`.
+const HEAP_STATE_PROP: [i8; 8] =
+    [-1, 'r' as i8, 's' as i8, 't' as i8, 'a' as i8, 't' as i8, 'e' as i8, 0];
+
+/// State shared by all the `Context` objects pointing at a heap.
+pub struct HeapState {
+    /// Options used when encoding values.
+    pub encoder_options: EncoderOptions,
+    /// Options used when decoding values.
+    pub decoder_options: DecoderOptions,
+    /// Pending timers and promises, if an `EventLoop` has been installed.
+    pub event_loop: Option<LoopState>,
+    /// Memory usage counters maintained by our allocator, or null if the
+    /// heap was created some other way.
+    pub counters: *mut HeapCounters,
+    /// Rust values attached using `Context::set_user_data`.
+    pub user_data: HashMap<TypeId, Box<Any>>,
+    /// The callbacks used by `Module` functions, indexed by magic value.
+    pub magic_callbacks: Vec<Callback>,
+    /// Should we keep the source of the scripts we compile?
+    pub keep_source: bool,
+    /// The source of recent files compiled while `keep_source` was set.
+    pub sources: KeptSources,
+    /// Source maps for bundled files, by generated file name.
+    pub source_maps: HashMap<String, SourceMap>
+}
+
+/// Look up the `HeapState` attached to the heap containing `ctx`, if any.
+/// The caller is responsible for not holding onto the result after the
+/// heap is destroyed.
+pub unsafe fn get<'a>(ctx: *mut duk_context) -> Option<&'a mut HeapState> {
+    duk_push_heap_stash(ctx);
+    duk_get_prop_string(ctx, -1, HEAP_STATE_PROP.as_ptr());
+    let p = duk_get_pointer(ctx, -1);
+    duk_pop_2(ctx);
+    if p.is_null() { None } else { Some(transmute(p)) }
+}
+
+/// Look up the `HeapState` attached to the heap containing `ctx`,
+/// creating it if necessary.
+pub unsafe fn get_or_install<'a>(ctx: *mut duk_context) -> &'a mut HeapState {
+    if let Some(state) = get(ctx) {
+        return state;
+    }
+    let state: Box<HeapState> = Box::new(HeapState{
+        encoder_options: Default::default(),
+        decoder_options: Default::default(),
+        event_loop: None,
+        counters: null_mut(),
+        user_data: HashMap::new(),
+        magic_callbacks: vec!(),
+        keep_source: false,
+        sources: KeptSources::new(),
+        source_maps: HashMap::new()
+    });
+    duk_push_heap_stash(ctx);
+    duk_push_pointer(ctx, transmute(state));
+    duk_put_prop_string(ctx, -2, HEAP_STATE_PROP.as_ptr());
+    duk_pop(ctx);
+    get(ctx).unwrap()
+}
+
+/// Free the `HeapState` attached to the heap containing `ctx`.  Call this
+/// just before destroying the heap.
+pub unsafe fn destroy(ctx: *mut duk_context) {
+    duk_push_heap_stash(ctx);
+    duk_get_prop_string(ctx, -1, HEAP_STATE_PROP.as_ptr());
+    let p = duk_get_pointer(ctx, -1);
+    duk_pop(ctx);
+    duk_del_prop_string(ctx, -1, HEAP_STATE_PROP.as_ptr());
+    duk_pop(ctx);
+    if !p.is_null() {
+        let _state: Box<HeapState> = transmute(p);
+    }
+}
+
+/// The encoder options for the heap containing `ctx`.
+pub unsafe fn encoder_options(ctx: *mut duk_context) -> EncoderOptions {
+    get(ctx).map(|s| s.encoder_options.clone()).unwrap_or_else(Default::default)
+}
+
+/// The decoder options for the heap containing `ctx`.
+pub unsafe fn decoder_options(ctx: *mut duk_context) -> DecoderOptions {
+    get(ctx).map(|s| s.decoder_options.clone()).unwrap_or_else(Default::default)
+}
@@ -1,4 +1,6 @@
 use libc::types::os::arch::c95::c_double;
+use std::borrow::Cow;
+use std::default::Default;
 use std::string::CowString;
 
 /// A value that can be passed to and from JavaScript.  This does not
@@ -16,3 +18,41 @@ pub enum Value<'a> {
     /// A JavaScript string value.
     String(CowString<'a>)
 }
+
+impl<'a> Value<'a> {
+    /// Convert to a value which doesn't borrow any data, copying any
+    /// borrowed string.
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Value::Undefined => Value::Undefined,
+            Value::Null => Value::Null,
+            Value::Bool(v) => Value::Bool(v),
+            Value::Number(v) => Value::Number(v),
+            Value::String(v) => Value::String(Cow::Owned(v.into_owned()))
+        }
+    }
+}
+/// The attributes of a property defined using `Context::define_global`,
+/// with the same meanings as in `Object.defineProperty`.  The default
+/// attributes match those of an ordinary assignment.
+#[derive(Copy, Clone, Show, PartialEq, Eq)]
+pub struct PropertyAttributes {
+    /// May the property be assigned to?
+    pub writable: bool,
+    /// Will the property show up in `for ... in` loops?
+    pub enumerable: bool,
+    /// May the property be deleted or redefined?
+    pub configurable: bool
+}
+
+impl Default for PropertyAttributes {
+    fn default() -> PropertyAttributes {
+        PropertyAttributes{writable: true, enumerable: true,
+                           configurable: true}
+    }
+}
+
+/// A binary buffer, which will be passed to JavaScript as a duktape buffer
+/// instead of as an array of numbers.
+#[derive(Show, PartialEq, Clone)]
+pub struct Buffer(pub Vec<u8>);